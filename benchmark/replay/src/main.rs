@@ -0,0 +1,134 @@
+//! Replays a trace recorded by emma's `trace` feature against the allocator selected via the features of the
+//! `allocator` crate.
+//!
+//! Each thread of the trace is replayed on its own thread. Objects that were allocated on one thread and released on
+//! another are handed over in the order given by the timestamps of the trace. The time taken is printed to stdout, the
+//! peak and final RSS to stderr. Both are relative to the RSS before the replay, which includes the parsed trace.
+
+use std::alloc::Layout;
+use std::sync::atomic::{AtomicPtr, Ordering};
+use std::sync::{Arc, Barrier};
+use std::time::Instant;
+
+use trace::{Operation, Trace};
+
+#[global_allocator]
+static ALLOC: ::allocator::Allocator = ::allocator::create_allocator();
+
+mod trace;
+
+fn layout(trace: &Trace, id: trace::AllocationId) -> Layout {
+	let (size, alignment) = trace.layouts[id];
+	Layout::from_size_align(size, alignment).unwrap()
+}
+
+/// Waits until the object with the given id has been allocated, potentially by another thread.
+fn wait_for(objects: &[AtomicPtr<u8>], id: trace::AllocationId) -> *mut u8 {
+	loop {
+		let p = objects[id].load(Ordering::Acquire);
+		if !p.is_null() {
+			return p;
+		}
+		std::thread::yield_now();
+	}
+}
+
+/// Writes to every page of a fresh object, as a program would.
+fn touch(p: *mut u8, size: usize) {
+	for offset in (0..size).step_by(4096) {
+		unsafe { p.add(offset).write_volatile(0x42) };
+	}
+}
+
+fn replay(trace: &Trace, objects: &[AtomicPtr<u8>], operations: &[Operation]) {
+	for &operation in operations {
+		match operation {
+			Operation::Alloc { id } => {
+				let layout = layout(trace, id);
+				let p = unsafe { std::alloc::alloc(layout) };
+				if p.is_null() {
+					std::alloc::handle_alloc_error(layout);
+				}
+				touch(p, layout.size());
+				objects[id].store(p, Ordering::Release);
+			}
+			Operation::Dealloc { id } => {
+				let p = wait_for(objects, id);
+				unsafe { std::alloc::dealloc(p, layout(trace, id)) };
+			}
+			Operation::Realloc { old_id, new_id } => {
+				let p = wait_for(objects, old_id);
+				let new_layout = layout(trace, new_id);
+				let q = unsafe { std::alloc::realloc(p, layout(trace, old_id), new_layout.size()) };
+				if q.is_null() {
+					std::alloc::handle_alloc_error(new_layout);
+				}
+				objects[new_id].store(q, Ordering::Release);
+			}
+		}
+	}
+}
+
+/// Reads a value in kB from `/proc/self/status` and returns it in MiB.
+fn proc_status_mib(key: &str) -> f64 {
+	let status = std::fs::read_to_string("/proc/self/status").unwrap();
+	let line = status.lines().find(|line| line.starts_with(key)).unwrap();
+	let kbs: u64 = line[key.len()..].trim().trim_end_matches("kB").trim().parse().unwrap();
+	kbs as f64 / 1024.
+}
+
+/// Resets the peak RSS of the process to its current RSS, which is returned in MiB.
+fn reset_peak_rss() -> f64 {
+	if let Err(err) = std::fs::write("/proc/self/clear_refs", "5") {
+		eprintln!("Cannot reset the peak RSS, which therefore includes reading the trace: {err}");
+	}
+	proc_status_mib("VmRSS:")
+}
+
+fn main() {
+	let path = std::env::args().nth(1).expect("usage: replay <trace>");
+	let trace = match Trace::parse(&std::fs::read(&path).unwrap()) {
+		Ok(trace) => Arc::new(trace),
+		Err(err) => {
+			eprintln!("{path}: {err}");
+			std::process::exit(1);
+		}
+	};
+	eprintln!(
+		"Replaying {} operations on {} threads ({} records skipped)",
+		trace.operations(),
+		trace.threads.len(),
+		trace.skipped
+	);
+
+	let objects: Arc<[AtomicPtr<u8>]> = (0..trace.layouts.len())
+		.map(|_| AtomicPtr::new(std::ptr::null_mut()))
+		.collect();
+	let barrier = Arc::new(Barrier::new(trace.threads.len() + 1));
+	let threads: Vec<_> = (0..trace.threads.len())
+		.map(|i| {
+			let trace = trace.clone();
+			let objects = objects.clone();
+			let barrier = barrier.clone();
+			std::thread::Builder::new()
+				.name(format!("replay #{i}"))
+				.spawn(move || {
+					barrier.wait();
+					replay(&trace, &objects, &trace.threads[i]);
+				})
+				.unwrap()
+		})
+		.collect();
+
+	let baseline = reset_peak_rss();
+	barrier.wait();
+	let start = Instant::now();
+	for thread in threads.into_iter() {
+		thread.join().unwrap();
+	}
+	let elapsed = Instant::now() - start;
+
+	println!("Time elapsed = {}", elapsed.as_secs_f64());
+	eprintln!("Peak RSS = {} MiB", proc_status_mib("VmHWM:") - baseline);
+	eprintln!("Final RSS = {} MiB", proc_status_mib("VmRSS:") - baseline);
+}
@@ -0,0 +1,132 @@
+//! Reads traces recorded by emma's `trace` feature and turns them into per-thread sequences of operations.
+
+use std::collections::{BTreeMap, HashMap};
+
+const MAGIC: &[u8; 8] = b"EMMATRCE";
+const VERSION: u32 = 1;
+const HEADER_SIZE: usize = 16;
+const RECORD_SIZE: usize = 48;
+
+const KIND_ALLOC: u8 = 1;
+const KIND_DEALLOC: u8 = 2;
+const KIND_REALLOC: u8 = 3;
+
+#[derive(Debug, Copy, Clone)]
+struct Record {
+	timestamp: u64,
+	ptr: u64,
+	new_ptr: u64,
+	size: usize,
+	alignment: usize,
+	tid: u32,
+	kind: u8,
+}
+
+impl Record {
+	fn parse(bytes: &[u8]) -> Self {
+		let u32_at = |offset: usize| u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap());
+		let u64_at = |offset: usize| u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap());
+
+		Self {
+			timestamp: u64_at(0),
+			ptr: u64_at(8),
+			new_ptr: u64_at(16),
+			size: u64_at(24) as usize,
+			alignment: u32_at(32) as usize,
+			tid: u32_at(36),
+			kind: bytes[40],
+		}
+	}
+}
+
+/// Allocations are identified by their index into [`Trace::layouts`].
+pub type AllocationId = usize;
+
+#[derive(Debug, Copy, Clone)]
+pub enum Operation {
+	Alloc { id: AllocationId },
+	Dealloc { id: AllocationId },
+	Realloc { old_id: AllocationId, new_id: AllocationId },
+}
+
+#[derive(Debug)]
+pub struct Trace {
+	/// The size and alignment of each allocation.
+	pub layouts: Vec<(usize, usize)>,
+	/// The operations performed by each thread, in the order of the original thread ids.
+	pub threads: Vec<Vec<Operation>>,
+	/// The number of records that could not be replayed, because they referred to objects allocated before the trace
+	/// started.
+	pub skipped: usize,
+}
+
+impl Trace {
+	pub fn parse(bytes: &[u8]) -> Result<Self, String> {
+		if bytes.len() < HEADER_SIZE || &bytes[..8] != MAGIC {
+			return Err("not an emma trace".to_owned());
+		}
+		let version = u32::from_le_bytes(bytes[8..12].try_into().unwrap());
+		if version != VERSION {
+			return Err(format!("unsupported trace version {version}"));
+		}
+		let record_size = u32::from_le_bytes(bytes[12..16].try_into().unwrap()) as usize;
+		if record_size != RECORD_SIZE {
+			return Err(format!("unsupported record size {record_size}"));
+		}
+
+		// Buffers are flushed independently per thread, so the file is only ordered in chunks.
+		let mut records: Vec<_> = bytes[HEADER_SIZE..]
+			.chunks_exact(RECORD_SIZE)
+			.map(Record::parse)
+			.collect();
+		records.sort_by_key(|record| record.timestamp);
+
+		let mut trace = Trace {
+			layouts: Vec::new(),
+			threads: Vec::new(),
+			skipped: 0,
+		};
+		let mut live = HashMap::<u64, AllocationId>::new();
+		let mut threads = BTreeMap::<u32, Vec<Operation>>::new();
+		for record in records {
+			let operation = match record.kind {
+				KIND_ALLOC if record.ptr != 0 => {
+					let id = trace.new_allocation(record.size, record.alignment);
+					live.insert(record.ptr, id);
+					Some(Operation::Alloc { id })
+				}
+				KIND_DEALLOC => live.remove(&record.ptr).map(|id| Operation::Dealloc { id }),
+				KIND_REALLOC if record.new_ptr != 0 => {
+					let new_id = trace.new_allocation(record.size, record.alignment);
+					let operation = if let Some(old_id) = live.remove(&record.ptr) {
+						Operation::Realloc { old_id, new_id }
+					} else {
+						Operation::Alloc { id: new_id }
+					};
+					live.insert(record.new_ptr, new_id);
+					Some(operation)
+				}
+				KIND_ALLOC | KIND_REALLOC => None,
+				kind => return Err(format!("unknown record kind {kind}")),
+			};
+
+			if let Some(operation) = operation {
+				threads.entry(record.tid).or_default().push(operation);
+			} else {
+				trace.skipped += 1;
+			}
+		}
+		trace.threads = threads.into_values().collect();
+
+		Ok(trace)
+	}
+
+	fn new_allocation(&mut self, size: usize, alignment: usize) -> AllocationId {
+		self.layouts.push((size, alignment));
+		self.layouts.len() - 1
+	}
+
+	pub fn operations(&self) -> usize {
+		self.threads.iter().map(Vec::len).sum()
+	}
+}
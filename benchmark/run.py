@@ -1,5 +1,10 @@
 #!/usr/bin/env python3
 
+# Usage: run.py [TRACE]
+#
+# Runs all benchmarks against all allocators, and plots the results to plots/. If a trace recorded with emma's `trace`
+# feature is given, it is replayed as an additional benchmark.
+
 import json
 import multiprocessing
 import numpy
@@ -8,6 +13,7 @@ import re
 import scipy
 import shutil
 import subprocess
+import sys
 from matplotlib import pyplot as plt
 from pathlib import Path
 from subprocess import run
@@ -18,6 +24,8 @@ BENCHMARKS = [
 	("hoard/cache-thrash", [str(multiprocessing.cpu_count()), "50", "30000", "32", "1"]),
 	("hoard/threadtest", [str(multiprocessing.cpu_count())]),
 ]
+if len(sys.argv) > 1:
+	BENCHMARKS.append(("replay", [str(Path(sys.argv[1]).absolute())]))
 
 ALLOCATORS = [
 	"emma-tls",
@@ -0,0 +1,9 @@
+fn main() {
+	println!("cargo::rerun-if-changed=build.rs");
+	println!("cargo::rustc-check-cfg=cfg(thread_heaps)");
+
+	// Threads hold heaps of their own with both `tls` (via `#[thread_local]`) and `std-tls` (via `std::thread_local!`).
+	if std::env::var_os("CARGO_FEATURE_TLS").is_some() || std::env::var_os("CARGO_FEATURE_STD_TLS").is_some() {
+		println!("cargo::rustc-cfg=thread_heaps");
+	}
+}
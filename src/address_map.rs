@@ -0,0 +1,264 @@
+use core::mem::MaybeUninit;
+use core::num::NonZero;
+use core::ptr::NonNull;
+
+use crate::mmap::{MMapFlags, MMapProt, mmap, munmap, set_name};
+
+/// A hash map from (non-zero) addresses to values that takes its storage directly from the OS, which makes it usable
+/// from within the allocator itself.
+///
+/// The map uses linear probing with backward-shift deletion, so that no tombstones are required. It grows by doubling
+/// its capacity once it is half full.
+#[derive(Debug)]
+pub struct AddressMap<V: Copy> {
+	slots: Option<NonNull<Slot<V>>>,
+	capacity: usize,
+	len: usize,
+}
+
+unsafe impl<V: Copy + Send> Send for AddressMap<V> {}
+
+#[derive(Debug, Copy, Clone)]
+struct Slot<V: Copy> {
+	/// The key of this slot, `0` marks an empty slot.
+	key: usize,
+	value: MaybeUninit<V>,
+}
+
+impl<V: Copy> AddressMap<V> {
+	pub const fn new() -> Self {
+		Self {
+			slots: None,
+			capacity: 0,
+			len: 0,
+		}
+	}
+
+	#[cfg(test)]
+	fn len(&self) -> usize {
+		self.len
+	}
+
+	/// The number of slots, which is the upper bound for indices passed to [`AddressMap::slot`].
+	#[inline]
+	pub fn capacity(&self) -> usize {
+		self.capacity
+	}
+
+	#[inline]
+	fn home(&self, key: usize) -> usize {
+		debug_assert!(self.capacity.is_power_of_two());
+		// Fibonacci hashing, dropping the lowest bits first as they are typically zero due to alignment.
+		(key >> 4).wrapping_mul(0x9E37_79B9_7F4A_7C15) >> (usize::BITS - self.capacity.trailing_zeros())
+	}
+
+	#[inline]
+	unsafe fn slot_ptr(&self, index: usize) -> NonNull<Slot<V>> {
+		debug_assert!(index < self.capacity);
+		unsafe { self.slots.unwrap_unchecked().add(index) }
+	}
+
+	/// Returns the key and value stored in the slot with the given index, if the slot is occupied.
+	pub fn slot(&self, index: usize) -> Option<(usize, &V)> {
+		if index >= self.capacity {
+			return None;
+		}
+
+		let slot = unsafe { self.slot_ptr(index).as_ref() };
+		if slot.key == 0 {
+			None
+		} else {
+			Some((slot.key, unsafe { slot.value.assume_init_ref() }))
+		}
+	}
+
+	fn find(&self, key: usize) -> Option<usize> {
+		debug_assert_ne!(key, 0);
+
+		if self.len == 0 {
+			return None;
+		}
+
+		let mut index = self.home(key);
+		loop {
+			let slot_key = unsafe { self.slot_ptr(index).as_ref().key };
+			if slot_key == key {
+				return Some(index);
+			} else if slot_key == 0 {
+				return None;
+			}
+			index = (index + 1) & (self.capacity - 1);
+		}
+	}
+
+	pub fn get(&self, key: usize) -> Option<&V> {
+		self
+			.find(key)
+			.map(|index| unsafe { self.slot_ptr(index).as_ref().value.assume_init_ref() })
+	}
+
+	/// Inserts the value for the given key, replacing any previous value. Returns `false` if the map could not obtain the
+	/// memory required to store the value. A map that cannot grow fills up beyond half of its capacity instead, but
+	/// always keeps one slot empty.
+	pub fn insert(&mut self, key: usize, value: V) -> bool {
+		debug_assert_ne!(key, 0);
+
+		if (self.len + 1) * 2 > self.capacity && !self.grow() && self.len + 1 >= self.capacity {
+			return false;
+		}
+
+		let mut index = self.home(key);
+		loop {
+			let slot = unsafe { self.slot_ptr(index).as_mut() };
+			if slot.key == 0 {
+				slot.key = key;
+				slot.value.write(value);
+				self.len += 1;
+				return true;
+			} else if slot.key == key {
+				slot.value.write(value);
+				return true;
+			}
+			index = (index + 1) & (self.capacity - 1);
+		}
+	}
+
+	pub fn remove(&mut self, key: usize) -> Option<V> {
+		let mut hole = self.find(key)?;
+		let value = unsafe { self.slot_ptr(hole).as_ref().value.assume_init() };
+		self.len -= 1;
+
+		// Shift all following entries of the cluster back, as long as that does not move them in front of their home slot.
+		let mut index = hole;
+		loop {
+			index = (index + 1) & (self.capacity - 1);
+			let slot = unsafe { *self.slot_ptr(index).as_ref() };
+			if slot.key == 0 {
+				break;
+			}
+
+			let home = self.home(slot.key);
+			let distance_to_hole = hole.wrapping_sub(home) & (self.capacity - 1);
+			let distance_to_index = index.wrapping_sub(home) & (self.capacity - 1);
+			if distance_to_hole <= distance_to_index {
+				unsafe { self.slot_ptr(hole).write(slot) };
+				hole = index;
+			}
+		}
+		unsafe { self.slot_ptr(hole).as_mut().key = 0 };
+
+		Some(value)
+	}
+
+	/// Removes all entries for which `f` returns `false`.
+	pub fn retain(&mut self, mut f: impl FnMut(usize, &V) -> bool) {
+		let mut index = 0;
+		while index < self.capacity {
+			match self.slot(index) {
+				Some((key, value)) if !f(key, value) => {
+					// Removing the entry may shift the next one of its cluster into this slot, which is then checked next.
+					self.remove(key);
+				}
+				_ => index += 1,
+			}
+		}
+	}
+
+	fn grow(&mut self) -> bool {
+		let capacity = if self.capacity == 0 {
+			(4096 / size_of::<Slot<V>>()).max(1).next_power_of_two()
+		} else {
+			self.capacity * 2
+		};
+
+		let Some(slots) = (unsafe { Self::map_slots(capacity) }) else {
+			return false;
+		};
+
+		let old = core::mem::replace(
+			self,
+			Self {
+				slots: Some(slots),
+				capacity,
+				len: 0,
+			},
+		);
+		for index in 0..old.capacity {
+			if let Some((key, &value)) = old.slot(index) {
+				let inserted = self.insert(key, value);
+				debug_assert!(inserted);
+			}
+		}
+		drop(old);
+
+		true
+	}
+
+	unsafe fn map_slots(capacity: usize) -> Option<NonNull<Slot<V>>> {
+		// Anonymous mappings are zero-initialized, which marks all slots as empty.
+		let size = NonZero::new((capacity * size_of::<Slot<V>>() + 4095) & !4095)?;
+		unsafe {
+			let slots = mmap(
+				None,
+				size,
+				MMapProt::READ | MMapProt::WRITE,
+				MMapFlags::PRIVATE | MMapFlags::ANONYMOUS | MMapFlags::NORESERVE,
+				None,
+				0,
+			)
+			.ok()?;
+			set_name(slots, size, c"emma:meta");
+			Some(slots.cast())
+		}
+	}
+}
+
+impl<V: Copy> Drop for AddressMap<V> {
+	fn drop(&mut self) {
+		if let Some(slots) = self.slots {
+			let size = (self.capacity * size_of::<Slot<V>>() + 4095) & !4095;
+			unsafe { munmap(slots.cast(), NonZero::new_unchecked(size)).unwrap() };
+		}
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn insert_get_remove() {
+		let mut map = AddressMap::<usize>::new();
+		for i in 1..10_000 {
+			assert!(map.insert(i * 16, i));
+		}
+		assert_eq!(map.len(), 9_999);
+		for i in 1..10_000 {
+			assert_eq!(map.get(i * 16), Some(&i));
+		}
+		for i in (1..10_000).step_by(3) {
+			assert_eq!(map.remove(i * 16), Some(i));
+		}
+		for i in 1..10_000 {
+			if i % 3 == 1 {
+				assert_eq!(map.get(i * 16), None);
+			} else {
+				assert_eq!(map.get(i * 16), Some(&i));
+			}
+		}
+		assert_eq!((0..map.capacity()).filter_map(|i| map.slot(i)).count(), map.len());
+	}
+
+	#[test]
+	fn retain() {
+		let mut map = AddressMap::<usize>::new();
+		for i in 1..10_000 {
+			assert!(map.insert(i * 16, i));
+		}
+		map.retain(|_, &value| value % 3 != 1);
+		assert_eq!(map.len(), 6_666);
+		for i in 1..10_000 {
+			assert_eq!(map.get(i * 16), (i % 3 != 1).then_some(&i));
+		}
+	}
+}
@@ -1,205 +1,266 @@
+use core::ffi::CStr;
+use core::marker::PhantomData;
 use core::mem::offset_of;
 use core::num::NonZero;
 use core::ptr::{self, NonNull};
 
-use const_format::assertc;
-#[cfg(feature = "tls")]
+#[cfg(any(thread_heaps, feature = "sharded"))]
 use {
+	super::remote_frees::RemoteFrees,
 	crate::emma::{AtomicHeapId, HeapId},
-	core::sync::atomic::{AtomicU32, Ordering},
 };
 
-use crate::mmap::alloc_aligned;
+use super::page::{Geometry, Page, alloc_from_bin};
+use crate::emma::config::Config;
+use crate::emma::huge_pages::{self, HugePagePolicy};
+use crate::emma::instance::InstanceId;
+use crate::emma::{Tier, registry};
+use crate::mmap::{MAdviseAdvice, madvise};
 
-const ARENA_SIZE: u32 = 4 * 1024 * 1024;
-pub const MAXIMUM_OBJECT_ALIGNMENT: u32 = 512 * 1024;
+/// The geometry of the arenas for large objects of the configuration `C`, each of which is a single page.
+#[derive(Debug)]
+pub struct Large<C>(PhantomData<C>);
+
+impl<C: Config> Geometry for Large<C> {
+	type Config = C;
+	const TIER: Tier = Tier::Large;
+	const NAME: &'static CStr = c"emma:large";
+	const ARENA_SIZE: u32 = C::ARENA_SIZE;
+	const PAGE_SIZE: u32 = C::ARENA_SIZE;
+	const MAXIMUM_OBJECT_ALIGNMENT: u32 = C::MAXIMUM_LARGE_OBJECT_ALIGNMENT;
+}
 
+/// An arena holds the objects of a single size, which is the `object_size` of its page. Objects may span multiple
+/// consecutive slots of that size after growing in place.
 #[derive(Debug)]
-struct Arena {
-	#[cfg(feature = "tls")]
+#[repr(C)]
+struct Arena<C> {
+	#[cfg(any(thread_heaps, feature = "sharded"))]
 	owner: AtomicHeapId,
-	page: Page,
+	page: Page<Large<C>>,
+	/// whether the arena is currently advised to be backed by huge pages (which is always the case for `hugetlb`)
+	huge_pages: bool,
+	/// whether the arena is backed by pages from the hugetlb pool
+	hugetlb: bool,
 }
 
-assertc!(
-	ARENA_SIZE.is_power_of_two(),
-	"The arena size ({}) should be a power of two.",
-	ARENA_SIZE
-);
-assertc!(
-	MAXIMUM_OBJECT_ALIGNMENT.is_power_of_two(),
-	"The maximum object size ({}) should be a power of two.",
-	MAXIMUM_OBJECT_ALIGNMENT
-);
-assertc!(size_of::<Arena>() < ARENA_SIZE as usize);
-
-impl Arena {
+impl<C: Config> Arena<C> {
+	/// The number of bytes available for objects in an arena.
+	const INITIAL_RESERVE: u32 = C::ARENA_SIZE - size_of::<Self>() as u32;
+	/// Checks the geometry when the first arena is created, as generic constants cannot be checked up front.
+	const VALID_GEOMETRY: () = assert!(
+		size_of::<Self>() < C::ARENA_SIZE as usize,
+		"The metadata of an arena should leave room for objects."
+	);
+
 	/// Makes a pointer to the arena from any pointer to a location inside the arena.
 	#[inline]
-	unsafe fn from_inner_ptr(p: NonNull<u8>) -> NonNull<Arena> {
-		unsafe { NonNull::new_unchecked(((p.as_ptr() as usize) & !(ARENA_SIZE as usize - 1)) as *mut Arena) }
+	unsafe fn from_inner_ptr(p: NonNull<u8>) -> NonNull<Self> {
+		unsafe { Page::<Large<C>>::arena(p).cast() }
 	}
 
+	/// Accounts for objects being carved from the reserve of this arena, which may promote the arena to huge pages.
 	#[inline]
-	unsafe fn object_offset(p: NonNull<u8>) -> NonZero<u32> {
-		unsafe { NonZero::new_unchecked((p.as_ptr() as u32) % ARENA_SIZE) }
+	unsafe fn carve(arena: NonNull<Self>, bytes_in_reserve: u32) {
+		let arena = arena.as_ptr();
+		unsafe {
+			if !(*arena).huge_pages
+				&& huge_pages::is_mostly_full(C::ARENA_SIZE - bytes_in_reserve, C::ARENA_SIZE)
+				&& huge_pages::policy(Large::<C>::TIER) == HugePagePolicy::WhenMostlyFull
+			{
+				(*arena).huge_pages = huge_pages::promote(NonNull::new_unchecked(arena).cast(), C::ARENA_SIZE as usize);
+			}
+		}
 	}
-}
 
-#[derive(Debug)]
-pub struct Page {
-	pub next_page: Option<NonNull<Page>>,
-	free_list: Option<NonZero<u32>>,
-	#[cfg(feature = "tls")]
-	foreign_free_list: AtomicU32,
-	bytes_in_reserve: u32,
-}
-
-impl Page {
-	#[inline]
-	pub unsafe fn from_new_arena(#[cfg(feature = "tls")] owner: HeapId) -> Option<NonNull<Page>> {
+	/// Accounts for the page of this arena having been reset. Returns whether the physical memory of the arena should be
+	/// released, which is not the case while the arena is backed by huge pages, as that would split them. An arena that
+	/// is promoted only while it is mostly full is demoted again, as it is now empty.
+	unsafe fn reset(arena: NonNull<Self>) -> bool {
+		let arena = arena.as_ptr();
 		unsafe {
-			let region = alloc_aligned(
-				NonZero::new(ARENA_SIZE as usize).unwrap(),
-				NonZero::new(ARENA_SIZE as usize).unwrap(),
-				3,
-			)?;
-
-			region.cast().write(Arena {
-				#[cfg(feature = "tls")]
-				owner: AtomicHeapId::new(owner),
-				page: Page {
-					next_page: None,
-					free_list: None,
-					#[cfg(feature = "tls")]
-					foreign_free_list: AtomicU32::new(0),
-					bytes_in_reserve: ARENA_SIZE - size_of::<Arena>() as u32,
-				},
-			});
-
-			Some(region.byte_add(offset_of!(Arena, page)).cast())
+			if (*arena).hugetlb {
+				// Pages from the hugetlb pool can only be released as a whole.
+				false
+			} else if !(*arena).huge_pages {
+				true
+			} else if huge_pages::policy(Large::<C>::TIER) == HugePagePolicy::WhenMostlyFull {
+				huge_pages::demote(NonNull::new_unchecked(arena).cast(), C::ARENA_SIZE as usize);
+				(*arena).huge_pages = false;
+				true
+			} else {
+				false
+			}
 		}
 	}
+}
 
-	#[inline]
-	pub fn alloc(&mut self, object_size: u32) -> Option<NonNull<u8>> {
-		if let Some(offset) = self.free_list {
-			unsafe {
-				let p = Arena::from_inner_ptr(NonNull::new_unchecked(self).cast())
-					.byte_add(offset.get() as usize)
-					.cast();
-				self.free_list = p.cast::<Option<NonZero<u32>>>().read();
-
-				Some(p)
-			}
-		} else {
-			#[cfg(feature = "tls")]
-			{
-				if let Some(offset) = NonZero::new(self.foreign_free_list.swap(0, Ordering::Acquire)) {
-					unsafe {
-						let p = Arena::from_inner_ptr(NonNull::new_unchecked(self).cast())
-							.byte_add(offset.get() as usize)
-							.cast();
-						self.free_list = p.cast::<Option<NonZero<u32>>>().read();
-
-						return Some(p);
-					}
-				}
-			}
+/// Maps a new arena for objects of `object_size` bytes, and returns its page.
+#[inline]
+unsafe fn from_new_arena<C: Config>(
+	object_size: u32,
+	instance: InstanceId,
+	#[cfg(any(thread_heaps, feature = "sharded"))] owner: HeapId,
+) -> Option<NonNull<Page<Large<C>>>> {
+	let () = Arena::<C>::VALID_GEOMETRY;
+	unsafe {
+		let (region, hugetlb) = registry::map::<C>(
+			NonZero::new(C::ARENA_SIZE as usize).unwrap(),
+			NonZero::new(C::ARENA_SIZE as usize).unwrap(),
+			Large::<C>::TIER,
+			Large::<C>::NAME,
+			instance,
+		)?;
+		let huge_pages = hugetlb || huge_pages::advise_new_mapping(region, C::ARENA_SIZE as usize, Large::<C>::TIER);
 
-			if self.bytes_in_reserve >= object_size {
-				self.bytes_in_reserve -= self.bytes_in_reserve % object_size;
-				unsafe {
-					let p = Arena::from_inner_ptr(NonNull::new_unchecked(self).cast())
-						.cast::<u8>()
-						.byte_add((ARENA_SIZE - self.bytes_in_reserve) as usize);
-					self.bytes_in_reserve -= object_size;
+		let mut page = Page::new(0, Arena::<C>::INITIAL_RESERVE, None);
+		page.object_size = object_size;
+		region.cast().write(Arena::<C> {
+			#[cfg(any(thread_heaps, feature = "sharded"))]
+			owner: AtomicHeapId::new(owner),
+			page,
+			huge_pages,
+			hugetlb,
+		});
 
-					return Some(p);
-				}
-			}
+		Some(region.byte_add(offset_of!(Arena<C>, page)).cast())
+	}
+}
 
-			None
-		}
+/// Allocates an object of `object_size` bytes from the free lists or the reserve of `page`. The reserve is carved from
+/// its start, which is skipped as needed to align the objects.
+#[inline]
+fn alloc_from_page<C: Config>(page: &mut Page<Large<C>>, object_size: u32) -> Option<NonNull<u8>> {
+	if let Some(p) = page.pop() {
+		return Some(p);
+	}
+	if page.bytes_in_reserve < object_size {
+		return None;
 	}
 
-	#[cfg(not(feature = "tls"))]
-	#[inline]
-	pub unsafe fn dealloc(p: NonNull<u8>) {
-		unsafe {
-			let page = &mut Arena::from_inner_ptr(p).as_mut().page;
-			p.cast::<Option<NonZero<u32>>>().write(page.free_list);
-			page.free_list = Some(Arena::object_offset(p));
+	page.bytes_in_reserve -= page.bytes_in_reserve % object_size;
+	unsafe {
+		let arena = Arena::<C>::from_inner_ptr(NonNull::new_unchecked(page).cast());
+		let p = arena
+			.cast::<u8>()
+			.byte_add((C::ARENA_SIZE - page.bytes_in_reserve) as usize);
+		page.bytes_in_reserve -= object_size;
+		Arena::<C>::carve(arena, page.bytes_in_reserve);
+		Some(p)
+	}
+}
+
+/// Links the slots of an object of `object_size` bytes at `p` into a list of their offsets, which ends in `next`.
+/// Returns the offset of the last slot.
+///
+/// An object that was grown in place spans multiple slots of the arena, all of which are freed.
+#[inline]
+unsafe fn link_slots<C: Config>(p: NonNull<u8>, object_size: u32, next: Option<NonZero<u32>>) -> NonZero<u32> {
+	unsafe {
+		let arena = Arena::<C>::from_inner_ptr(p);
+		let slot_size = arena.as_ref().page.object_size;
+		let mut offset = Page::<Large<C>>::object_offset(p);
+		for _ in 1..object_size.div_ceil(slot_size) {
+			let next_offset = offset.checked_add(slot_size).unwrap_unchecked();
+			arena
+				.byte_add(next_offset.get() as usize)
+				.cast::<Option<NonZero<u32>>>()
+				.write(Some(offset));
+			offset = next_offset;
 		}
+		p.cast::<Option<NonZero<u32>>>().write(next);
+		offset
 	}
+}
 
-	#[cfg(feature = "tls")]
-	#[inline]
-	pub unsafe fn dealloc(heap_id: HeapId, p: NonNull<u8>) {
-		unsafe {
-			let arena = Arena::from_inner_ptr(p);
-			let mut page = arena.byte_add(offset_of!(Arena, page)).cast::<Page>();
-
-			let p_offset = Arena::object_offset(p);
-
-			let owner = arena
-				.byte_add(offset_of!(Arena, owner))
-				.cast::<AtomicHeapId>()
-				.as_ref()
-				.load(Ordering::Relaxed);
-			if owner == heap_id {
-				let page = page.as_mut();
-				p.cast::<Option<NonZero<u32>>>().write(page.free_list);
-				page.free_list = Some(p_offset);
-			} else {
-				let free_list = page
-					.byte_add(offset_of!(Page, foreign_free_list))
-					.cast::<AtomicU32>()
-					.as_ref();
-				let mut next = free_list.load(Ordering::Relaxed);
-				loop {
-					p.cast::<Option<NonZero<u32>>>().write(NonZero::new(next));
-					match free_list.compare_exchange(next, p_offset.get(), Ordering::Release, Ordering::Relaxed) {
-						Ok(_) => break,
-						Err(new_next) => next = new_next,
-					}
-				}
-			}
+/// The size of the slots of the arena of `page`, which determines its bin. Objects that were grown in place span
+/// multiple slots, so their size may belong to a different bin.
+#[cfg(not(any(thread_heaps, feature = "sharded")))]
+#[inline]
+pub unsafe fn slot_size<C: Config>(page: NonNull<Page<Large<C>>>) -> u32 {
+	unsafe { page.as_ref().object_size }
+}
+
+/// Frees the object of `object_size` bytes at `p`. Returns its page if the page was full, in which case it is to be
+/// returned to the bin of its [`slot_size`] via [`return_to_bin`](super::page::return_to_bin).
+#[cfg(not(any(thread_heaps, feature = "sharded")))]
+#[inline]
+pub unsafe fn dealloc<C: Config>(p: NonNull<u8>, object_size: u32) -> Option<NonNull<Page<Large<C>>>> {
+	unsafe {
+		let page = Arena::<C>::from_inner_ptr(p)
+			.byte_add(offset_of!(Arena<C>, page))
+			.cast();
+		Page::free(page, |next| link_slots::<C>(p, object_size, next))
+	}
+}
+
+/// Frees the object of `object_size` bytes at `p` on behalf of the heap `heap_id`, which buffers it in `remote_frees`
+/// if the object belongs to another heap.
+#[cfg(any(thread_heaps, feature = "sharded"))]
+#[inline]
+pub unsafe fn dealloc<C: Config>(
+	heap_id: HeapId,
+	remote_frees: Option<&mut RemoteFrees>,
+	p: NonNull<u8>,
+	object_size: u32,
+) {
+	unsafe {
+		let page = Arena::<C>::from_inner_ptr(p)
+			.byte_add(offset_of!(Arena<C>, page))
+			.cast();
+		Page::<Large<C>>::free(heap_id, remote_frees, page, p, |next| {
+			link_slots::<C>(p, object_size, next)
+		});
+	}
+}
+
+/// Tries to grow the object of `old_size` bytes at `p` to `new_size` bytes without moving it. This succeeds if the
+/// object was the last one carved from the reserve of its arena, and the reserve still holds enough bytes.
+///
+/// Must only be called by the heap that owns the arena.
+pub unsafe fn grow_in_place<C: Config>(p: NonNull<u8>, old_size: u32, new_size: u32) -> bool {
+	debug_assert!(old_size < new_size);
+	unsafe {
+		let mut arena = Arena::<C>::from_inner_ptr(p);
+		let page = &mut arena.as_mut().page;
+		let slot_size = page.object_size;
+
+		let end = Page::<Large<C>>::object_offset(p).get() + old_size.div_ceil(slot_size) * slot_size;
+		let additional = (new_size.div_ceil(slot_size) - old_size.div_ceil(slot_size)) * slot_size;
+		if end != C::ARENA_SIZE - page.bytes_in_reserve || page.bytes_in_reserve < additional {
+			return false;
 		}
+
+		page.bytes_in_reserve -= additional;
+		Arena::<C>::carve(arena, page.bytes_in_reserve);
+		true
 	}
 }
 
+/// Allocates an object from the first page of `bin`, see [`alloc_from_bin`]. Once `bin` is empty, a new arena is
+/// mapped.
 #[inline]
-pub unsafe fn alloc(bin: &mut Option<NonNull<Page>>, object_size: u32, #[cfg(feature = "tls")] id: HeapId) -> *mut u8 {
+pub unsafe fn alloc<C: Config>(
+	bin: &mut Option<NonNull<Page<Large<C>>>>,
+	full_pages: &mut Option<NonNull<Page<Large<C>>>>,
+	object_size: u32,
+	instance: InstanceId,
+	#[cfg(any(thread_heaps, feature = "sharded"))] id: HeapId,
+) -> *mut u8 {
 	unsafe {
-		{
-			let mut pp: *mut Option<NonNull<Page>> = bin;
-			let mut p = *bin;
-			while let Some(mut q) = p {
-				let page = q.as_mut();
-
-				if let Some(ret) = page.alloc(object_size) {
-					if p != *bin {
-						*pp.as_mut().unwrap_unchecked() = page.next_page;
-						page.next_page = *bin;
-						*bin = p;
-					}
-					return ret.as_ptr();
-				}
-				pp = &mut page.next_page;
-				p = page.next_page;
-			}
+		if let Some(ret) = alloc_from_bin(bin, full_pages, |page| alloc_from_page(page, object_size)) {
+			return ret.as_ptr();
 		}
 
-		#[cfg(not(feature = "tls"))]
-		let page_from_new_arena = Page::from_new_arena();
-		#[cfg(feature = "tls")]
-		let page_from_new_arena = Page::from_new_arena(id);
+		#[cfg(not(any(thread_heaps, feature = "sharded")))]
+		let page_from_new_arena = from_new_arena(object_size, instance);
+		#[cfg(any(thread_heaps, feature = "sharded"))]
+		let page_from_new_arena = from_new_arena(object_size, instance, id);
 		if let Some(mut page) = page_from_new_arena {
 			page.as_mut().next_page = *bin;
 			*bin = Some(page);
 
-			let ret = page.as_mut().alloc(object_size);
+			let ret = alloc_from_page(page.as_mut(), object_size);
 			debug_assert!(ret.is_some());
 			ret.unwrap_unchecked().as_ptr()
 		} else {
@@ -208,3 +269,32 @@ pub unsafe fn alloc(bin: &mut Option<NonNull<Page>>, object_size: u32, #[cfg(fea
 		}
 	}
 }
+
+/// Resets the arenas in `bin` that no longer hold any objects, releasing their physical memory where the huge page
+/// policy allows it.
+pub unsafe fn trim<C: Config>(bin: &mut Option<NonNull<Page<Large<C>>>>, object_size: u32) {
+	unsafe {
+		let mut p = *bin;
+		while let Some(mut q) = p {
+			let page = q.as_mut();
+			// Only the start of the reserve is skipped to align the objects, so this is exact.
+			if page.bytes_in_reserve != Arena::<C>::INITIAL_RESERVE && page.is_empty(Arena::<C>::INITIAL_RESERVE, object_size)
+			{
+				page.free_list = None;
+				page.bytes_in_reserve = Arena::<C>::INITIAL_RESERVE;
+
+				let arena = Arena::<C>::from_inner_ptr(q.cast());
+				if Arena::<C>::reset(arena) {
+					let start = size_of::<Arena<C>>().next_multiple_of(4096);
+					// There is nothing we could do about a failure, and the arena remains usable either way.
+					let _ = madvise(
+						arena.byte_add(start).cast(),
+						C::ARENA_SIZE as usize - start,
+						MAdviseAdvice::DONTNEED,
+					);
+				}
+			}
+			p = page.next_page;
+		}
+	}
+}
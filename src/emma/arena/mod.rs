@@ -1,3 +1,6 @@
 pub mod large_objects;
-pub mod medium_objects;
-pub mod small_objects;
+pub mod page;
+pub mod paged_objects;
+pub mod pool;
+#[cfg(any(thread_heaps, feature = "sharded"))]
+pub mod remote_frees;
@@ -0,0 +1,428 @@
+//! The pages that objects of all tiers are allocated from, and the lists that heaps keep them in: the bins, the list of
+//! full pages and, with thread heaps or shards, the stack of pages that are handed back by other heaps. The tiers only
+//! differ in the [`Geometry`] of their arenas and in how objects are carved from the reserve of a page.
+
+use core::ffi::CStr;
+use core::marker::PhantomData;
+use core::num::NonZero;
+use core::ptr::NonNull;
+
+#[cfg(any(thread_heaps, feature = "sharded"))]
+use {
+	super::remote_frees::{FULL, RemoteFrees},
+	crate::emma::{AtomicHeapId, HeapId, reclaimed_pages},
+	core::sync::atomic::{AtomicU32, AtomicUsize, Ordering},
+};
+
+use crate::emma::{Config, Tier};
+
+/// The geometry of the arenas of a tier, as derived from [`Geometry::Config`]. With thread heaps or shards, the arenas
+/// of every tier start with the id of the heap that owns them.
+pub trait Geometry {
+	/// The configuration of the [`Emma`](crate::emma::Emma) whose heaps allocate from the arenas.
+	type Config: Config;
+	/// The tier whose arenas have this geometry.
+	const TIER: Tier;
+	/// The name that arenas are labeled with (see [`crate::mmap::set_name`]).
+	const NAME: &'static CStr;
+	/// The size and alignment of the arenas.
+	const ARENA_SIZE: u32;
+	/// The size of the pages that arenas are split into, which is the arena size if every arena is a single page.
+	const PAGE_SIZE: u32;
+	/// The largest alignment that objects of the tier may have.
+	const MAXIMUM_OBJECT_ALIGNMENT: u32;
+}
+
+#[derive(Debug)]
+pub struct Page<G> {
+	pub next_page: Option<NonNull<Page<G>>>,
+	/// the index of this page in its arena
+	pub(super) page_number: u32,
+	/// the free_list is an arena-relative byte offset
+	pub(super) free_list: Option<NonZero<u32>>,
+	/// the foreign_free_list is an arena-relative byte offset
+	#[cfg(any(thread_heaps, feature = "sharded"))]
+	pub(super) foreign_free_list: AtomicU32,
+	/// the amount of bytes that have not yet been allocated or added to a `free_list`
+	pub(super) bytes_in_reserve: u32,
+	/// the size of the objects on this page, while it is assigned to a bin
+	pub(super) object_size: u32,
+	/// whether the physical memory of the page has been released, and the page has not been used since
+	pub(super) purged: bool,
+	/// the next page in the list of full pages, while `in_full_list` is set
+	pub(super) next_full: Option<NonNull<Page<G>>>,
+	/// whether the page is in the list of full pages, which it only leaves in [`sweep_full`], even if it has been
+	/// returned to its bin before
+	in_full_list: bool,
+	/// whether the page is full (see [`Page::mark_full`]), which is [`FULL`] in the `foreign_free_list` with `tls`
+	#[cfg(not(any(thread_heaps, feature = "sharded")))]
+	full: bool,
+	/// the next page on the stack of pages that are handed back to the heap, see [`reclaim`]
+	#[cfg(any(thread_heaps, feature = "sharded"))]
+	next_reclaimed: Option<NonNull<Page<G>>>,
+	geometry: PhantomData<G>,
+}
+
+impl<G: Geometry> Page<G> {
+	/// Creates the page with the index `page_number` of a new arena, whose reserve holds `bytes_in_reserve` bytes.
+	pub(super) const fn new(page_number: u32, bytes_in_reserve: u32, next_page: Option<NonNull<Page<G>>>) -> Self {
+		Self {
+			next_page,
+			page_number,
+			free_list: None,
+			#[cfg(any(thread_heaps, feature = "sharded"))]
+			foreign_free_list: AtomicU32::new(0),
+			bytes_in_reserve,
+			object_size: 0,
+			purged: false,
+			next_full: None,
+			in_full_list: false,
+			#[cfg(not(any(thread_heaps, feature = "sharded")))]
+			full: false,
+			#[cfg(any(thread_heaps, feature = "sharded"))]
+			next_reclaimed: None,
+			geometry: PhantomData,
+		}
+	}
+
+	/// Makes a pointer to the arena from any pointer to a location inside the arena.
+	#[inline]
+	pub(super) unsafe fn arena(p: NonNull<u8>) -> NonNull<u8> {
+		unsafe { NonNull::new_unchecked(((p.as_ptr() as usize) & !(G::ARENA_SIZE as usize - 1)) as *mut u8) }
+	}
+
+	#[inline]
+	pub(super) unsafe fn object_offset(p: NonNull<u8>) -> NonZero<u32> {
+		unsafe { NonZero::new_unchecked((p.as_ptr() as u32) % G::ARENA_SIZE) }
+	}
+
+	/// The heap that owns the arena containing `p`.
+	#[cfg(any(thread_heaps, feature = "sharded"))]
+	#[inline]
+	unsafe fn owner<'a>(p: NonNull<u8>) -> &'a AtomicHeapId {
+		unsafe { Self::arena(p).cast::<AtomicHeapId>().as_ref() }
+	}
+
+	/// Returns whether the arena containing `p` is owned by the heap `heap_id`.
+	#[cfg(any(thread_heaps, feature = "sharded"))]
+	#[inline]
+	pub unsafe fn is_owned_by(heap_id: HeapId, p: NonNull<u8>) -> bool {
+		unsafe { Self::owner(p).load(Ordering::Relaxed) == heap_id }
+	}
+
+	/// Takes an object from the free lists of this page, if there is any.
+	#[inline]
+	pub(super) fn pop(&mut self) -> Option<NonNull<u8>> {
+		let offset = match self.free_list {
+			Some(offset) => offset,
+			#[cfg(any(thread_heaps, feature = "sharded"))]
+			None => NonZero::new(self.foreign_free_list.swap(0, Ordering::Acquire))?,
+			#[cfg(not(any(thread_heaps, feature = "sharded")))]
+			None => return None,
+		};
+		unsafe {
+			let p = Self::arena(NonNull::new_unchecked(self).cast()).byte_add(offset.get() as usize);
+			self.free_list = p.cast::<Option<NonZero<u32>>>().read();
+			Some(p)
+		}
+	}
+
+	/// Moves all objects on the foreign free list to the (local) free list.
+	#[cfg(any(thread_heaps, feature = "sharded"))]
+	unsafe fn collect_foreign_free_list(&mut self) {
+		let Some(foreign) = NonZero::new(self.foreign_free_list.swap(0, Ordering::Acquire)) else {
+			return;
+		};
+
+		unsafe {
+			let arena = Self::arena(NonNull::new_unchecked(self).cast());
+			let mut last = foreign;
+			while let Some(next) = arena
+				.byte_add(last.get() as usize)
+				.cast::<Option<NonZero<u32>>>()
+				.read()
+			{
+				last = next;
+			}
+			arena
+				.byte_add(last.get() as usize)
+				.cast::<Option<NonZero<u32>>>()
+				.write(self.free_list);
+		}
+		self.free_list = Some(foreign);
+	}
+
+	/// Returns whether all objects of `object_size` bytes that have been carved from the reserve of this page, which
+	/// initially held `initial_reserve` bytes, have been released again. Carving must not skip more than `object_size`
+	/// bytes in total.
+	pub(super) unsafe fn is_empty(&mut self, initial_reserve: u32, object_size: u32) -> bool {
+		#[cfg(any(thread_heaps, feature = "sharded"))]
+		unsafe {
+			self.collect_foreign_free_list()
+		};
+
+		let carved = (initial_reserve - self.bytes_in_reserve) / object_size;
+		let arena = unsafe { Self::arena(NonNull::new_unchecked(self).cast()) };
+		let mut free = 0;
+		let mut next = self.free_list;
+		while let Some(offset) = next {
+			free += 1;
+			next = unsafe {
+				arena
+					.byte_add(offset.get() as usize)
+					.cast::<Option<NonZero<u32>>>()
+					.read()
+			};
+		}
+		debug_assert!(free <= carved);
+		free == carved
+	}
+
+	/// Marks a page that has no space left as full, so that it can be moved to the list of full pages. Fails if other
+	/// threads have freed objects on the page in the meantime, in which case the page is to be allocated from again.
+	#[inline]
+	fn mark_full(&mut self) -> bool {
+		#[cfg(not(any(thread_heaps, feature = "sharded")))]
+		{
+			self.full = true;
+			true
+		}
+		#[cfg(any(thread_heaps, feature = "sharded"))]
+		self
+			.foreign_free_list
+			.compare_exchange(0, FULL, Ordering::Relaxed, Ordering::Relaxed)
+			.is_ok()
+	}
+
+	/// Returns whether the page is still marked as full, rather than having been returned to its bin.
+	#[inline]
+	fn is_full(&self) -> bool {
+		#[cfg(not(any(thread_heaps, feature = "sharded")))]
+		{
+			self.full
+		}
+		#[cfg(any(thread_heaps, feature = "sharded"))]
+		{
+			self.foreign_free_list.load(Ordering::Relaxed) & FULL != 0
+		}
+	}
+
+	/// Hands a page whose [`FULL`] mark has just been cleared back to the heap owning it, which returns the page to its
+	/// bin in [`reclaim`].
+	#[cfg(any(thread_heaps, feature = "sharded"))]
+	unsafe fn hand_back(page: NonNull<Self>) {
+		unsafe {
+			let owner = Self::owner(page.cast()).load(Ordering::Relaxed);
+			push_reclaimed(reclaimed_pages::<G::Config>(owner, G::TIER), page);
+		}
+	}
+
+	/// Hands the page of the foreign free list `list` back to the heap owning it, see [`Page::hand_back`].
+	#[cfg(any(thread_heaps, feature = "sharded"))]
+	unsafe fn hand_back_list(list: NonNull<AtomicU32>) {
+		unsafe { Self::hand_back(list.byte_sub(core::mem::offset_of!(Self, foreign_free_list)).cast()) }
+	}
+
+	/// Frees the object at `p` to `page`. `link` is passed the offset of the object that the object is to be linked in
+	/// front of, and returns the offset of the object. Returns the page if it was full, in which case it is to be
+	/// returned to its bin via [`return_to_bin`].
+	#[cfg(not(any(thread_heaps, feature = "sharded")))]
+	#[inline]
+	pub(super) unsafe fn free(
+		mut page: NonNull<Self>,
+		link: impl FnOnce(Option<NonZero<u32>>) -> NonZero<u32>,
+	) -> Option<NonNull<Self>> {
+		let page_ref = unsafe { page.as_mut() };
+		page_ref.free_list = Some(link(page_ref.free_list));
+		core::mem::replace(&mut page_ref.full, false).then_some(page)
+	}
+
+	/// Frees the object at `p` to `page` on behalf of the heap `heap_id`, see [`RemoteFrees::free`] for `link`. If the
+	/// page was full, it is handed back to the heap owning it.
+	#[cfg(any(thread_heaps, feature = "sharded"))]
+	#[inline]
+	pub(super) unsafe fn free(
+		heap_id: HeapId,
+		remote_frees: Option<&mut RemoteFrees>,
+		mut page: NonNull<Self>,
+		p: NonNull<u8>,
+		link: impl Fn(Option<NonZero<u32>>) -> NonZero<u32>,
+	) {
+		unsafe {
+			if Self::is_owned_by(heap_id, p) {
+				let page_ref = page.as_mut();
+				page_ref.free_list = Some(link(page_ref.free_list));
+				if page_ref.foreign_free_list.load(Ordering::Relaxed) & FULL != 0
+					&& page_ref.foreign_free_list.fetch_and(!FULL, Ordering::Relaxed) & FULL != 0
+				{
+					Self::hand_back(page);
+				}
+				return;
+			}
+
+			let free_list = page
+				.byte_add(core::mem::offset_of!(Self, foreign_free_list))
+				.cast::<AtomicU32>();
+			if let Some(remote_frees) = remote_frees {
+				remote_frees.free(free_list, p, link, Self::hand_back_list);
+				return;
+			}
+
+			let free_list = free_list.as_ref();
+			let mut next = free_list.load(Ordering::Relaxed);
+			loop {
+				let first = link(NonZero::new(next & !FULL));
+				match free_list.compare_exchange(next, first.get(), Ordering::Release, Ordering::Relaxed) {
+					Ok(_) => break,
+					Err(new_next) => next = new_next,
+				}
+			}
+			if next & FULL != 0 {
+				Self::hand_back(page);
+			}
+		}
+	}
+}
+
+/// Allocates an object from the first page of `bin` via `alloc`. Pages without space left are moved to `full_pages`,
+/// so that each page is only skipped once, until it is returned to `bin` after an object on it was freed.
+#[inline]
+pub(super) unsafe fn alloc_from_bin<G: Geometry>(
+	bin: &mut Option<NonNull<Page<G>>>,
+	full_pages: &mut Option<NonNull<Page<G>>>,
+	mut alloc: impl FnMut(&mut Page<G>) -> Option<NonNull<u8>>,
+) -> Option<NonNull<u8>> {
+	while let Some(mut p) = *bin {
+		let page = unsafe { p.as_mut() };
+
+		if let Some(ret) = alloc(page) {
+			return Some(ret);
+		}
+		if page.mark_full() {
+			*bin = page.next_page;
+			if !page.in_full_list {
+				page.next_full = *full_pages;
+				page.in_full_list = true;
+				*full_pages = Some(p);
+			}
+		}
+	}
+	None
+}
+
+/// Returns a page that [`Page::free`] found to be full to `bin`. It stays in the list of full pages until the next
+/// call to [`sweep_full`].
+#[cfg(not(any(thread_heaps, feature = "sharded")))]
+#[inline]
+pub unsafe fn return_to_bin<G>(bin: &mut Option<NonNull<Page<G>>>, mut page: NonNull<Page<G>>) {
+	unsafe { page.as_mut().next_page = *bin };
+	*bin = Some(page);
+}
+
+/// Pushes `page` onto the stack `reclaimed` of a heap.
+#[cfg(any(thread_heaps, feature = "sharded"))]
+unsafe fn push_reclaimed<G>(reclaimed: &AtomicUsize, page: NonNull<Page<G>>) {
+	let mut top = reclaimed.load(Ordering::Relaxed);
+	loop {
+		unsafe { (*page.as_ptr()).next_reclaimed = NonNull::new(top as *mut Page<G>) };
+		match reclaimed.compare_exchange_weak(top, page.as_ptr() as usize, Ordering::Release, Ordering::Relaxed) {
+			Ok(_) => return,
+			Err(current) => top = current,
+		}
+	}
+}
+
+/// Returns the `reclaimed` pages, which have been handed back to the heap `from`, to the bins of the heap `id`, which
+/// `bin_index` maps object sizes to. `from` is either `id` itself or an orphan that `id` adopts, whose arenas are
+/// handed over to `id` here if adopting its lists did not already, as their pages were in none of them. Pages whose
+/// arena has been adopted by another heap in the meantime are passed on to that heap.
+#[cfg(any(thread_heaps, feature = "sharded"))]
+pub unsafe fn reclaim<G: Geometry>(
+	reclaimed: Option<NonNull<Page<G>>>,
+	bins: &mut [Option<NonNull<Page<G>>>],
+	bin_index: impl Fn(u32) -> usize,
+	from: HeapId,
+	id: HeapId,
+) {
+	let mut next = reclaimed;
+	while let Some(mut page) = next {
+		unsafe {
+			next = page.as_ref().next_reclaimed;
+			let owner = Page::<G>::owner(page.cast());
+			if owner.load(Ordering::Relaxed) == from {
+				owner.store(id, Ordering::Relaxed);
+			}
+			let owner = owner.load(Ordering::Relaxed);
+			if owner == id {
+				let bin = &mut bins[bin_index(page.as_ref().object_size)];
+				page.as_mut().next_page = *bin;
+				*bin = Some(page);
+			} else {
+				push_reclaimed(reclaimed_pages::<G::Config>(owner, G::TIER), page);
+			}
+		}
+	}
+}
+
+/// Removes the pages that have been returned to their bins from `full_pages`. This must happen before any of them is
+/// returned to a reserve list, from which its arena may be donated to another heap.
+pub unsafe fn sweep_full<G: Geometry>(full_pages: &mut Option<NonNull<Page<G>>>) {
+	unsafe {
+		let mut pp: *mut Option<NonNull<Page<G>>> = full_pages;
+		while let Some(mut p) = *pp {
+			let page = p.as_mut();
+			if page.is_full() {
+				pp = &raw mut page.next_full;
+			} else {
+				*pp = page.next_full;
+				page.in_full_list = false;
+			}
+		}
+	}
+}
+
+/// Moves all pages of the list `from`, which `next` links, to the front of the list `to`, handing their arenas over to
+/// the heap `owner`.
+#[cfg(thread_heaps)]
+unsafe fn adopt_list<G: Geometry>(
+	to: &mut Option<NonNull<Page<G>>>,
+	from: &mut Option<NonNull<Page<G>>>,
+	owner: HeapId,
+	next: impl Fn(&mut Page<G>) -> &mut Option<NonNull<Page<G>>>,
+) {
+	unsafe {
+		let Some(mut last) = *from else {
+			return;
+		};
+		loop {
+			Page::<G>::owner(last.cast()).store(owner, Ordering::Relaxed);
+			match *next(last.as_mut()) {
+				Some(next) => last = next,
+				None => break,
+			}
+		}
+		*next(last.as_mut()) = *to;
+		*to = from.take();
+	}
+}
+
+/// Moves all pages of the list `from` to the front of the list `to`, handing their arenas over to the heap `owner`.
+#[cfg(thread_heaps)]
+pub unsafe fn adopt<G: Geometry>(
+	to: &mut Option<NonNull<Page<G>>>,
+	from: &mut Option<NonNull<Page<G>>>,
+	owner: HeapId,
+) {
+	unsafe { adopt_list(to, from, owner, |page| &mut page.next_page) }
+}
+
+/// Moves all pages of the list of full pages `from` to the front of the list of full pages `to`, handing their arenas
+/// over to the heap `owner`.
+#[cfg(thread_heaps)]
+pub unsafe fn adopt_full<G: Geometry>(
+	to: &mut Option<NonNull<Page<G>>>,
+	from: &mut Option<NonNull<Page<G>>>,
+	owner: HeapId,
+) {
+	unsafe { adopt_list(to, from, owner, |page| &mut page.next_full) }
+}
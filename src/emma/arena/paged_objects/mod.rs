@@ -0,0 +1,547 @@
+//! Arenas that are split into pages of a fixed size, each of which holds objects of a single size. Small and medium
+//! objects are allocated from such arenas, which only differ in their [`Geometry`].
+
+use core::ffi::CStr;
+use core::marker::PhantomData;
+use core::mem::offset_of;
+use core::num::NonZero;
+use core::ptr::{self, NonNull};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+#[cfg(any(thread_heaps, feature = "sharded"))]
+use {
+	super::remote_frees::RemoteFrees,
+	crate::emma::{AtomicHeapId, HeapId},
+};
+
+use super::page::{Geometry, Page, alloc_from_bin};
+use super::pool::ArenaPool;
+use crate::emma::config::Config;
+use crate::emma::huge_pages::{self, HugePagePolicy};
+use crate::emma::instance::InstanceId;
+use crate::emma::{Tier, registry};
+use crate::mmap::{MAdviseAdvice, madvise};
+
+/// The geometry of the arenas for small objects of the configuration `C`.
+#[derive(Debug)]
+pub struct Small<C>(PhantomData<C>);
+
+impl<C: Config> Geometry for Small<C> {
+	type Config = C;
+	const TIER: Tier = Tier::Small;
+	const NAME: &'static CStr = c"emma:small";
+	const ARENA_SIZE: u32 = C::ARENA_SIZE;
+	const PAGE_SIZE: u32 = C::SMALL_PAGE_SIZE;
+	const MAXIMUM_OBJECT_ALIGNMENT: u32 = C::MAXIMUM_SMALL_OBJECT_ALIGNMENT;
+}
+
+/// The geometry of the arenas for medium objects of the configuration `C`.
+#[derive(Debug)]
+pub struct Medium<C>(PhantomData<C>);
+
+impl<C: Config> Geometry for Medium<C> {
+	type Config = C;
+	const TIER: Tier = Tier::Medium;
+	const NAME: &'static CStr = c"emma:medium";
+	const ARENA_SIZE: u32 = C::ARENA_SIZE;
+	const PAGE_SIZE: u32 = C::MEDIUM_PAGE_SIZE;
+	const MAXIMUM_OBJECT_ALIGNMENT: u32 = C::MAXIMUM_MEDIUM_OBJECT_ALIGNMENT;
+}
+
+/// The metadata at the start of an arena, which is followed by its pages (see [`Arena::pages`]).
+#[derive(Debug)]
+#[repr(C)]
+struct Arena<G> {
+	#[cfg(any(thread_heaps, feature = "sharded"))]
+	owner: AtomicHeapId,
+	/// the number of pages that are currently assigned to a bin (rather than to a reserve list)
+	pages_in_use: u32,
+	/// whether the arena is currently advised to be backed by huge pages (which is always the case for `hugetlb`)
+	huge_pages: bool,
+	/// whether the arena is backed by pages from the hugetlb pool
+	hugetlb: bool,
+	/// whether the arena has been collapsed into huge pages by [`collapse`]
+	collapsed: bool,
+	/// the number of consecutive calls to [`collapse`] that found this arena to be mostly full
+	dense_epochs: u8,
+	/// the epoch of the last call to [`collapse`] that visited this arena
+	last_epoch: u32,
+	/// the number of pages whose physical memory has been released and that have not been used since
+	purged_pages: u32,
+	/// the next arena in the pool (see [`new_pool`]), while this arena is in it
+	pool_next: AtomicUsize,
+	/// whether the arena is being donated to the pool by [`donate`]
+	donated: bool,
+	geometry: PhantomData<G>,
+}
+
+/// Creates a pool for empty arenas, which is shared between all heaps of an instance.
+pub const fn new_pool<G: Geometry>() -> ArenaPool {
+	ArenaPool::new(G::ARENA_SIZE as usize, offset_of!(Arena<G>, pool_next))
+}
+
+impl<G: Geometry> Arena<G> {
+	const PAGES_PER_ARENA: u32 = G::ARENA_SIZE / G::PAGE_SIZE;
+	/// The number of pages a heap keeps in its reserve before it donates empty arenas to the pool of its instance.
+	const RESERVE_THRESHOLD: u32 = Self::PAGES_PER_ARENA;
+	const PAGES_OFFSET: usize = size_of::<Self>().next_multiple_of(align_of::<Page<G>>());
+	/// The size of the metadata at the start of the first page, which includes all pages.
+	const METADATA_ZONE_SIZE: u32 = ((Self::PAGES_OFFSET + Self::PAGES_PER_ARENA as usize * size_of::<Page<G>>()) as u32)
+		.next_multiple_of(G::MAXIMUM_OBJECT_ALIGNMENT);
+	/// Checks the geometry when the first arena is created, as generic constants cannot be checked up front.
+	const VALID_GEOMETRY: () = {
+		assert!(
+			G::ARENA_SIZE.is_power_of_two(),
+			"The arena size should be a power of two."
+		);
+		assert!(
+			G::PAGE_SIZE.is_power_of_two(),
+			"The page size should be a power of two."
+		);
+		assert!(
+			G::MAXIMUM_OBJECT_ALIGNMENT.is_power_of_two(),
+			"The maximum object alignment should be a power of two."
+		);
+		assert!(G::ARENA_SIZE.is_multiple_of(G::PAGE_SIZE));
+		assert!(G::PAGE_SIZE.is_multiple_of(G::MAXIMUM_OBJECT_ALIGNMENT));
+		assert!(
+			Self::METADATA_ZONE_SIZE < G::PAGE_SIZE,
+			"The metadata of an arena should leave room for objects on its first page."
+		);
+	};
+
+	/// Makes a pointer to the arena from any pointer to a location inside the arena.
+	#[inline]
+	unsafe fn from_inner_ptr(p: NonNull<u8>) -> NonNull<Self> {
+		unsafe { Page::<G>::arena(p).cast() }
+	}
+
+	/// Returns the first of the [`Arena::PAGES_PER_ARENA`] pages of `arena`, which follow its metadata.
+	#[inline]
+	unsafe fn pages(arena: NonNull<Self>) -> NonNull<Page<G>> {
+		unsafe { arena.byte_add(Self::PAGES_OFFSET).cast() }
+	}
+
+	/// Accounts for `page` of this arena being assigned to a bin, which may promote the arena to huge pages.
+	#[inline]
+	unsafe fn take_page(arena: NonNull<Self>, page: &mut Page<G>) {
+		let arena = arena.as_ptr();
+		unsafe {
+			(*arena).pages_in_use += 1;
+			if core::mem::replace(&mut page.purged, false) {
+				(*arena).purged_pages -= 1;
+			}
+			if !(*arena).huge_pages
+				&& huge_pages::is_mostly_full((*arena).pages_in_use, Self::PAGES_PER_ARENA)
+				&& huge_pages::policy(G::TIER) == HugePagePolicy::WhenMostlyFull
+			{
+				(*arena).huge_pages = huge_pages::promote(NonNull::new_unchecked(arena).cast(), G::ARENA_SIZE as usize);
+			}
+		}
+	}
+
+	/// Accounts for a page of this arena that was reset and returned to a reserve list. Returns whether the physical
+	/// memory of the page should be released, which is not the case while the arena is backed by huge pages, as that
+	/// would split them.
+	///
+	/// An arena that is promoted only while it is mostly full, or that was collapsed, is returned to normal pages once
+	/// it has become mostly empty, at which point all pages that were returned in the meantime are released.
+	unsafe fn return_page(arena: NonNull<Self>) -> bool {
+		let arena_p = arena.as_ptr();
+		unsafe {
+			(*arena_p).pages_in_use -= 1;
+			if (*arena_p).hugetlb {
+				// Pages from the hugetlb pool can only be released as a whole.
+				return false;
+			} else if !(*arena_p).huge_pages && !(*arena_p).collapsed {
+				return true;
+			}
+
+			if huge_pages::is_mostly_empty((*arena_p).pages_in_use, Self::PAGES_PER_ARENA) {
+				if (*arena_p).huge_pages && huge_pages::policy(G::TIER) == HugePagePolicy::WhenMostlyFull {
+					huge_pages::demote(arena.cast(), G::ARENA_SIZE as usize);
+					(*arena_p).huge_pages = false;
+				}
+				(*arena_p).collapsed = false;
+
+				if !(*arena_p).huge_pages {
+					let pages = Self::pages(arena);
+					for i in 0..Self::PAGES_PER_ARENA as usize {
+						let page = pages.add(i);
+						// Pages in a bin always have at least one object carved from their reserve.
+						if page.as_ref().bytes_in_reserve == initial_reserve(page.as_ref()) {
+							release(page);
+						}
+					}
+				}
+			}
+			false
+		}
+	}
+}
+
+/// The first page of an arena, which is to be assigned to a bin, followed by the first and last of the remaining
+/// pages, which are linked to each other.
+type NewPages<G> = (NonNull<Page<G>>, NonNull<Page<G>>, NonNull<Page<G>>);
+
+/// Maps a new arena, and returns its first page, which is to be assigned to a bin, followed by the first and last of
+/// the remaining pages, which are linked to each other.
+#[inline]
+unsafe fn from_new_arena<G: Geometry>(
+	instance: InstanceId,
+	#[cfg(any(thread_heaps, feature = "sharded"))] owner: HeapId,
+) -> Option<NewPages<G>> {
+	let () = Arena::<G>::VALID_GEOMETRY;
+	let pages_per_arena = Arena::<G>::PAGES_PER_ARENA as usize;
+
+	let (region, hugetlb) = unsafe {
+		registry::map::<G::Config>(
+			NonZero::new(G::ARENA_SIZE as usize).unwrap(),
+			NonZero::new(G::ARENA_SIZE as usize).unwrap(),
+			G::TIER,
+			G::NAME,
+			instance,
+		)?
+	};
+	let huge_pages = hugetlb || unsafe { huge_pages::advise_new_mapping(region, G::ARENA_SIZE as usize, G::TIER) };
+
+	unsafe {
+		let arena = region.cast::<Arena<G>>();
+		arena.write(Arena {
+			#[cfg(any(thread_heaps, feature = "sharded"))]
+			owner: AtomicHeapId::new(owner),
+			// The first page is handed out immediately.
+			pages_in_use: 1,
+			huge_pages,
+			hugetlb,
+			collapsed: false,
+			dense_epochs: 0,
+			last_epoch: 0,
+			purged_pages: 0,
+			pool_next: AtomicUsize::new(0),
+			donated: false,
+			geometry: PhantomData,
+		});
+
+		let pages = Arena::pages(arena);
+		pages.write(Page::new(0, G::PAGE_SIZE - Arena::<G>::METADATA_ZONE_SIZE, None));
+		for i in 1..pages_per_arena {
+			let next_page = (i < pages_per_arena - 1).then(|| pages.add(i + 1));
+			pages.add(i).write(Page::new(i as u32, G::PAGE_SIZE, next_page));
+		}
+
+		Some((pages, pages.add(1), pages.add(pages_per_arena - 1)))
+	}
+}
+
+/// Takes an empty arena from `pool`, which is returned just like [`from_new_arena`] does.
+#[inline]
+unsafe fn from_pool<G: Geometry>(
+	pool: &ArenaPool,
+	#[cfg(any(thread_heaps, feature = "sharded"))] owner: HeapId,
+) -> Option<NewPages<G>> {
+	let pages_per_arena = Arena::<G>::PAGES_PER_ARENA as usize;
+	let arena = pool.pop()?.cast::<Arena<G>>();
+	unsafe {
+		let arena_p = arena.as_ptr();
+		debug_assert_eq!((*arena_p).pages_in_use, 0);
+		#[cfg(any(thread_heaps, feature = "sharded"))]
+		(*arena_p).owner.store(owner, Ordering::Relaxed);
+		(*arena_p).donated = false;
+		(*arena_p).dense_epochs = 0;
+
+		let mut pages = Arena::pages(arena);
+		for i in 0..pages_per_arena {
+			let page = pages.add(i).as_ptr();
+			debug_assert_eq!((*page).bytes_in_reserve, initial_reserve(&*page));
+			(*page).next_page = if i == 0 || i == pages_per_arena - 1 {
+				None
+			} else {
+				Some(pages.add(i + 1))
+			};
+		}
+		// The first page is handed out immediately.
+		Arena::take_page(arena, pages.as_mut());
+
+		Some((pages, pages.add(1), pages.add(pages_per_arena - 1)))
+	}
+}
+
+/// Returns the page that holds the object at `p`.
+#[inline]
+unsafe fn page_of<G: Geometry>(p: NonNull<u8>) -> NonNull<Page<G>> {
+	unsafe {
+		let page_number = ((p.as_ptr() as usize) & (G::ARENA_SIZE as usize - 1)) / G::PAGE_SIZE as usize;
+		Arena::pages(Arena::<G>::from_inner_ptr(p)).add(page_number)
+	}
+}
+
+#[inline]
+fn is_on_page<G: Geometry>(page: &Page<G>, p: NonNull<u8>) -> bool {
+	unsafe { page_of::<G>(p) == NonNull::from(page) }
+}
+
+/// The number of bytes available for objects on `page`.
+#[inline]
+fn initial_reserve<G: Geometry>(page: &Page<G>) -> u32 {
+	if page.page_number == 0 {
+		G::PAGE_SIZE - Arena::<G>::METADATA_ZONE_SIZE
+	} else {
+		G::PAGE_SIZE
+	}
+}
+
+/// Allocates an object of `object_size` bytes from the free lists or the reserve of `page`.
+#[inline]
+fn alloc_from_page<G: Geometry>(page: &mut Page<G>, object_size: u32) -> Option<NonNull<u8>> {
+	if let Some(p) = page.pop() {
+		debug_assert!(is_on_page(page, p));
+		return Some(p);
+	}
+	if page.bytes_in_reserve < object_size {
+		return None;
+	}
+
+	unsafe {
+		let p = Page::<G>::arena(NonNull::new_unchecked(page).cast())
+			.byte_add(((page.page_number + 1) * G::PAGE_SIZE - page.bytes_in_reserve) as usize);
+		page.bytes_in_reserve -= object_size;
+
+		if page.bytes_in_reserve % 4096 >= object_size {
+			page.bytes_in_reserve -= object_size;
+			let mut q = p.byte_add(object_size as usize);
+			let mut offset = Page::<G>::object_offset(q);
+			page.free_list = Some(offset);
+
+			while page.bytes_in_reserve % 4096 >= object_size {
+				page.bytes_in_reserve -= object_size;
+				let next = q.byte_add(object_size as usize);
+				offset = offset.checked_add(object_size).unwrap_unchecked();
+				q.cast::<Option<NonZero<u32>>>().write(Some(offset));
+				q = next;
+			}
+			q.cast::<Option<NonZero<u32>>>().write(None);
+		}
+
+		debug_assert!(is_on_page(page, p));
+		Some(p)
+	}
+}
+
+/// Releases the physical memory of a page that has been reset.
+unsafe fn release<G: Geometry>(mut page: NonNull<Page<G>>) {
+	unsafe {
+		let page = page.as_mut();
+		debug_assert_eq!(page.bytes_in_reserve, initial_reserve(page));
+
+		// The metadata zone at the start of the first page may not be released, so only whole 4 KiB pages are purged.
+		let start = (page.page_number * G::PAGE_SIZE + G::PAGE_SIZE - initial_reserve(page)).next_multiple_of(4096);
+		let end = (page.page_number + 1) * G::PAGE_SIZE;
+		let arena = Arena::<G>::from_inner_ptr(NonNull::from(&mut *page).cast());
+		// There is nothing we could do about a failure, and the page remains usable either way.
+		let _ = madvise(
+			arena.byte_add(start as usize).cast(),
+			(end - start) as usize,
+			MAdviseAdvice::DONTNEED,
+		);
+		if !core::mem::replace(&mut page.purged, true) {
+			(*arena.as_ptr()).purged_pages += 1;
+		}
+	}
+}
+
+/// Frees the object at `p`. Returns its page if the page was full, in which case it is to be returned to its bin via
+/// [`return_to_bin`](super::page::return_to_bin).
+#[cfg(not(any(thread_heaps, feature = "sharded")))]
+#[inline]
+pub unsafe fn dealloc<G: Geometry>(p: NonNull<u8>) -> Option<NonNull<Page<G>>> {
+	unsafe {
+		Page::free(page_of::<G>(p), |next| {
+			p.cast::<Option<NonZero<u32>>>().write(next);
+			Page::<G>::object_offset(p)
+		})
+	}
+}
+
+/// Frees the object at `p` on behalf of the heap `heap_id`, which buffers it in `remote_frees` if the object belongs to
+/// another heap.
+#[cfg(any(thread_heaps, feature = "sharded"))]
+#[inline]
+pub unsafe fn dealloc<G: Geometry>(heap_id: HeapId, remote_frees: Option<&mut RemoteFrees>, p: NonNull<u8>) {
+	unsafe {
+		let p_offset = Page::<G>::object_offset(p);
+		Page::free(heap_id, remote_frees, page_of::<G>(p), p, |next| {
+			p.cast::<Option<NonZero<u32>>>().write(next);
+			p_offset
+		});
+	}
+}
+
+/// Allocates an object from the first page of `bin`, see [`alloc_from_bin`]. Once `bin` is empty, a page is taken from
+/// `reserve_pages`, or from a new arena, which is taken from `pool` before one is mapped.
+#[inline]
+pub unsafe fn alloc<G: Geometry>(
+	bin: &mut Option<NonNull<Page<G>>>,
+	full_pages: &mut Option<NonNull<Page<G>>>,
+	reserve_pages: &mut Option<NonNull<Page<G>>>,
+	object_size: u32,
+	pool: &ArenaPool,
+	instance: InstanceId,
+	#[cfg(any(thread_heaps, feature = "sharded"))] id: HeapId,
+) -> *mut u8 {
+	unsafe {
+		if let Some(ret) = alloc_from_bin(bin, full_pages, |page| alloc_from_page(page, object_size)) {
+			return ret.as_ptr();
+		}
+
+		if let Some(mut p) = *reserve_pages {
+			let page = p.as_mut();
+
+			*reserve_pages = page.next_page;
+			page.object_size = object_size;
+			page.next_page = *bin;
+			*bin = Some(p);
+			Arena::take_page(Arena::from_inner_ptr(p.cast()), page);
+
+			let ret = alloc_from_page(page, object_size);
+			debug_assert!(ret.is_some());
+			return ret.unwrap_unchecked().as_ptr();
+		}
+
+		#[cfg(not(any(thread_heaps, feature = "sharded")))]
+		let pages_from_new_arena = from_pool(pool).or_else(|| from_new_arena(instance));
+		#[cfg(any(thread_heaps, feature = "sharded"))]
+		let pages_from_new_arena = from_pool(pool, id).or_else(|| from_new_arena(instance, id));
+		if let Some((mut page, first_additional_page, mut last_additional_page)) = pages_from_new_arena {
+			debug_assert_eq!(last_additional_page.as_ref().next_page, None);
+			last_additional_page.as_mut().next_page = *reserve_pages;
+			*reserve_pages = Some(first_additional_page);
+
+			page.as_mut().next_page = *bin;
+			*bin = Some(page);
+			page.as_mut().object_size = object_size;
+
+			let ret = alloc_from_page(page.as_mut(), object_size);
+			debug_assert!(ret.is_some());
+			ret.unwrap_unchecked().as_ptr()
+		} else {
+			// OOM?
+			ptr::null_mut()
+		}
+	}
+}
+
+/// Returns all pages in `bin` that no longer hold any objects to `reserve_pages`, releasing their physical memory where
+/// the huge page policy allows it.
+pub unsafe fn trim<G: Geometry>(
+	bin: &mut Option<NonNull<Page<G>>>,
+	reserve_pages: &mut Option<NonNull<Page<G>>>,
+	object_size: u32,
+) {
+	unsafe {
+		let mut pp: *mut Option<NonNull<Page<G>>> = bin;
+		while let Some(mut p) = *pp {
+			let page = p.as_mut();
+			// Objects are carved back to back, so this is exact.
+			if page.is_empty(initial_reserve(page), object_size) {
+				*pp = page.next_page;
+				page.free_list = None;
+				page.bytes_in_reserve = initial_reserve(page);
+				page.next_page = *reserve_pages;
+				*reserve_pages = Some(p);
+				if Arena::return_page(Arena::<G>::from_inner_ptr(p.cast())) {
+					release(p);
+				}
+			} else {
+				pp = &raw mut page.next_page;
+			}
+		}
+	}
+}
+
+/// Donates arenas whose pages are all in `reserve_pages` to `pool`, as long as more than
+/// [`Arena::RESERVE_THRESHOLD`] pages remain in `reserve_pages`.
+pub unsafe fn donate<G: Geometry>(reserve_pages: &mut Option<NonNull<Page<G>>>, pool: &ArenaPool) {
+	let pages_per_arena = Arena::<G>::PAGES_PER_ARENA;
+	unsafe {
+		let mut remaining = 0;
+		let mut p = *reserve_pages;
+		while let Some(page) = p {
+			remaining += 1;
+			p = page.as_ref().next_page;
+		}
+
+		// The donated arenas are linked via `pool_next`, but only pushed once none of their pages are in the list anymore.
+		let mut donated = 0;
+		let mut pp: *mut Option<NonNull<Page<G>>> = reserve_pages;
+		while let Some(page) = *pp {
+			let arena = Arena::<G>::from_inner_ptr(page.cast()).as_ptr();
+			if (*arena).pages_in_use == 0 && !(*arena).donated && remaining >= Arena::<G>::RESERVE_THRESHOLD + pages_per_arena
+			{
+				(*arena).donated = true;
+				(*arena).pool_next.store(donated, Ordering::Relaxed);
+				donated = arena as usize;
+				remaining -= pages_per_arena;
+			}
+
+			if (*arena).donated {
+				*pp = page.as_ref().next_page;
+			} else {
+				pp = &raw mut (*page.as_ptr()).next_page;
+			}
+		}
+
+		while let Some(arena) = NonNull::new(donated as *mut Arena<G>) {
+			donated = (*arena.as_ptr()).pool_next.load(Ordering::Relaxed);
+			pool.push(arena.cast());
+		}
+	}
+}
+
+/// Collapses the arenas of the pages in `bin` into huge pages, once they have been mostly full for
+/// [`huge_pages::COLLAPSE_AFTER_EPOCHS`] consecutive epochs and none of their pages are purged. Each arena is only
+/// considered once per `epoch`, no matter how many of its pages are in the bin.
+pub unsafe fn collapse<G: Geometry>(bin: &Option<NonNull<Page<G>>>, epoch: u32) {
+	let mut p = *bin;
+	while let Some(page) = p {
+		unsafe {
+			collapse_arena(Arena::<G>::from_inner_ptr(page.cast()), epoch);
+			p = page.as_ref().next_page;
+		}
+	}
+}
+
+/// Collapses the arenas of the pages in `full_pages`, just like [`collapse`] does for a bin.
+pub unsafe fn collapse_full<G: Geometry>(full_pages: &Option<NonNull<Page<G>>>, epoch: u32) {
+	let mut p = *full_pages;
+	while let Some(page) = p {
+		unsafe {
+			collapse_arena(Arena::<G>::from_inner_ptr(page.cast()), epoch);
+			p = page.as_ref().next_full;
+		}
+	}
+}
+
+unsafe fn collapse_arena<G: Geometry>(arena: NonNull<Arena<G>>, epoch: u32) {
+	let arena = arena.as_ptr();
+	unsafe {
+		if (*arena).last_epoch != epoch {
+			(*arena).dense_epochs = if !huge_pages::is_mostly_full((*arena).pages_in_use, Arena::<G>::PAGES_PER_ARENA) {
+				0
+			} else if (*arena).last_epoch.wrapping_add(1) == epoch {
+				(*arena).dense_epochs.saturating_add(1)
+			} else {
+				1
+			};
+			(*arena).last_epoch = epoch;
+
+			if (*arena).dense_epochs >= huge_pages::COLLAPSE_AFTER_EPOCHS
+				&& !(*arena).collapsed
+				&& !(*arena).huge_pages
+				&& (*arena).purged_pages == 0
+			{
+				(*arena).collapsed = huge_pages::collapse(NonNull::new_unchecked(arena).cast(), G::ARENA_SIZE as usize);
+			}
+		}
+	}
+}
@@ -0,0 +1,121 @@
+use core::ffi::c_void;
+use core::ptr::NonNull;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// A lock-free stack of empty arenas that is shared between all heaps of an instance, so that an arena one heap no
+/// longer needs can be reused by another heap instead of mapping a new one.
+///
+/// Arenas are aligned to their size, which leaves the low bits of their address free to hold a counter that is
+/// incremented on every push and pop. This prevents the ABA problem: a pop that read the top of the stack before
+/// another thread popped that arena, and pushed it again, fails to swap in its outdated next arena. Arenas are only
+/// unmapped while nobody uses the pool (see [`ArenaPool::clear`]), so reading the next arena of an arena that is
+/// concurrently popped is always safe.
+#[derive(Debug)]
+pub struct ArenaPool {
+	/// The address of the top arena combined with the counter.
+	top: AtomicUsize,
+	/// The alignment of the arenas, which is also the exclusive upper bound of the counter.
+	alignment: usize,
+	/// The offset of the [`AtomicUsize`] in each arena that holds the address of the next arena on the stack.
+	next_offset: usize,
+}
+
+impl ArenaPool {
+	pub const fn new(alignment: usize, next_offset: usize) -> Self {
+		assert!(alignment.is_power_of_two());
+		Self {
+			top: AtomicUsize::new(0),
+			alignment,
+			next_offset,
+		}
+	}
+
+	#[inline]
+	unsafe fn next<'a>(&self, arena: usize) -> &'a AtomicUsize {
+		unsafe { &*((arena + self.next_offset) as *const AtomicUsize) }
+	}
+
+	/// Pushes an empty arena, which must not be used by anyone else until it is popped again.
+	pub unsafe fn push(&self, arena: NonNull<c_void>) {
+		let arena = arena.as_ptr() as usize;
+		debug_assert_eq!(arena & (self.alignment - 1), 0);
+
+		let mut top = self.top.load(Ordering::Relaxed);
+		loop {
+			unsafe { self.next(arena) }.store(top & !(self.alignment - 1), Ordering::Relaxed);
+			let new_top = arena | (top.wrapping_add(1) & (self.alignment - 1));
+			match self
+				.top
+				.compare_exchange_weak(top, new_top, Ordering::Release, Ordering::Relaxed)
+			{
+				Ok(_) => return,
+				Err(current) => top = current,
+			}
+		}
+	}
+
+	/// Empties the pool, whose arenas are about to be unmapped, which must not race with any push or pop.
+	pub fn clear(&self) {
+		self.top.fetch_and(self.alignment - 1, Ordering::Relaxed);
+	}
+
+	/// Pops an arena, if there is any.
+	pub fn pop(&self) -> Option<NonNull<c_void>> {
+		let mut top = self.top.load(Ordering::Acquire);
+		loop {
+			let arena = top & !(self.alignment - 1);
+			if arena == 0 {
+				return None;
+			}
+
+			let next = unsafe { self.next(arena) }.load(Ordering::Relaxed);
+			let new_top = next | (top.wrapping_add(1) & (self.alignment - 1));
+			match self
+				.top
+				.compare_exchange_weak(top, new_top, Ordering::Acquire, Ordering::Acquire)
+			{
+				Ok(_) => return NonNull::new(arena as *mut c_void),
+				Err(current) => top = current,
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use core::num::NonZero;
+
+	use super::*;
+	use crate::emma::config::{Config, DefaultConfig};
+	use crate::mmap::{alloc_aligned, munmap};
+
+	#[test]
+	fn push_pop() {
+		const ARENA_SIZE: usize = DefaultConfig::ARENA_SIZE as usize;
+		let size = NonZero::new(3 * ARENA_SIZE).unwrap();
+		let pool = ArenaPool::new(ARENA_SIZE, 64);
+		unsafe {
+			let region = alloc_aligned(
+				size,
+				NonZero::new(ARENA_SIZE).unwrap(),
+				DefaultConfig::ALLOC_ALIGNED_RETRIES,
+				c"emma:test",
+			)
+			.unwrap();
+			let arenas = [0, 1, 2].map(|i| region.byte_add(i * ARENA_SIZE));
+
+			assert_eq!(pool.pop(), None);
+			for &arena in arenas.iter() {
+				pool.push(arena);
+			}
+			assert_eq!(pool.pop(), Some(arenas[2]));
+			assert_eq!(pool.pop(), Some(arenas[1]));
+			pool.push(arenas[2]);
+			assert_eq!(pool.pop(), Some(arenas[2]));
+			assert_eq!(pool.pop(), Some(arenas[0]));
+			assert_eq!(pool.pop(), None);
+
+			munmap(region, size).unwrap();
+		}
+	}
+}
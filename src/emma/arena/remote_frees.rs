@@ -0,0 +1,148 @@
+use core::num::NonZero;
+use core::ptr::NonNull;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+/// Set in the foreign free list of a page while the page is in the list of full pages of its heap. Whoever clears it
+/// hands the page back to its heap. Arena-relative offsets never reach this bit, as arenas are at most 2 GiB large (see
+/// [`Config::ARENA_SIZE`](crate::emma::Config::ARENA_SIZE)).
+pub const FULL: u32 = 1 << 31;
+
+/// The number of foreign free lists that objects can be buffered for at the same time.
+const BATCHES: usize = 16;
+/// The number of objects after which a batch is published.
+const BATCH_LIMIT: u32 = 64;
+
+/// Buffers objects that are freed by a heap that does not own them, grouped by the foreign free list of their page.
+/// Each batch is linked up front, so that publishing it takes a single CAS on the foreign free list, instead of one per
+/// object. This keeps a thread that frees many objects of another thread from contending on the pages of that thread.
+///
+/// Buffered objects are not counted as free by their page, so their page cannot be trimmed, nor their arena be reused
+/// by another heap, before the batch is published.
+#[derive(Debug)]
+pub struct RemoteFrees {
+	batches: [Batch; BATCHES],
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Batch {
+	/// The foreign free list that the batch is published to, which is `None` for an empty batch.
+	list: Option<NonNull<AtomicU32>>,
+	/// The arena-relative offset of the first object in the batch.
+	head: Option<NonZero<u32>>,
+	/// The object whose next offset is to be set to the previous head of `list` when the batch is published.
+	tail: NonNull<Option<NonZero<u32>>>,
+	len: u32,
+	/// Hands the page of `list` back to its heap, once publishing the batch cleared [`FULL`].
+	hand_back: unsafe fn(NonNull<AtomicU32>),
+}
+
+impl Batch {
+	const EMPTY: Batch = Batch {
+		list: None,
+		head: None,
+		tail: NonNull::dangling(),
+		len: 0,
+		hand_back: |_| {},
+	};
+
+	unsafe fn publish(&mut self) {
+		let (Some(list), Some(head)) = (self.list, self.head) else {
+			return;
+		};
+		unsafe {
+			let list = list.as_ref();
+			let mut next = list.load(Ordering::Relaxed);
+			loop {
+				self.tail.write(NonZero::new(next & !FULL));
+				match list.compare_exchange(next, head.get(), Ordering::Release, Ordering::Relaxed) {
+					Ok(_) => break,
+					Err(new_next) => next = new_next,
+				}
+			}
+			let hand_back = self.hand_back;
+			*self = Batch::EMPTY;
+			if next & FULL != 0 {
+				hand_back(list.into());
+			}
+		}
+	}
+}
+
+impl RemoteFrees {
+	pub const fn new() -> Self {
+		Self {
+			batches: [Batch::EMPTY; BATCHES],
+		}
+	}
+
+	/// Frees the object at `p` into the foreign free list `list`. `link` is passed the arena-relative offset of the
+	/// object that the object is to be linked in front of, and returns the offset of the object's first slot. The next
+	/// offset of the object's last slot must be stored at `p`. `hand_back` is called for `list` if its page was full.
+	#[inline]
+	pub unsafe fn free(
+		&mut self,
+		list: NonNull<AtomicU32>,
+		p: NonNull<u8>,
+		link: impl FnOnce(Option<NonZero<u32>>) -> NonZero<u32>,
+		hand_back: unsafe fn(NonNull<AtomicU32>),
+	) {
+		let hash = (list.as_ptr() as usize).wrapping_mul(0x9e37_79b9_7f4a_7c15_u64 as usize);
+		let batch = &mut self.batches[hash >> (usize::BITS - BATCHES.ilog2())];
+		if batch.list != Some(list) {
+			unsafe { batch.publish() };
+			batch.list = Some(list);
+			batch.tail = p.cast();
+			batch.hand_back = hand_back;
+		}
+
+		batch.head = Some(link(batch.head));
+		batch.len += 1;
+		if batch.len >= BATCH_LIMIT {
+			unsafe { batch.publish() };
+		}
+	}
+
+	/// Publishes all buffered objects.
+	pub unsafe fn flush(&mut self) {
+		for batch in self.batches.iter_mut() {
+			unsafe { batch.publish() };
+		}
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn batches_are_published_at_once() {
+		let mut objects = [[0u32; 2]; 8];
+		let base = objects.as_mut_ptr().cast::<u8>();
+		let object = |i: usize| unsafe { NonNull::new_unchecked(base.add(i * 8)) };
+		let offset = |i: usize| NonZero::new((i * 8 + 8) as u32).unwrap();
+
+		let list = AtomicU32::new(offset(7).get());
+		let mut remote_frees = RemoteFrees::new();
+		unsafe {
+			for i in 0..3 {
+				remote_frees.free(
+					NonNull::from(&list),
+					object(i),
+					|next| {
+						object(i).cast::<Option<NonZero<u32>>>().write(next);
+						offset(i)
+					},
+					|_| panic!("The page is not full."),
+				);
+			}
+			assert_eq!(list.load(Ordering::Relaxed), offset(7).get());
+
+			remote_frees.flush();
+			assert_eq!(list.load(Ordering::Relaxed), offset(2).get());
+			let next = |i: usize| object(i).cast::<Option<NonZero<u32>>>().read();
+			assert_eq!(next(2), Some(offset(1)));
+			assert_eq!(next(1), Some(offset(0)));
+			assert_eq!(next(0), Some(offset(7)));
+		}
+	}
+}
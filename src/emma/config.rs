@@ -0,0 +1,152 @@
+//! The geometry of arenas and tiers, which is fixed at compile time by the [`Config`] that an [`Emma`](super::Emma) is
+//! instantiated with.
+//!
+//! The bins of heaps are arrays whose lengths cannot depend on a generic parameter without `generic_const_exprs`, so
+//! heaps have room for [`MAX_SMALL_OBJECT_BINS`], [`MAX_MEDIUM_OBJECT_BINS`] and [`MAX_LARGE_OBJECT_BINS`] bins, of
+//! which they use as many as their configuration needs (see [`Tiers`]).
+
+use super::powerlaw_bin_from_size;
+
+/// The geometry of arenas and tiers. The maximum object alignments of the tiers also set the boundaries between them:
+/// A tier serves objects of almost twice its maximum alignment, and all larger objects go to the next tier.
+///
+/// The geometry is checked when an [`Emma`](super::Emma) is created for it. Among other things, small objects may be
+/// aligned to at most 256 bytes, and the maximum alignment may double at most 6 times from the small to the medium
+/// tier, and at most 8 times from the medium to the large tier. Configurations are told apart by their type, so that an
+/// instance of one is never handed to an [`Emma`](super::Emma) of another once it has been dropped.
+pub trait Config: 'static {
+	/// The size and alignment of the arenas of all tiers (and of regions), which may be at most 2 GiB.
+	const ARENA_SIZE: u32;
+	/// The size of the pages that arenas for small objects are split into.
+	const SMALL_PAGE_SIZE: u32;
+	/// The size of the pages that arenas for medium objects are split into.
+	const MEDIUM_PAGE_SIZE: u32;
+	/// The largest alignment that small objects may have.
+	const MAXIMUM_SMALL_OBJECT_ALIGNMENT: u32;
+	/// The largest alignment that medium objects may have.
+	const MAXIMUM_MEDIUM_OBJECT_ALIGNMENT: u32;
+	/// The largest alignment that large objects may have, above which objects are huge and mapped individually.
+	const MAXIMUM_LARGE_OBJECT_ALIGNMENT: u32;
+	/// How often obtaining an aligned mapping from the OS is retried, as the kernel does not align mappings itself.
+	const ALLOC_ALIGNED_RETRIES: usize;
+}
+
+/// The geometry emma has always used: 4 MiB arenas, with 32 KiB pages for small objects and 64 KiB pages for medium
+/// objects.
+#[derive(Debug)]
+pub struct DefaultConfig;
+
+impl Config for DefaultConfig {
+	const ARENA_SIZE: u32 = 4 * 1024 * 1024;
+	const SMALL_PAGE_SIZE: u32 = 32 * 1024;
+	const MEDIUM_PAGE_SIZE: u32 = 64 * 1024;
+	const MAXIMUM_SMALL_OBJECT_ALIGNMENT: u32 = 256;
+	const MAXIMUM_MEDIUM_OBJECT_ALIGNMENT: u32 = 4096;
+	const MAXIMUM_LARGE_OBJECT_ALIGNMENT: u32 = 512 * 1024;
+	const ALLOC_ALIGNED_RETRIES: usize = 3;
+}
+
+/// Halves the arenas to 2 MiB, so that an arena fits a single transparent huge page, while keeping the pages of the
+/// default geometry. Objects of more than 448 KiB are huge, i.e., mapped individually.
+#[derive(Debug)]
+pub struct CompactConfig;
+
+impl Config for CompactConfig {
+	const ARENA_SIZE: u32 = 2 * 1024 * 1024;
+	const SMALL_PAGE_SIZE: u32 = DefaultConfig::SMALL_PAGE_SIZE;
+	const MEDIUM_PAGE_SIZE: u32 = DefaultConfig::MEDIUM_PAGE_SIZE;
+	const MAXIMUM_SMALL_OBJECT_ALIGNMENT: u32 = DefaultConfig::MAXIMUM_SMALL_OBJECT_ALIGNMENT;
+	const MAXIMUM_MEDIUM_OBJECT_ALIGNMENT: u32 = DefaultConfig::MAXIMUM_MEDIUM_OBJECT_ALIGNMENT;
+	const MAXIMUM_LARGE_OBJECT_ALIGNMENT: u32 = 256 * 1024;
+	const ALLOC_ALIGNED_RETRIES: usize = DefaultConfig::ALLOC_ALIGNED_RETRIES;
+}
+
+/// The number of bins for small objects that a heap has room for, i.e., for a maximum small object alignment of 256.
+pub const MAX_SMALL_OBJECT_BINS: usize = 63;
+/// The number of bins for medium objects that a heap has room for, i.e., for 6 doublings of the maximum alignment.
+pub const MAX_MEDIUM_OBJECT_BINS: usize = 24;
+/// The number of bins for large objects that a heap has room for, i.e., for 8 doublings of the maximum alignment.
+pub const MAX_LARGE_OBJECT_BINS: usize = 32;
+
+/// What emma derives from a [`Config`], along with the checks of its geometry, which are implemented for every
+/// configuration so that they cannot be overridden.
+pub trait Tiers: Config {
+	/// The number of bins for small objects, whose sizes are multiples of 8 bytes.
+	const SMALL_OBJECT_BINS: usize = ((2 * Self::MAXIMUM_SMALL_OBJECT_ALIGNMENT - 8) / 8) as usize;
+	/// The number of bins for medium objects, whose sizes follow a power law with 4 bins per doubling.
+	const MEDIUM_OBJECT_BINS: usize =
+		((Self::MAXIMUM_MEDIUM_OBJECT_ALIGNMENT.ilog2() - Self::MAXIMUM_SMALL_OBJECT_ALIGNMENT.ilog2()) * 4) as usize;
+	/// The number of bins for large objects, whose sizes follow a power law with 4 bins per doubling.
+	const LARGE_OBJECT_BINS: usize =
+		((Self::MAXIMUM_LARGE_OBJECT_ALIGNMENT.ilog2() - Self::MAXIMUM_MEDIUM_OBJECT_ALIGNMENT.ilog2()) * 4) as usize;
+	/// The power law bin of the smallest medium object.
+	const FIRST_MEDIUM_BIN: u32 = powerlaw_bin_from_size((Self::MAXIMUM_SMALL_OBJECT_ALIGNMENT * 2) as usize);
+	/// The power law bin of the largest medium object.
+	const LAST_MEDIUM_BIN: u32 = powerlaw_bin_from_size(
+		(Self::MAXIMUM_MEDIUM_OBJECT_ALIGNMENT
+			+ Self::MAXIMUM_MEDIUM_OBJECT_ALIGNMENT / 2
+			+ Self::MAXIMUM_MEDIUM_OBJECT_ALIGNMENT / 4) as usize,
+	);
+	/// The power law bin of the smallest large object.
+	const FIRST_LARGE_BIN: u32 = powerlaw_bin_from_size((Self::MAXIMUM_MEDIUM_OBJECT_ALIGNMENT * 2) as usize);
+	/// The size of the largest large object, above which objects are huge.
+	const MAXIMUM_LARGE_OBJECT_SIZE: usize = (Self::MAXIMUM_LARGE_OBJECT_ALIGNMENT
+		+ Self::MAXIMUM_LARGE_OBJECT_ALIGNMENT / 2
+		+ Self::MAXIMUM_LARGE_OBJECT_ALIGNMENT / 4) as usize;
+	/// The power law bin of the largest large object.
+	const LAST_LARGE_BIN: u32 = powerlaw_bin_from_size(Self::MAXIMUM_LARGE_OBJECT_SIZE);
+
+	/// Fails to compile once it is evaluated for an invalid geometry, which [`Emma::new`](super::Emma::new) does.
+	const VALID: () = {
+		assert!(
+			Self::ARENA_SIZE.is_power_of_two(),
+			"The arena size should be a power of two."
+		);
+		assert!(
+			Self::ARENA_SIZE <= 1 << 31,
+			"The arena size should not exceed 2 GiB, as the highest bit of offsets into arenas marks full pages."
+		);
+		assert!(
+			Self::SMALL_PAGE_SIZE.is_power_of_two() && Self::MEDIUM_PAGE_SIZE.is_power_of_two(),
+			"The page sizes should be powers of two."
+		);
+		assert!(
+			Self::SMALL_PAGE_SIZE < Self::ARENA_SIZE && Self::MEDIUM_PAGE_SIZE < Self::ARENA_SIZE,
+			"The page sizes should be smaller than the arena size."
+		);
+		assert!(
+			Self::MAXIMUM_SMALL_OBJECT_ALIGNMENT.is_power_of_two()
+				&& Self::MAXIMUM_MEDIUM_OBJECT_ALIGNMENT.is_power_of_two()
+				&& Self::MAXIMUM_LARGE_OBJECT_ALIGNMENT.is_power_of_two(),
+			"The maximum object alignments should be powers of two."
+		);
+		assert!(
+			Self::MAXIMUM_SMALL_OBJECT_ALIGNMENT >= 8
+				&& Self::MAXIMUM_SMALL_OBJECT_ALIGNMENT < Self::MAXIMUM_MEDIUM_OBJECT_ALIGNMENT
+				&& Self::MAXIMUM_MEDIUM_OBJECT_ALIGNMENT < Self::MAXIMUM_LARGE_OBJECT_ALIGNMENT,
+			"The maximum object alignments should increase from tier to tier, starting at 8 or more."
+		);
+		assert!(
+			2 * Self::MAXIMUM_SMALL_OBJECT_ALIGNMENT <= Self::SMALL_PAGE_SIZE,
+			"Small pages should hold objects of twice the maximum small object alignment."
+		);
+		assert!(
+			2 * Self::MAXIMUM_MEDIUM_OBJECT_ALIGNMENT <= Self::MEDIUM_PAGE_SIZE,
+			"Medium pages should hold objects of twice the maximum medium object alignment."
+		);
+		assert!(
+			2 * Self::MAXIMUM_LARGE_OBJECT_ALIGNMENT <= Self::ARENA_SIZE,
+			"Arenas should hold objects of twice the maximum large object alignment."
+		);
+		assert!(
+			Self::SMALL_OBJECT_BINS <= MAX_SMALL_OBJECT_BINS
+				&& Self::MEDIUM_OBJECT_BINS <= MAX_MEDIUM_OBJECT_BINS
+				&& Self::LARGE_OBJECT_BINS <= MAX_LARGE_OBJECT_BINS,
+			"The tiers should not need more bins than heaps have room for."
+		);
+		assert!(Self::LAST_MEDIUM_BIN - Self::FIRST_MEDIUM_BIN == Self::MEDIUM_OBJECT_BINS as u32 - 1);
+		assert!(Self::LAST_LARGE_BIN - Self::FIRST_LARGE_BIN == Self::LARGE_OBJECT_BINS as u32 - 1);
+	};
+}
+
+impl<C: Config> Tiers for C {}
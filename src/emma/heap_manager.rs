@@ -1,70 +1,258 @@
 use core::mem::offset_of;
 use core::num::NonZero;
 use core::ptr::NonNull;
-use core::sync::atomic::{AtomicU32, Ordering};
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
 
-use const_format::assertc;
 use syscalls::Errno;
 
 use super::Heap;
+use super::config::Config;
+use super::instance::Instance;
 use crate::mmap::alloc_aligned;
 use crate::sync::Futex;
 use crate::sync::syscalls::FUTEX_OWNER_DIED;
 use crate::sys::Pid;
 
-#[derive(Debug, Default)]
-pub(crate) struct HeapManager;
+/// Hands out the thread heaps of one [`Instance`]. The thread-local storage that remembers the heap of each thread is
+/// shared between all instances, and holds a heap of each instance that the thread uses (see
+/// [`super::ThreadHeapCache`]).
+#[derive(Debug)]
+pub(crate) struct HeapManager<C: Config> {
+	thread_heaps: Futex<ThreadHeaps<C>>,
+	/// The number of heaps on [`ThreadHeaps::orphans`].
+	orphaned_heaps: AtomicUsize,
+}
 
-impl HeapManager {
+impl<C: Config> HeapManager<C> {
 	pub(crate) const fn new() -> Self {
-		Self
+		Self {
+			thread_heaps: Futex::new(ThreadHeaps::new()),
+			orphaned_heaps: AtomicUsize::new(0),
+		}
+	}
+
+	/// Locks a heap for the calling thread, creating a new one for `instance`, which this manager belongs to, if no
+	/// other heap is available.
+	pub unsafe fn acquire_thread_heap(&self, instance: NonNull<Instance<C>>) -> Option<NonNull<Heap<C>>> {
+		unsafe {
+			self
+				.thread_heaps
+				.lock()
+				.acquire_thread_heap(instance, &self.orphaned_heaps)
+		}
+	}
+
+	/// Trims `heap`, which must be owned by the calling thread, unlocks the [`ThreadHeap`] containing it, and puts it
+	/// on the orphan stack, so that its pages can be adopted by another heap (see [`HeapManager::take_orphan`]), or the
+	/// heap can be picked up by the next thread right away instead of waiting for the kernel to report the owner as dead.
+	///
+	/// Threads release their heaps without holding a reference to the [`Emma`](super::Emma) they belong to, e.g., when
+	/// they exit. The heap is trimmed without holding this manager, so that other threads can acquire and release heaps
+	/// in the meantime, but it is marked as being released first, so that the instance is not reset underneath (see
+	/// [`HeapManager::reset`]).
+	pub unsafe fn release_thread_heap(&self, mut heap: NonNull<Heap<C>>) {
+		let thread_heap = unsafe { ThreadHeap::from_heap(heap) };
+		{
+			let _thread_heaps = self.thread_heaps.lock();
+			unsafe { ThreadHeap::releasing(thread_heap) }.store(true, Ordering::Relaxed);
+		}
+		unsafe { heap.as_mut().trim() };
+
+		let mut thread_heaps = self.thread_heaps.lock();
+		unsafe {
+			ThreadHeap::releasing(thread_heap).store(false, Ordering::Relaxed);
+			ThreadHeap::unlock(thread_heap);
+			thread_heaps.orphans = ThreadHeap::push(thread_heap, thread_heaps.orphans);
+		}
+		self.orphaned_heaps.fetch_add(1, Ordering::Relaxed);
+	}
+
+	/// Returns whether there are heaps on the orphan stack, which is cheap enough to be checked before mapping new
+	/// arenas.
+	#[inline]
+	pub fn has_orphans(&self) -> bool {
+		self.orphaned_heaps.load(Ordering::Relaxed) != 0
 	}
 
-	pub unsafe fn acquire_thread_heap(&self) -> Option<NonNull<Heap>> {
-		unsafe { THREAD_HEAPS.lock().acquire_thread_heap() }
+	/// Locks a heap whose thread has exited, so that the calling thread can adopt its pages. With `scan`, this also looks
+	/// for heaps whose owner died without releasing them (see [`DEAD_OWNER_SCAN_LIMIT`]), which requires a syscall per
+	/// heap.
+	///
+	/// Must be followed by [`HeapManager::return_orphan`] once the pages have been adopted. `current` is the heap of the
+	/// calling thread, which is never returned.
+	pub unsafe fn take_orphan(&self, scan: bool, current: NonNull<Heap<C>>) -> Option<NonNull<Heap<C>>> {
+		let mut thread_heaps = self.thread_heaps.lock();
+		if let Some(heap) = unsafe { thread_heaps.pop_orphan(&self.orphaned_heaps) } {
+			Some(heap)
+		} else if scan && thread_heaps.free.is_none() {
+			// The scan would lock heaps on the free stack, too.
+			unsafe { thread_heaps.scan_for_dead_owner() }.filter(|&heap| heap != current)
+		} else {
+			None
+		}
+	}
+
+	/// Forgets the pages of all heaps, including the ones that are held by other threads, and calls `release` to release
+	/// the arenas of these pages, while no heap can be acquired or released. Heaps that are being released are waited
+	/// for first, by locking them, which their threads only unlock once they are done trimming them.
+	///
+	/// # Safety
+	/// No other thread may use a heap of this manager, other than to release it.
+	pub unsafe fn reset(&self, release: impl FnOnce()) {
+		let thread_heaps = loop {
+			let thread_heaps = self.thread_heaps.lock();
+			let Some(releasing) = thread_heaps.find_releasing() else {
+				break thread_heaps;
+			};
+			drop(thread_heaps);
+			unsafe { ThreadHeap::wait_for_release(releasing) };
+		};
+
+		let mut next = thread_heaps.heaps;
+		while let Some(thread_heap) = next {
+			unsafe {
+				ThreadHeap::heap(thread_heap).as_mut().reset();
+				next = ThreadHeap::next(thread_heap);
+			}
+		}
+		release();
+	}
+
+	/// Unlocks a heap obtained from [`HeapManager::take_orphan`], whose pages have all been adopted, and puts it on the
+	/// free stack.
+	pub unsafe fn return_orphan(&self, heap: NonNull<Heap<C>>) {
+		let mut thread_heaps = self.thread_heaps.lock();
+		unsafe {
+			let thread_heap = ThreadHeap::from_heap(heap);
+			ThreadHeap::unlock(thread_heap);
+			thread_heaps.free = ThreadHeap::push(thread_heap, thread_heaps.free);
+		}
 	}
 }
 
-/// This is not a member of [`HeapManager`], as we use thread-local storage to remember the heap per thread, which
-/// causes them to be shared across different [`Emma`](super::Emma) instances. This means that the [`ThreadManager`]
-/// also needs to be (effectively) shared across [`Emma`](super::Emma) instances.
-static THREAD_HEAPS: Futex<ThreadHeaps> = Futex::new(ThreadHeaps::new());
+/// The maximum number of heaps whose owner is checked for being dead per acquisition of a heap, so that acquiring a
+/// heap does not become more expensive with the number of heaps.
+const DEAD_OWNER_SCAN_LIMIT: usize = 4;
 
-struct ThreadHeaps {
+#[derive(Debug)]
+struct ThreadHeaps<C: Config> {
 	last_pid: Pid,
-	heaps: Option<NonNull<ThreadHeap>>,
+	/// All heaps ever created, linked via [`ThreadHeap::next`].
+	heaps: Option<NonNull<ThreadHeap<C>>>,
+	/// The heaps that were released by their thread and are unlocked, linked via [`ThreadHeap::next_free`].
+	orphans: Option<NonNull<ThreadHeap<C>>>,
+	/// The heaps that are unlocked and hold no pages, because they have been adopted by another heap, linked via
+	/// [`ThreadHeap::next_free`].
+	free: Option<NonNull<ThreadHeap<C>>>,
+	/// The heap at which the next scan for heaps with a dead owner continues.
+	scan_cursor: Option<NonNull<ThreadHeap<C>>>,
 }
-unsafe impl core::marker::Send for ThreadHeaps {}
+unsafe impl<C: Config> core::marker::Send for ThreadHeaps<C> {}
 
-impl ThreadHeaps {
+impl<C: Config> ThreadHeaps<C> {
 	pub const fn new() -> Self {
 		Self {
 			last_pid: 0,
 			heaps: None,
+			orphans: None,
+			free: None,
+			scan_cursor: None,
 		}
 	}
 
-	pub unsafe fn acquire_thread_heap(&mut self) -> Option<NonNull<Heap>> {
-		if let Some(already_owned) = unsafe { self.fixup_fork() } {
+	pub unsafe fn acquire_thread_heap(
+		&mut self,
+		instance: NonNull<Instance<C>>,
+		orphaned_heaps: &AtomicUsize,
+	) -> Option<NonNull<Heap<C>>> {
+		if let Some(already_owned) = unsafe { self.fixup_fork(orphaned_heaps) } {
 			return Some(already_owned);
 		}
 
-		let mut p = self.heaps;
-		while let Some(thread_heap) = p {
-			let thread_lock = unsafe {
-				thread_heap
-					.byte_add(offset_of!(ThreadHeap, thread_lock))
-					.cast::<AtomicU32>()
-					.as_ref()
-			};
+		// Prefer heaps that still hold pages, so that their memory is reused.
+		if let Some(heap) = unsafe { self.pop_orphan(orphaned_heaps) } {
+			return Some(heap);
+		}
+		if let Some(thread_heap) = self.free {
+			unsafe {
+				self.free = ThreadHeap::next_free(thread_heap);
+				ThreadHeap::lock_unlocked(thread_heap);
+				return Some(ThreadHeap::heap(thread_heap));
+			}
+		}
+
+		if let Some(heap) = unsafe { self.scan_for_dead_owner() } {
+			return Some(heap);
+		}
+
+		const {
+			assert!(
+				size_of::<ThreadHeap<C>>().is_multiple_of(align_of::<ThreadHeap<C>>()),
+				"The ThreadHeap should have a size that is a multiple of its alignment."
+			)
+		};
+		let size = (size_of::<ThreadHeap<C>>() + 4095) & !4095;
+		let thread_heap = unsafe {
+			alloc_aligned(
+				NonZero::new(size).unwrap(),
+				NonZero::new(align_of::<ThreadHeap<C>>()).unwrap(),
+				C::ALLOC_ALIGNED_RETRIES,
+				c"emma:heap-meta",
+			)?
+			.cast::<ThreadHeap<C>>()
+		};
+
+		let heap = unsafe {
+			thread_heap.write(ThreadHeap::new(self.heaps, instance));
+			let mut heap = ThreadHeap::heap(thread_heap);
+			heap.as_mut().assign_id();
+			heap
+		};
+		self.heaps = Some(thread_heap);
+
+		Some(heap)
+	}
+
+	/// Returns a heap that is being released by its thread (see [`HeapManager::release_thread_heap`]), if there is one.
+	fn find_releasing(&self) -> Option<NonNull<ThreadHeap<C>>> {
+		let mut next = self.heaps;
+		while let Some(thread_heap) = next {
+			if unsafe { ThreadHeap::releasing(thread_heap) }.load(Ordering::Relaxed) {
+				return Some(thread_heap);
+			}
+			next = unsafe { ThreadHeap::next(thread_heap) };
+		}
+		None
+	}
+
+	/// Locks a heap taken from the orphan stack, whose length is counted by `orphaned_heaps`.
+	unsafe fn pop_orphan(&mut self, orphaned_heaps: &AtomicUsize) -> Option<NonNull<Heap<C>>> {
+		let thread_heap = self.orphans?;
+		unsafe {
+			self.orphans = ThreadHeap::next_free(thread_heap);
+			orphaned_heaps.fetch_sub(1, Ordering::Relaxed);
+			ThreadHeap::lock_unlocked(thread_heap);
+			Some(ThreadHeap::heap(thread_heap))
+		}
+	}
 
+	/// Checks up to [`DEAD_OWNER_SCAN_LIMIT`] heaps, continuing where the previous scan stopped, for one that is unlocked
+	/// or whose owner has died without releasing it, and locks it for the calling thread.
+	unsafe fn scan_for_dead_owner(&mut self) -> Option<NonNull<Heap<C>>> {
+		for _ in 0..DEAD_OWNER_SCAN_LIMIT {
+			let thread_heap = self.scan_cursor.or(self.heaps)?;
+			self.scan_cursor = unsafe { ThreadHeap::next(thread_heap) };
+
+			let thread_lock = unsafe { ThreadHeap::thread_lock(thread_heap) };
 			// a robust futex sadly requires a global resource: https://www.man7.org/linux/man-pages/man2/set_robust_list.2.html
 			let tid = thread_lock.load(Ordering::Relaxed);
 			match unsafe { crate::sync::syscalls::futex_trylock_pi(thread_lock, crate::sync::syscalls::FutexFlags::PRIVATE) }
 			{
 				Ok(true) => {
-					thread_lock.fetch_nand(FUTEX_OWNER_DIED, Ordering::Release);
-					return Some(unsafe { thread_heap.byte_add(offset_of!(ThreadHeap, heap)).cast::<Heap>() });
+					thread_lock.fetch_and(!FUTEX_OWNER_DIED, Ordering::Release);
+					// The owner may have died while releasing the heap.
+					unsafe { ThreadHeap::releasing(thread_heap) }.store(false, Ordering::Relaxed);
+					return Some(unsafe { ThreadHeap::heap(thread_heap) });
 				}
 				Ok(false) | Err(Errno::EAGAIN) => (),
 				Err(Errno::ESRCH) => {
@@ -72,7 +260,8 @@ impl ThreadHeaps {
 						.compare_exchange(tid, crate::sys::gettid(), Ordering::Acquire, Ordering::Relaxed)
 						.is_ok()
 					{
-						return Some(unsafe { thread_heap.byte_add(offset_of!(ThreadHeap, heap)).cast::<Heap>() });
+						unsafe { ThreadHeap::releasing(thread_heap) }.store(false, Ordering::Relaxed);
+						return Some(unsafe { ThreadHeap::heap(thread_heap) });
 					}
 				}
 				Err(Errno::EDEADLK) => {
@@ -83,7 +272,7 @@ impl ThreadHeaps {
 					// 3. The first thread allocating memory in the new process is not the main thread
 					// 4. The main thread now allocates memory, which causes it to look for an available heap. It will find a heap
 					//    that it has already locked due to the fixup done previously.
-					return Some(unsafe { thread_heap.byte_add(offset_of!(ThreadHeap, heap)).cast::<Heap>() });
+					return Some(unsafe { ThreadHeap::heap(thread_heap) });
 				}
 				Err(Errno::ENOMEM) => panic!("ENOMEM"),
 				Err(Errno::EINVAL) => panic!("EINVAL"),
@@ -91,40 +280,14 @@ impl ThreadHeaps {
 				Err(Errno::EPERM) => panic!("EPERM"),
 				Err(err) => panic!("{}", err),
 			}
-
-			p = unsafe {
-				*thread_heap
-					.byte_add(offset_of!(ThreadHeap, next))
-					.cast::<Option<NonNull<ThreadHeap>>>()
-					.as_ref()
-			};
 		}
 
-		assertc!(
-			size_of::<ThreadHeap>().is_multiple_of(align_of::<ThreadHeap>()),
-			"The ThreadHeap should have a size ({}) that is a multiple of its alignment ({}).",
-			size_of::<ThreadHeap>(),
-			align_of::<ThreadHeap>()
-		);
-		let size = (size_of::<ThreadHeap>() + 4095) & !4095;
-		let thread_heap = unsafe {
-			alloc_aligned(
-				NonZero::new(size).unwrap(),
-				NonZero::new(align_of::<ThreadHeap>()).unwrap(),
-				3,
-			)?
-			.cast::<ThreadHeap>()
-		};
-
-		unsafe { thread_heap.write(ThreadHeap::new(self.heaps)) };
-		self.heaps = Some(thread_heap);
-
-		Some(unsafe { thread_heap.byte_add(offset_of!(ThreadHeap, heap)).cast::<Heap>() })
+		None
 	}
 
 	/// Fixes up locks on existing threads post fork. Potentially returns an already owned heap.
 	#[inline]
-	unsafe fn fixup_fork(&mut self) -> Option<NonNull<Heap>> {
+	unsafe fn fixup_fork(&mut self, orphaned_heaps: &AtomicUsize) -> Option<NonNull<Heap<C>>> {
 		let pid = crate::sys::getpid();
 		debug_assert_ne!(pid, 0);
 		if self.last_pid != pid {
@@ -135,19 +298,25 @@ impl ThreadHeaps {
 			//
 			// The one thread remaining after the fork has a TID that is equal to the (new) PID, as it is the new main thread
 			// of the process.
+			// All heaps are owned now, including the ones that were unlocked before.
+			self.orphans = None;
+			orphaned_heaps.store(0, Ordering::Relaxed);
+			self.free = None;
 			if let Some(mut heap) = self.heaps {
 				loop {
 					unsafe {
 						heap
-							.byte_add(offset_of!(ThreadHeap, thread_lock))
+							.byte_add(offset_of!(ThreadHeap<C>, thread_lock))
 							.cast::<AtomicU32>()
 							.as_mut()
-							.store(pid, Ordering::Relaxed)
+							.store(pid, Ordering::Relaxed);
+						// Threads that were releasing their heaps did not survive the fork.
+						ThreadHeap::releasing(heap).store(false, Ordering::Relaxed);
 					};
 					if let Some(next) = unsafe {
 						*heap
-							.byte_add(offset_of!(ThreadHeap, next))
-							.cast::<Option<NonNull<ThreadHeap>>>()
+							.byte_add(offset_of!(ThreadHeap<C>, next))
+							.cast::<Option<NonNull<ThreadHeap<C>>>>()
 							.as_ref()
 					} {
 						heap = next;
@@ -161,8 +330,8 @@ impl ThreadHeaps {
 						self
 							.heaps
 							.unwrap()
-							.byte_add(offset_of!(ThreadHeap, heap))
-							.cast::<Heap>()
+							.byte_add(offset_of!(ThreadHeap<C>, heap))
+							.cast::<Heap<C>>()
 					});
 				}
 			}
@@ -173,18 +342,131 @@ impl ThreadHeaps {
 }
 
 #[derive(Debug)]
-struct ThreadHeap {
-	next: Option<NonNull<ThreadHeap>>,
+struct ThreadHeap<C: Config> {
+	next: Option<NonNull<ThreadHeap<C>>>,
+	/// The next heap on the free stack, only meaningful while this heap is on it.
+	next_free: Option<NonNull<ThreadHeap<C>>>,
 	thread_lock: AtomicU32,
-	heap: Heap,
+	/// Whether the thread that holds this heap is trimming it in order to release it, which is only modified while
+	/// holding [`HeapManager::thread_heaps`].
+	releasing: AtomicBool,
+	heap: Heap<C>,
 }
 
-impl ThreadHeap {
-	fn new(next: Option<NonNull<ThreadHeap>>) -> Self {
+impl<C: Config> ThreadHeap<C> {
+	fn new(next: Option<NonNull<ThreadHeap<C>>>, instance: NonNull<Instance<C>>) -> Self {
 		Self {
 			next,
+			next_free: None,
 			thread_lock: AtomicU32::new(crate::sys::gettid()),
-			heap: Heap::new(),
+			releasing: AtomicBool::new(false),
+			heap: Heap::new(instance),
 		}
 	}
+
+	#[inline]
+	unsafe fn next(thread_heap: NonNull<ThreadHeap<C>>) -> Option<NonNull<ThreadHeap<C>>> {
+		unsafe {
+			*thread_heap
+				.byte_add(offset_of!(ThreadHeap<C>, next))
+				.cast::<Option<NonNull<ThreadHeap<C>>>>()
+				.as_ref()
+		}
+	}
+
+	#[inline]
+	unsafe fn next_free(thread_heap: NonNull<ThreadHeap<C>>) -> Option<NonNull<ThreadHeap<C>>> {
+		unsafe {
+			*thread_heap
+				.byte_add(offset_of!(ThreadHeap<C>, next_free))
+				.cast::<Option<NonNull<ThreadHeap<C>>>>()
+				.as_ref()
+		}
+	}
+
+	/// Pushes `thread_heap` onto the stack starting at `top` (linked via [`ThreadHeap::next_free`]), returning the new
+	/// top of the stack.
+	#[inline]
+	unsafe fn push(
+		thread_heap: NonNull<ThreadHeap<C>>,
+		top: Option<NonNull<ThreadHeap<C>>>,
+	) -> Option<NonNull<ThreadHeap<C>>> {
+		unsafe {
+			thread_heap
+				.byte_add(offset_of!(ThreadHeap<C>, next_free))
+				.cast::<Option<NonNull<ThreadHeap<C>>>>()
+				.write(top)
+		};
+		Some(thread_heap)
+	}
+
+	/// Locks a heap taken from the orphan or free stack for the calling thread.
+	#[inline]
+	unsafe fn lock_unlocked(thread_heap: NonNull<ThreadHeap<C>>) {
+		// Heaps on the stacks are unlocked, and only taken from them while holding `HeapManager::thread_heaps`. The dead
+		// owner scan only runs while both stacks are empty, so no one else can have locked it in the meantime.
+		let locked = unsafe { ThreadHeap::thread_lock(thread_heap) }
+			.compare_exchange(0, crate::sys::gettid(), Ordering::Acquire, Ordering::Relaxed)
+			.is_ok();
+		debug_assert!(locked);
+	}
+
+	/// Unlocks a heap that is owned by the calling thread.
+	#[inline]
+	unsafe fn unlock(thread_heap: NonNull<ThreadHeap<C>>) {
+		let thread_lock = unsafe { ThreadHeap::thread_lock(thread_heap) };
+		if thread_lock
+			.compare_exchange(crate::sys::gettid(), 0, Ordering::Release, Ordering::Relaxed)
+			.is_err()
+		{
+			// Someone is waiting for the lock, which means that the kernel has to hand it over.
+			unsafe {
+				crate::sync::syscalls::futex_unlock_pi(thread_lock, crate::sync::syscalls::FutexFlags::PRIVATE).unwrap()
+			};
+		}
+	}
+
+	/// Waits until the thread that is releasing `thread_heap` has unlocked it. A thread that dies while releasing its
+	/// heap leaves it marked as being released, which the kernel reports here.
+	#[cold]
+	unsafe fn wait_for_release(thread_heap: NonNull<ThreadHeap<C>>) {
+		let thread_lock = unsafe { ThreadHeap::thread_lock(thread_heap) };
+		let res =
+			unsafe { crate::sync::syscalls::futex_lock_pi(thread_lock, crate::sync::syscalls::FutexFlags::PRIVATE, None) };
+		debug_assert!(res.is_ok());
+		if thread_lock.load(Ordering::Relaxed) & FUTEX_OWNER_DIED != 0 {
+			unsafe { ThreadHeap::releasing(thread_heap) }.store(false, Ordering::Relaxed);
+		}
+		unsafe { ThreadHeap::unlock(thread_heap) };
+	}
+
+	#[inline]
+	unsafe fn releasing<'a>(thread_heap: NonNull<ThreadHeap<C>>) -> &'a AtomicBool {
+		unsafe {
+			thread_heap
+				.byte_add(offset_of!(ThreadHeap<C>, releasing))
+				.cast::<AtomicBool>()
+				.as_ref()
+		}
+	}
+
+	#[inline]
+	unsafe fn from_heap(heap: NonNull<Heap<C>>) -> NonNull<ThreadHeap<C>> {
+		unsafe { heap.byte_sub(offset_of!(ThreadHeap<C>, heap)).cast() }
+	}
+
+	#[inline]
+	unsafe fn thread_lock<'a>(thread_heap: NonNull<ThreadHeap<C>>) -> &'a AtomicU32 {
+		unsafe {
+			thread_heap
+				.byte_add(offset_of!(ThreadHeap<C>, thread_lock))
+				.cast::<AtomicU32>()
+				.as_ref()
+		}
+	}
+
+	#[inline]
+	unsafe fn heap(thread_heap: NonNull<ThreadHeap<C>>) -> NonNull<Heap<C>> {
+		unsafe { thread_heap.byte_add(offset_of!(ThreadHeap<C>, heap)).cast::<Heap<C>>() }
+	}
 }
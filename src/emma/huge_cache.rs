@@ -0,0 +1,196 @@
+//! Keeps recently freed huge objects mapped, so that allocating a huge object of the same size does not have to go
+//! through `mmap` and `munmap` again.
+//!
+//! Cached mappings are keyed by their (page-rounded) size, and remain registered with the [`registry`]. A mapping is
+//! unmapped once it has not been reused for the configured decay time, or when it would push the cache over its byte
+//! limit, in which case the least recently freed mappings are evicted first. Optionally, the memory of cached mappings
+//! is handed back to the kernel lazily (`MADV_FREE`), so that it can be reclaimed under memory pressure.
+//!
+//! The decay is lazy: Expired mappings are only unmapped when a huge object is allocated or freed, which reads the
+//! clock through the vDSO. A cache that is no longer used keeps its mappings until the instances that freed them are
+//! trimmed (see [`Emma::trim`](super::Emma::trim)).
+//!
+//! The cache is global, i.e., shared between all [`Emma`](super::Emma) instances, but a cached mapping is only reused
+//! by the instance that freed it, so that it stays registered to its owner. It is disabled by default.
+
+use core::ffi::c_void;
+use core::num::NonZero;
+use core::ptr::NonNull;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use core::time::Duration;
+
+use super::instance::InstanceId;
+use super::registry;
+use crate::mmap::{MAdviseAdvice, madvise};
+use crate::sync::Futex;
+
+/// The maximum number of mappings that are cached at the same time, regardless of their size.
+const MAX_ENTRIES: usize = 32;
+
+static LIMIT: AtomicUsize = AtomicUsize::new(0);
+static DECAY_MILLIS: AtomicU64 = AtomicU64::new(0);
+static LAZY_FREE: AtomicBool = AtomicBool::new(false);
+static HITS: AtomicUsize = AtomicUsize::new(0);
+static MISSES: AtomicUsize = AtomicUsize::new(0);
+
+static CACHE: Futex<Cache> = Futex::new(Cache::new());
+
+/// Statistics about the huge object cache, as reported by
+/// [`Emma::huge_object_cache_stats`](super::Emma::huge_object_cache_stats).
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct HugeObjectCacheStats {
+	/// The number of huge objects that were allocated by reusing a cached mapping.
+	pub hits: usize,
+	/// The number of huge objects that had to be mapped while the cache was enabled.
+	pub misses: usize,
+	/// The number of mappings that are currently cached.
+	pub cached_mappings: usize,
+	/// The number of bytes that are currently cached.
+	pub cached_bytes: usize,
+}
+
+#[derive(Debug, Copy, Clone)]
+struct Entry {
+	address: NonNull<c_void>,
+	size: usize,
+	owner: InstanceId,
+	/// The time at which the mapping was added to the cache, see [`crate::sys::monotonic_coarse_millis`].
+	freed_at: u64,
+}
+
+/// The cached mappings, ordered from least to most recently freed.
+#[derive(Debug)]
+struct Cache {
+	entries: [Option<Entry>; MAX_ENTRIES],
+	len: usize,
+	bytes: usize,
+}
+
+unsafe impl Send for Cache {}
+
+impl Cache {
+	const fn new() -> Self {
+		Self {
+			entries: [None; MAX_ENTRIES],
+			len: 0,
+			bytes: 0,
+		}
+	}
+
+	fn remove(&mut self, index: usize) -> Entry {
+		debug_assert!(index < self.len);
+		let entry = self.entries[index].take().unwrap();
+		self.entries[index..self.len].rotate_left(1);
+		self.len -= 1;
+		self.bytes -= entry.size;
+		entry
+	}
+
+	/// Unmaps the least recently freed mappings, until the cache can take a mapping of `size` bytes while holding at most
+	/// `limit` bytes.
+	unsafe fn evict_for(&mut self, size: usize, limit: usize) {
+		while self.len > 0 && (self.len == MAX_ENTRIES || self.bytes + size > limit) {
+			let entry = self.remove(0);
+			unsafe { registry::unmap(entry.address) };
+		}
+	}
+
+	/// Unmaps all mappings that have been cached for longer than the decay time.
+	unsafe fn evict_expired(&mut self, now: u64) {
+		let decay = DECAY_MILLIS.load(Ordering::Relaxed);
+		while let Some(entry) = self.entries[0]
+			&& now.saturating_sub(entry.freed_at) > decay
+		{
+			self.remove(0);
+			unsafe { registry::unmap(entry.address) };
+		}
+	}
+}
+
+/// Configures the cache to hold up to `limit` bytes, and to unmap mappings that have not been reused within `decay`. A
+/// `limit` of `0` disables the cache and unmaps everything that it currently holds.
+pub fn configure(limit: usize, decay: Duration, lazy_free: bool) {
+	LIMIT.store(limit, Ordering::Relaxed);
+	DECAY_MILLIS.store(decay.as_millis().try_into().unwrap_or(u64::MAX), Ordering::Relaxed);
+	LAZY_FREE.store(lazy_free, Ordering::Relaxed);
+
+	let mut cache = CACHE.lock();
+	unsafe { cache.evict_for(0, limit) };
+}
+
+pub fn stats() -> HugeObjectCacheStats {
+	let cache = CACHE.lock();
+	HugeObjectCacheStats {
+		hits: HITS.load(Ordering::Relaxed),
+		misses: MISSES.load(Ordering::Relaxed),
+		cached_mappings: cache.len,
+		cached_bytes: cache.bytes,
+	}
+}
+
+/// Takes a cached mapping of `owner` of exactly `size` bytes that satisfies `alignment`, preferring the most recently
+/// freed one.
+pub fn take(size: NonZero<usize>, alignment: NonZero<usize>, owner: InstanceId) -> Option<NonNull<c_void>> {
+	if LIMIT.load(Ordering::Relaxed) == 0 {
+		return None;
+	}
+
+	let mut cache = CACHE.lock();
+	unsafe { cache.evict_expired(crate::sys::monotonic_coarse_millis()) };
+	let index = cache.entries[..cache.len].iter().rposition(|entry| {
+		let entry = entry.as_ref().unwrap();
+		entry.size == size.get() && entry.owner == owner && entry.address.as_ptr() as usize & (alignment.get() - 1) == 0
+	});
+	if let Some(index) = index {
+		HITS.fetch_add(1, Ordering::Relaxed);
+		Some(cache.remove(index).address)
+	} else {
+		MISSES.fetch_add(1, Ordering::Relaxed);
+		None
+	}
+}
+
+/// Adds a freed huge object of `owner` to the cache. Returns `false` if the mapping was not cached, in which case the
+/// caller has to unmap it.
+pub unsafe fn put(address: NonNull<c_void>, size: NonZero<usize>, owner: InstanceId) -> bool {
+	let limit = LIMIT.load(Ordering::Relaxed);
+	if size.get() > limit {
+		return false;
+	}
+
+	if LAZY_FREE.load(Ordering::Relaxed) {
+		// This is merely an optimization, so failures (e.g., on kernels predating 4.5) are ignored.
+		let _ = unsafe { madvise(address, size.get(), MAdviseAdvice::FREE) };
+	}
+
+	let now = crate::sys::monotonic_coarse_millis();
+	let mut cache = CACHE.lock();
+	unsafe {
+		cache.evict_expired(now);
+		cache.evict_for(size.get(), limit);
+	}
+	let len = cache.len;
+	cache.entries[len] = Some(Entry {
+		address,
+		size: size.get(),
+		owner,
+		freed_at: now,
+	});
+	cache.len += 1;
+	cache.bytes += size.get();
+	true
+}
+
+/// Unmaps all cached mappings of `owner`.
+pub fn evict(owner: InstanceId) {
+	let mut cache = CACHE.lock();
+	let mut index = 0;
+	while index < cache.len {
+		if cache.entries[index].unwrap().owner == owner {
+			let entry = cache.remove(index);
+			unsafe { registry::unmap(entry.address) };
+		} else {
+			index += 1;
+		}
+	}
+}
@@ -0,0 +1,222 @@
+//! Decides whether arenas (and huge objects) should be backed by transparent huge pages.
+//!
+//! Arenas are 4 MiB (or 2 MiB, see [`CompactConfig`](super::CompactConfig)) in size and alignment, so they can be
+//! backed entirely by 2 MiB pages. This reduces TLB pressure, but a huge page can only be released as a whole: Purging
+//! a part of it splits it, and `khugepaged` may later collapse it again. Therefore, [`Heap::trim`](super::Heap::trim)
+//! does not purge arenas that are advised to use huge pages.
+//!
+//! Independently of the policies, arenas for small and medium objects that stay dense can be collapsed into huge pages
+//! synchronously (`MADV_COLLAPSE`) when trimming, see [`set_collapse`].
+//!
+//! Alternatively, arenas and huge objects can be backed by pages from the hugetlb pool (`MAP_HUGETLB`), which is
+//! configured by the administrator (e.g., via `/proc/sys/vm/nr_hugepages`). If the pool is exhausted, emma falls back
+//! to normal pages. The physical memory of hugetlb mappings is never purged.
+//!
+//! The policies are global, i.e., shared between all [`Emma`](super::Emma) instances.
+
+use core::ffi::c_void;
+use core::num::NonZero;
+use core::ptr::NonNull;
+use core::sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering};
+
+use super::Tier;
+use crate::mmap::{MAdviseAdvice, madvise};
+
+/// Whether memory of a [`Tier`] should be backed by transparent huge pages.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+#[repr(u8)]
+pub enum HugePagePolicy {
+	/// Do not advise the kernel either way, which leaves the decision to the system-wide THP configuration.
+	#[default]
+	System = 0,
+	/// Advise the kernel to use huge pages (`MADV_HUGEPAGE`) as soon as the memory is mapped.
+	Always = 1,
+	/// Advise the kernel not to use huge pages (`MADV_NOHUGEPAGE`).
+	Never = 2,
+	/// Advise the kernel not to use huge pages until an arena is mostly in use, and return to normal pages once
+	/// [`Emma::trim`](super::Emma::trim) finds that it has become mostly empty again. Huge objects are not tracked, so
+	/// for them, this is the same as [`HugePagePolicy::System`].
+	WhenMostlyFull = 3,
+}
+
+impl HugePagePolicy {
+	fn from_u8(value: u8) -> Self {
+		match value {
+			0 => HugePagePolicy::System,
+			1 => HugePagePolicy::Always,
+			2 => HugePagePolicy::Never,
+			3 => HugePagePolicy::WhenMostlyFull,
+			_ => unreachable!(),
+		}
+	}
+}
+
+static POLICIES: [AtomicU8; 4] = [const { AtomicU8::new(HugePagePolicy::System as u8) }; 4];
+static COLLAPSE: AtomicBool = AtomicBool::new(false);
+static COLLAPSED_ARENAS: AtomicUsize = AtomicUsize::new(0);
+static HUGETLB_ARENAS: AtomicBool = AtomicBool::new(false);
+/// The page size to use for huge objects as [`HugetlbPageSize`], or `0` if hugetlb pages are not used for them.
+static HUGETLB_HUGE_OBJECTS: AtomicU8 = AtomicU8::new(0);
+static HUGETLB_HUGE_OBJECT_THRESHOLD: AtomicUsize = AtomicUsize::new(0);
+static HUGETLB_MAPPINGS: AtomicUsize = AtomicUsize::new(0);
+static HUGETLB_FALLBACKS: AtomicUsize = AtomicUsize::new(0);
+
+/// The number of consecutive trims for which an arena has to be mostly full before it is collapsed.
+pub const COLLAPSE_AFTER_EPOCHS: u8 = 3;
+
+/// Statistics about huge pages, as reported by [`Emma::huge_page_stats`](super::Emma::huge_page_stats).
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct HugePageStats {
+	/// The number of times an arena was collapsed into huge pages.
+	pub collapsed_arenas: usize,
+	/// The number of arenas and huge objects that were mapped from the hugetlb pool.
+	pub hugetlb_mappings: usize,
+	/// The number of arenas and huge objects that should have been mapped from the hugetlb pool, but were mapped using
+	/// normal pages instead (usually because the pool was exhausted).
+	pub hugetlb_fallbacks: usize,
+}
+
+/// The size of the pages of a hugetlb mapping.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[repr(u8)]
+pub enum HugetlbPageSize {
+	/// 2 MiB pages (`MAP_HUGE_2MB`)
+	Size2MiB = 1,
+	/// 1 GiB pages (`MAP_HUGE_1GB`)
+	Size1GiB = 2,
+}
+
+impl HugetlbPageSize {
+	#[inline]
+	pub const fn bytes(self) -> NonZero<usize> {
+		match self {
+			HugetlbPageSize::Size2MiB => NonZero::new(2 * 1024 * 1024).unwrap(),
+			HugetlbPageSize::Size1GiB => NonZero::new(1024 * 1024 * 1024).unwrap(),
+		}
+	}
+}
+
+pub fn set_policy(tier: Tier, policy: HugePagePolicy) {
+	POLICIES[tier as usize].store(policy as u8, Ordering::Relaxed);
+}
+
+#[inline]
+pub fn policy(tier: Tier) -> HugePagePolicy {
+	HugePagePolicy::from_u8(POLICIES[tier as usize].load(Ordering::Relaxed))
+}
+
+pub fn set_collapse(enabled: bool) {
+	COLLAPSE.store(enabled, Ordering::Relaxed);
+}
+
+#[inline]
+pub fn collapse_enabled() -> bool {
+	COLLAPSE.load(Ordering::Relaxed)
+}
+
+pub fn stats() -> HugePageStats {
+	HugePageStats {
+		collapsed_arenas: COLLAPSED_ARENAS.load(Ordering::Relaxed),
+		hugetlb_mappings: HUGETLB_MAPPINGS.load(Ordering::Relaxed),
+		hugetlb_fallbacks: HUGETLB_FALLBACKS.load(Ordering::Relaxed),
+	}
+}
+
+pub fn set_hugetlb_arenas(enabled: bool) {
+	HUGETLB_ARENAS.store(enabled, Ordering::Relaxed);
+}
+
+pub fn set_hugetlb_huge_objects(page_size: Option<HugetlbPageSize>, threshold: usize) {
+	HUGETLB_HUGE_OBJECT_THRESHOLD.store(threshold, Ordering::Relaxed);
+	HUGETLB_HUGE_OBJECTS.store(
+		page_size.map(|page_size| page_size as u8).unwrap_or(0),
+		Ordering::Relaxed,
+	);
+}
+
+/// Returns the size of the hugetlb pages that a new mapping of `size` bytes for `tier` should use, if any. Arenas only
+/// ever use 2 MiB pages, as they are smaller than 1 GiB.
+pub fn hugetlb_page_size(tier: Tier, size: usize) -> Option<HugetlbPageSize> {
+	if tier != Tier::Huge {
+		HUGETLB_ARENAS
+			.load(Ordering::Relaxed)
+			.then_some(HugetlbPageSize::Size2MiB)
+	} else if size >= HUGETLB_HUGE_OBJECT_THRESHOLD.load(Ordering::Relaxed) {
+		match HUGETLB_HUGE_OBJECTS.load(Ordering::Relaxed) {
+			0 => None,
+			1 => Some(HugetlbPageSize::Size2MiB),
+			2 => Some(HugetlbPageSize::Size1GiB),
+			_ => unreachable!(),
+		}
+	} else {
+		None
+	}
+}
+
+/// Counts an attempt to obtain a mapping from the hugetlb pool.
+pub fn record_hugetlb(success: bool) {
+	if success {
+		HUGETLB_MAPPINGS.fetch_add(1, Ordering::Relaxed);
+	} else {
+		HUGETLB_FALLBACKS.fetch_add(1, Ordering::Relaxed);
+	}
+}
+
+/// Returns whether an arena that uses `used` out of `total` units should be promoted to huge pages.
+#[inline]
+pub fn is_mostly_full(used: u32, total: u32) -> bool {
+	used as u64 * 4 >= total as u64 * 3
+}
+
+/// Returns whether an arena that uses `used` out of `total` units should be demoted to normal pages. The gap to
+/// [`is_mostly_full`] prevents arenas from oscillating between the two states.
+#[inline]
+pub fn is_mostly_empty(used: u32, total: u32) -> bool {
+	(used as u64) * 2 < total as u64
+}
+
+/// Applies the policy of `tier` to a freshly mapped region. Returns whether the region was advised to use huge pages.
+pub unsafe fn advise_new_mapping(address: NonNull<c_void>, size: usize, tier: Tier) -> bool {
+	match policy(tier) {
+		HugePagePolicy::System => false,
+		HugePagePolicy::Always => unsafe { advise(address, size, MAdviseAdvice::HUGEPAGE) },
+		HugePagePolicy::Never => {
+			unsafe { advise(address, size, MAdviseAdvice::NOHUGEPAGE) };
+			false
+		}
+		HugePagePolicy::WhenMostlyFull => {
+			if tier != Tier::Huge {
+				unsafe { advise(address, size, MAdviseAdvice::NOHUGEPAGE) };
+			}
+			false
+		}
+	}
+}
+
+/// Advises the kernel to back a region that has become mostly full with huge pages. Returns whether that succeeded.
+#[cold]
+pub unsafe fn promote(address: NonNull<c_void>, size: usize) -> bool {
+	unsafe { advise(address, size, MAdviseAdvice::HUGEPAGE) }
+}
+
+/// Advises the kernel to no longer back a region with huge pages.
+#[cold]
+pub unsafe fn demote(address: NonNull<c_void>, size: usize) {
+	unsafe { advise(address, size, MAdviseAdvice::NOHUGEPAGE) };
+}
+
+/// Synchronously collapses a region into huge pages, which may require copying all of its contents. Returns whether
+/// that succeeded, which requires at least linux 6.1.
+#[cold]
+pub unsafe fn collapse(address: NonNull<c_void>, size: usize) -> bool {
+	let collapsed = unsafe { advise(address, size, MAdviseAdvice::COLLAPSE) };
+	if collapsed {
+		COLLAPSED_ARENAS.fetch_add(1, Ordering::Relaxed);
+	}
+	collapsed
+}
+
+unsafe fn advise(address: NonNull<c_void>, size: usize, advice: MAdviseAdvice) -> bool {
+	// Kernels without `CONFIG_TRANSPARENT_HUGEPAGE` reject the advice with `EINVAL`, which is fine.
+	unsafe { madvise(address, size, advice) }.is_ok()
+}
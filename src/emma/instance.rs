@@ -0,0 +1,209 @@
+use core::any::TypeId;
+use core::num::NonZero;
+use core::ptr::{self, NonNull};
+use core::sync::atomic::{AtomicPtr, Ordering};
+
+#[cfg(not(any(thread_heaps, feature = "sharded")))]
+use super::Heap;
+use super::arena::paged_objects::{self, Medium, Small};
+use super::arena::pool::ArenaPool;
+use super::config::Config;
+#[cfg(thread_heaps)]
+use super::heap_manager::HeapManager;
+#[cfg(feature = "sharded")]
+use super::shards::Shards;
+use super::{huge_cache, region, registry};
+use crate::mmap::alloc_aligned;
+use crate::sync::Futex;
+
+/// Identifies an [`Instance`] by its address, which is never zero. Instances are never unmapped, but they are handed to
+/// a new [`Emma`](super::Emma) once their previous one has been dropped (see [`Instance::recycle`]).
+pub type InstanceId = usize;
+
+/// Instances of all configurations whose [`Emma`](super::Emma) has been dropped, linked via [`RecycledLink::next`].
+static RECYCLED: Futex<Recycled> = Futex::new(Recycled(None));
+
+#[derive(Debug)]
+struct Recycled(Option<NonNull<RecycledLink>>);
+
+unsafe impl Send for Recycled {}
+
+/// Links an [`Instance`] into [`RECYCLED`], at the very start of the instance.
+#[derive(Debug)]
+struct RecycledLink {
+	/// The configuration of the instance, as only an [`Emma`](super::Emma) of the same configuration may take it over.
+	config: TypeId,
+	/// The next instance in [`RECYCLED`], while this instance is in it.
+	next: AtomicPtr<RecycledLink>,
+}
+
+/// Everything that one [`Emma`](super::Emma) does not share with other instances: its heaps and the empty arenas that
+/// they pass between each other. Every arena and huge object is registered as owned by the instance it was mapped for
+/// (see [`super::registry`]), so that objects and statistics of different instances never mix.
+///
+/// The instance is mapped when its [`Emma`](super::Emma) is first used, so that it keeps its address even if the
+/// [`Emma`](super::Emma) is moved, which heaps refer to it by.
+#[derive(Debug)]
+#[repr(C)]
+pub struct Instance<C: Config> {
+	/// Links the instance into [`RECYCLED`], which holds instances of every configuration.
+	recycled: RecycledLink,
+	#[cfg(not(any(thread_heaps, feature = "sharded")))]
+	pub heap: Futex<Heap<C>>,
+	#[cfg(thread_heaps)]
+	pub heap_manager: HeapManager<C>,
+	#[cfg(feature = "sharded")]
+	pub shards: Shards<C>,
+	/// Empty arenas for small objects that are shared between the heaps of this instance.
+	pub small_object_pool: ArenaPool,
+	/// Empty arenas for medium objects that are shared between the heaps of this instance.
+	pub medium_object_pool: ArenaPool,
+	/// Empty arenas that are shared between the regions of this instance (see [`super::Region`]).
+	pub region_pool: ArenaPool,
+}
+
+impl<C: Config> Instance<C> {
+	/// Returns the instance that `slot` points to, after setting up a new one if `slot` is still null, which is taken
+	/// from the recycled instances if there are any. Returns `None` if the instance could not be mapped.
+	#[inline]
+	pub fn get_or_map(slot: &AtomicPtr<Instance<C>>) -> Option<&Instance<C>> {
+		match unsafe { slot.load(Ordering::Acquire).as_ref() } {
+			Some(instance) => Some(instance),
+			None => Self::map(slot),
+		}
+	}
+
+	#[cold]
+	fn map(slot: &AtomicPtr<Instance<C>>) -> Option<&Instance<C>> {
+		let instance = match Self::take_recycled() {
+			Some(instance) => instance,
+			None => Self::map_new()?,
+		};
+
+		match slot.compare_exchange(ptr::null_mut(), instance.as_ptr(), Ordering::AcqRel, Ordering::Acquire) {
+			Ok(_) => Some(unsafe { instance.as_ref() }),
+			Err(winner) => unsafe {
+				// Another thread set up the instance in the meantime, so ours has never been used.
+				Self::push_recycled(instance);
+				Some(&*winner)
+			},
+		}
+	}
+
+	fn map_new() -> Option<NonNull<Instance<C>>> {
+		let size = NonZero::new((size_of::<Instance<C>>() + 4095) & !4095).unwrap();
+		let instance = unsafe {
+			alloc_aligned(
+				size,
+				NonZero::new(align_of::<Instance<C>>()).unwrap(),
+				C::ALLOC_ALIGNED_RETRIES,
+				c"emma:instance",
+			)?
+			.cast::<Instance<C>>()
+		};
+		unsafe {
+			instance.write(Instance {
+				recycled: RecycledLink {
+					config: TypeId::of::<C>(),
+					next: AtomicPtr::new(ptr::null_mut()),
+				},
+				#[cfg(not(any(thread_heaps, feature = "sharded")))]
+				heap: Futex::new(Heap::new(instance)),
+				#[cfg(thread_heaps)]
+				heap_manager: HeapManager::new(),
+				#[cfg(feature = "sharded")]
+				shards: Shards::new(),
+				small_object_pool: paged_objects::new_pool::<Small<C>>(),
+				medium_object_pool: paged_objects::new_pool::<Medium<C>>(),
+				region_pool: region::new_pool::<C>(),
+			})
+		};
+		Some(instance)
+	}
+
+	/// Takes the most recently recycled instance of the configuration `C` out of [`RECYCLED`], if there is one.
+	fn take_recycled() -> Option<NonNull<Instance<C>>> {
+		let config = TypeId::of::<C>();
+		let mut recycled = RECYCLED.lock();
+		let mut previous: Option<&RecycledLink> = None;
+		let mut next = recycled.0;
+		while let Some(link) = next {
+			let link_ref = unsafe { link.as_ref() };
+			let following = link_ref.next.load(Ordering::Relaxed);
+			if link_ref.config == config {
+				match previous {
+					Some(previous) => previous.next.store(following, Ordering::Relaxed),
+					None => recycled.0 = NonNull::new(following),
+				}
+				return Some(link.cast());
+			}
+			previous = Some(link_ref);
+			next = NonNull::new(following);
+		}
+		None
+	}
+
+	unsafe fn push_recycled(instance: NonNull<Instance<C>>) {
+		let mut recycled = RECYCLED.lock();
+		let next = recycled.0.map_or(ptr::null_mut(), NonNull::as_ptr);
+		unsafe { instance.as_ref() }
+			.recycled
+			.next
+			.store(next, Ordering::Relaxed);
+		recycled.0 = Some(instance.cast());
+	}
+
+	/// Releases all memory of `instance` (see [`Instance::reset`]) and keeps it for the next [`Emma`](super::Emma) that
+	/// needs an instance. Instances are never unmapped, as threads may still hold on to their heaps (see
+	/// [`super::ThreadHeapCache`]). Such heaps are empty, and serve as the heaps of the next [`Emma`](super::Emma).
+	///
+	/// # Safety
+	/// The same as for [`Instance::reset`]. Additionally, the [`Emma`](super::Emma) of `instance` must no longer use it.
+	pub unsafe fn recycle(instance: NonNull<Instance<C>>) {
+		unsafe {
+			instance.as_ref().reset();
+			Self::push_recycled(instance);
+		}
+	}
+
+	/// Unmaps all arenas and huge objects of this instance in a single pass over the registry, after its heaps and arena
+	/// pools have forgotten them. Arenas that regions have returned are unmapped as well, while those of regions that
+	/// are still alive are left to them.
+	///
+	/// # Safety
+	/// No other thread may use the instance concurrently, and none of its objects may be used afterwards.
+	pub unsafe fn reset(&self) {
+		let release = || unsafe {
+			self.small_object_pool.clear();
+			self.medium_object_pool.clear();
+			region::unmap_pool::<C>(&self.region_pool);
+			huge_cache::evict(self.id());
+			#[cfg(feature = "heap-profile")]
+			crate::heap_profile::forget_samples(|address| {
+				registry::owner(address, C::ARENA_SIZE as usize) == Some(self.id())
+			});
+			registry::unmap_owned(self.id());
+		};
+
+		#[cfg(not(any(thread_heaps, feature = "sharded")))]
+		{
+			let mut heap = self.heap.lock();
+			heap.reset();
+			release();
+		}
+		#[cfg(feature = "sharded")]
+		unsafe {
+			self.shards.reset();
+			release();
+		}
+		#[cfg(thread_heaps)]
+		unsafe {
+			self.heap_manager.reset(release)
+		};
+	}
+
+	#[inline]
+	pub fn id(&self) -> InstanceId {
+		self as *const Self as InstanceId
+	}
+}
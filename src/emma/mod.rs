@@ -1,47 +1,190 @@
 use core::alloc::Layout;
 use core::num::NonZero;
 use core::ptr::{self, NonNull};
-#[cfg(feature = "tls")]
-use core::sync::atomic::AtomicU64;
+use core::sync::atomic::AtomicPtr;
+#[cfg(any(thread_heaps, feature = "sharded"))]
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 
-use arena::{large_objects, medium_objects, small_objects};
+use arena::large_objects::{self, Large};
+use arena::page::{self, Page};
+use arena::paged_objects::{self, Medium, Small};
+#[cfg(any(thread_heaps, feature = "sharded"))]
+use arena::remote_frees::RemoteFrees;
+use config::{MAX_LARGE_OBJECT_BINS, MAX_MEDIUM_OBJECT_BINS, MAX_SMALL_OBJECT_BINS, Tiers};
 use const_format::assertc_eq;
-
-use crate::mmap::{alloc_aligned, munmap};
-#[cfg(not(feature = "tls"))]
-use crate::sync::Futex;
+use instance::Instance;
+#[cfg(thread_heaps)]
+use instance::InstanceId;
 
 mod arena;
+mod config;
+mod huge_cache;
+mod huge_pages;
+mod instance;
+mod region;
+mod registry;
+pub use config::{CompactConfig, Config, DefaultConfig};
+pub use huge_cache::HugeObjectCacheStats;
+pub use huge_pages::{HugePagePolicy, HugePageStats, HugetlbPageSize};
+pub use region::Region;
+pub use registry::ResidentBytes;
 
-#[cfg(feature = "tls")]
+#[cfg(thread_heaps)]
 mod heap_manager;
+#[cfg(feature = "percpu")]
+mod percpu;
+#[cfg(feature = "sharded")]
+mod shards;
 
-pub type DefaultEmma = Emma;
+pub type DefaultEmma = Emma<DefaultConfig>;
 
 /// The main allocator struct. Instantiate to interface with Emma.
+///
+/// Each instance has heaps and arenas of its own, so objects of different instances never share memory, and must be
+/// freed by the instance that allocated them. With thread-local heaps (the `tls` and `std-tls` features), threads hold
+/// on to a heap of each of the last few instances they used.
+///
+/// The geometry of arenas and tiers is set by `C` (see [`Config`]), e.g., `Emma<CompactConfig>` uses smaller arenas.
+/// Instances of different configurations can be used side by side.
 #[derive(Debug)]
-pub struct Emma {
-	#[cfg(not(feature = "tls"))]
-	heap: Futex<Heap>,
-
-	/// TODO: make static!
-	#[cfg(feature = "tls")]
-	heap_manager: heap_manager::HeapManager,
+pub struct Emma<C: Config = DefaultConfig> {
+	/// The state of this instance, which is mapped when it is first needed (see [`Instance::get_or_map`]).
+	instance: AtomicPtr<Instance<C>>,
 }
 
-impl Emma {
-	/// Create a new [`Emma`] instance.
+impl<C: Config> Emma<C> {
+	/// Create a new [`Emma`] instance. Fails to compile if the geometry of `C` is invalid (see [`Config`]).
 	#[allow(clippy::new_without_default)] // Sadly, default is not const.
 	pub const fn new() -> Self {
+		let () = C::VALID;
 		Self {
-			#[cfg(not(feature = "tls"))]
-			heap: Futex::new(Heap::new()),
+			instance: AtomicPtr::new(ptr::null_mut()),
+		}
+	}
 
-			#[cfg(feature = "tls")]
-			heap_manager: heap_manager::HeapManager::new(),
+	/// Returns the state of this instance, which is mapped if this is the first use of the instance.
+	#[inline(always)]
+	fn instance(&self) -> Option<&Instance<C>> {
+		Instance::get_or_map(&self.instance)
+	}
+
+	/// Returns the state of this instance, unless nothing has been allocated from it yet.
+	#[inline(always)]
+	fn mapped_instance(&self) -> Option<&Instance<C>> {
+		unsafe { self.instance.load(core::sync::atomic::Ordering::Acquire).as_ref() }
+	}
+
+	/// Determines how many bytes of each tier are currently backed by physical memory, by querying the kernel (via
+	/// `mincore`) for every arena and huge object. Arenas are reserved in full but only populated as they are used, so
+	/// this is usually much less than the address space that emma has mapped.
+	///
+	/// The accounting covers only the memory of this [`Emma`] instance. Creating or releasing arenas and huge objects
+	/// blocks while the kernel is queried.
+	pub fn resident_bytes(&self) -> ResidentBytes {
+		self.mapped_instance().map_or_else(ResidentBytes::default, |instance| {
+			registry::resident_bytes(instance.id())
+		})
+	}
+
+	/// Sets whether newly mapped memory of `tier` should be backed by transparent huge pages. Arenas that already exist
+	/// keep their previous advice. The policies are shared by all [`Emma`] instances.
+	pub fn set_huge_page_policy(&self, tier: Tier, policy: HugePagePolicy) {
+		huge_pages::set_policy(tier, policy);
+	}
+
+	/// Enables collapsing arenas for small and medium objects into huge pages (`MADV_COLLAPSE`) from [`Emma::trim`],
+	/// once they have stayed mostly full for a few consecutive trims, and none of their pages have been released. The
+	/// setting is shared by all [`Emma`] instances.
+	pub fn set_huge_page_collapse(&self, enabled: bool) {
+		huge_pages::set_collapse(enabled);
+	}
+
+	/// Backs new arenas with 2 MiB pages from the hugetlb pool (`MAP_HUGETLB`), which has to be reserved by the
+	/// administrator. Arenas fall back to normal pages while the pool is exhausted. The physical memory of hugetlb arenas
+	/// is never released by [`Emma::trim`]. The setting is shared by all [`Emma`] instances.
+	pub fn set_hugetlb_arenas(&self, enabled: bool) {
+		huge_pages::set_hugetlb_arenas(enabled);
+	}
+
+	/// Backs new huge objects of at least `threshold` bytes with pages of `page_size` from the hugetlb pool, or disables
+	/// this for `None`. The size of each such object is rounded up to a multiple of `page_size`. Objects fall back to
+	/// normal pages while the pool is exhausted. The setting is shared by all [`Emma`] instances.
+	pub fn set_hugetlb_huge_objects(&self, page_size: Option<HugetlbPageSize>, threshold: usize) {
+		huge_pages::set_hugetlb_huge_objects(page_size, threshold);
+	}
+
+	/// Returns statistics about huge pages. The statistics cover all [`Emma`] instances.
+	pub fn huge_page_stats(&self) -> HugePageStats {
+		huge_pages::stats()
+	}
+
+	/// Returns pages that no longer hold any objects to the reserve and releases their physical memory to the OS, unless
+	/// they are backed by huge pages (see [`HugePagePolicy`]). Once a heap holds more than an arena's worth of empty
+	/// pages, arenas that are entirely empty are donated to a pool that is shared by all heaps of this instance, which
+	/// take arenas from it before mapping new ones. Collapses dense arenas into huge pages, if enabled via
+	/// [`Emma::set_huge_page_collapse`].
+	///
+	/// Also unmaps the huge objects of this instance held by the huge object cache (see [`Emma::set_huge_object_cache`]).
+	/// With the `tls` feature, only the heap of the calling thread is trimmed, after it adopted the pages of a heap whose
+	/// thread has exited, if there is one. Without the `std` feature, this is the only way such heaps are found before a
+	/// new thread takes them over. Objects that the calling thread freed on behalf of other threads, which are buffered
+	/// to be handed back in batches, are handed back first. With the `sharded` feature, all shards are trimmed. With the
+	/// `percpu` feature, the small objects that are cached for the CPU of the calling thread are freed first, while those
+	/// of other CPUs remain cached.
+	pub fn trim(&self) {
+		let Some(instance) = self.mapped_instance() else {
+			return;
+		};
+		huge_cache::evict(instance.id());
+		#[cfg(not(any(thread_heaps, feature = "sharded")))]
+		unsafe {
+			instance.heap.lock().trim()
+		};
+		#[cfg(feature = "percpu")]
+		instance.shards.flush_cache(NonNull::from(instance));
+		#[cfg(feature = "sharded")]
+		instance.shards.for_each(|heap| unsafe { heap.trim() });
+		#[cfg(thread_heaps)]
+		if let Some(mut thread_heap) = current_thread_heap(instance) {
+			unsafe {
+				// Heaps whose owner died without releasing them are only discovered here, as that takes a syscall per heap.
+				thread_heap.as_mut().adopt_orphan(true);
+				thread_heap.as_mut().trim();
+			}
 		}
 	}
 
+	/// Releases all memory of this instance at once, by unmapping every arena and huge object that it owns in a single
+	/// pass, instead of freeing objects one by one. The instance remains usable, and maps new arenas as needed. Does
+	/// nothing if nothing has been allocated from the instance yet. Dropping an [`Emma`] resets it as well.
+	///
+	/// # Safety
+	/// No object allocated from this instance may be used or freed afterwards, and no other thread may use the instance
+	/// while it is reset.
+	pub unsafe fn reset(&self) {
+		if let Some(instance) = self.mapped_instance() {
+			unsafe { instance.reset() };
+		}
+	}
+
+	/// Keeps up to `limit` bytes of freed huge objects mapped, so that they can be reused for huge objects of the same
+	/// (page-rounded) size. Mappings that have not been reused within `decay` are unmapped. With `lazy_free`, the memory
+	/// of cached mappings is released lazily (`MADV_FREE`), so that the kernel may reclaim it under memory pressure. A
+	/// `limit` of `0` (the default) disables the cache. The cache is shared by all [`Emma`] instances, but each mapping
+	/// is only reused by the instance that freed it.
+	///
+	/// Mappings only expire while huge objects are allocated or freed, so an idle cache keeps its mappings. They count
+	/// towards the huge tier of [`Emma::resident_bytes`], and are unmapped by [`Emma::trim`] of the instance that freed
+	/// them.
+	pub fn set_huge_object_cache(&self, limit: usize, decay: core::time::Duration, lazy_free: bool) {
+		huge_cache::configure(limit, decay, lazy_free);
+	}
+
+	/// Returns statistics about the huge object cache. The statistics cover all [`Emma`] instances.
+	pub fn huge_object_cache_stats(&self) -> HugeObjectCacheStats {
+		huge_cache::stats()
+	}
+
 	/// Print internals of the [`Emma`] type. This is probably not interesting for consumers of this library.
 	pub const fn print_internals() -> impl core::fmt::Debug {
 		struct F(fn(&mut core::fmt::Formatter) -> core::fmt::Result);
@@ -64,12 +207,42 @@ impl Emma {
 		const TLS_ENABLED: &str = "enabled";
 		writeln!(f, "tls {TLS_ENABLED}")?;
 
+		#[cfg(not(feature = "std-tls"))]
+		const STD_TLS_ENABLED: &str = "disabled";
+		#[cfg(feature = "std-tls")]
+		const STD_TLS_ENABLED: &str = "enabled";
+		writeln!(f, "std-tls {STD_TLS_ENABLED}")?;
+
+		#[cfg(not(feature = "sharded"))]
+		const SHARDED_ENABLED: &str = "disabled";
+		#[cfg(feature = "sharded")]
+		const SHARDED_ENABLED: &str = "enabled";
+		writeln!(f, "sharded {SHARDED_ENABLED}")?;
+
+		#[cfg(not(feature = "percpu"))]
+		const PERCPU_ENABLED: &str = "disabled";
+		#[cfg(feature = "percpu")]
+		const PERCPU_ENABLED: &str = "enabled";
+		writeln!(f, "percpu {PERCPU_ENABLED}")?;
+
 		#[cfg(not(feature = "boundary-checks"))]
 		const BOUNDARY_CHECKS_ENABLED: &str = "disabled";
 		#[cfg(feature = "boundary-checks")]
 		const BOUNDARY_CHECKS_ENABLED: &str = "enabled";
 		writeln!(f, "boundary checks {BOUNDARY_CHECKS_ENABLED}")?;
 
+		#[cfg(not(feature = "heap-profile"))]
+		const HEAP_PROFILE_ENABLED: &str = "disabled";
+		#[cfg(feature = "heap-profile")]
+		const HEAP_PROFILE_ENABLED: &str = "enabled";
+		writeln!(f, "heap profile {HEAP_PROFILE_ENABLED}")?;
+
+		#[cfg(not(feature = "trace"))]
+		const TRACE_ENABLED: &str = "disabled";
+		#[cfg(feature = "trace")]
+		const TRACE_ENABLED: &str = "enabled";
+		writeln!(f, "trace {TRACE_ENABLED}")?;
+
 		#[cfg(not(debug_assertions))]
 		const DEBUG_ASSERTIONS_ENABLED: &str = "disabled";
 		#[cfg(debug_assertions)]
@@ -79,127 +252,520 @@ impl Emma {
 
 		writeln!(f, "Object Sizes")?;
 		writeln!(f, "Emma: size {} align {}", size_of::<Self>(), align_of::<Self>())?;
-		writeln!(f, "Heap: size {} align {}", size_of::<Heap>(), align_of::<Heap>())?;
+		writeln!(
+			f,
+			"Instance: size {} align {}",
+			size_of::<Instance<C>>(),
+			align_of::<Instance<C>>()
+		)?;
+		writeln!(f, "Heap: size {} align {}", size_of::<Heap<C>>(), align_of::<Heap<C>>())?;
 
 		Ok(())
 	}
 }
 
-/// The per-thread heap. Can be accessed without locking, but may not be sent between threads.
+#[cfg(feature = "heap-profile")]
+impl<C: Config> Emma<C> {
+	/// Sets the average number of allocated bytes between two allocations that are sampled for the heap profile. An
+	/// interval of `0` disables sampling. The sampling interval is shared by all [`Emma`] instances.
+	pub fn set_heap_profile_sampling_interval(&self, bytes: usize) {
+		crate::heap_profile::set_sampling_interval(bytes);
+	}
+
+	/// Writes all sampled allocations that are still live in the gperftools heap profile format, which can be consumed
+	/// by `pprof`. The heap profile is shared by all [`Emma`] instances.
+	///
+	/// Call stacks are captured by walking frame pointers, so the program should be compiled with
+	/// `-C force-frame-pointers=yes`. `writer` may allocate, but the profile is not an atomic snapshot if other threads
+	/// (de)allocate memory concurrently.
+	pub fn dump_heap_profile<W: core::fmt::Write>(&self, writer: &mut W) -> core::fmt::Result {
+		crate::heap_profile::dump(writer)
+	}
+}
+
+#[cfg(feature = "trace")]
+impl<C: Config> Emma<C> {
+	/// Writes the trace header to `fd` and starts recording every allocation, deallocation and reallocation into it. The
+	/// format of the trace is described in [`crate::trace`]. Returns `false` if the header could not be written.
+	///
+	/// The trace is shared by all [`Emma`] instances. Records are buffered per thread, so each thread should call
+	/// [`Emma::flush_trace`] before it terminates, unless the trace is stopped afterwards, which flushes the records of
+	/// all threads. With the `std` and `std-tls` features, threads flush their records when they exit.
+	///
+	/// # Safety
+	/// `fd` must remain open until tracing is stopped and all threads have flushed their records.
+	pub unsafe fn start_trace(&self, fd: core::ffi::c_int) -> bool {
+		unsafe { crate::trace::start(fd) }.is_ok()
+	}
+
+	/// Writes the records buffered by the calling thread to the trace.
+	pub fn flush_trace(&self) {
+		crate::trace::flush();
+	}
+
+	/// Flushes the records of all threads and stops recording.
+	pub fn stop_trace(&self) {
+		crate::trace::stop();
+	}
+
+	#[inline]
+	fn record_trace(
+		&self,
+		timestamp: u64,
+		kind: crate::trace::Kind,
+		ptr: *mut u8,
+		new_ptr: *mut u8,
+		size: usize,
+		alignment: usize,
+	) {
+		if !crate::trace::is_active() {
+			return;
+		}
+
+		crate::trace::record(crate::trace::Record {
+			timestamp,
+			ptr: ptr as u64,
+			new_ptr: new_ptr as u64,
+			size: size as u64,
+			alignment: alignment as u32,
+			tid: crate::trace::tid(),
+			kind,
+			tier: Tier::of::<C>(NonZero::new((size + alignment - 1) & !(alignment - 1)).unwrap()) as u8,
+			padding: [0; 6],
+		});
+	}
+}
+
+/// Returns the stack of pages of `tier` that are handed back to the heap `owner`. Pages are handed back by whoever
+/// clears their [`arena::remote_frees::FULL`] mark, and returned to their bins by the heap in [`Heap::reclaim`]. Heaps
+/// are never unmapped, so the stack remains valid even if `owner` has been orphaned in the meantime.
+#[cfg(any(thread_heaps, feature = "sharded"))]
+unsafe fn reclaimed_pages<'a, C: Config>(owner: HeapId, tier: Tier) -> &'a AtomicUsize {
+	debug_assert_ne!(owner, 0);
+	unsafe { &(*(owner as usize as *const Heap<C>)).reclaimed_pages[tier as usize] }
+}
+
+/// The number of instances whose heaps a thread holds at the same time. Acquiring a heap of yet another instance
+/// releases the heap that was acquired the longest ago.
+#[cfg(thread_heaps)]
+const THREAD_HEAP_CACHE_SIZE: usize = 4;
+
+/// The heaps that a thread holds, each of which belongs to the instance in the same slot of `instances`. Slots without
+/// a heap have the instance id zero, which no instance uses. Instances of all configurations share the cache, which
+/// remembers how to release each heap (see [`CachedHeap`]).
+#[cfg(thread_heaps)]
+#[derive(Debug)]
+struct ThreadHeapCache {
+	instances: [InstanceId; THREAD_HEAP_CACHE_SIZE],
+	heaps: [Option<CachedHeap>; THREAD_HEAP_CACHE_SIZE],
+}
+
+/// A heap in the [`ThreadHeapCache`], whose configuration is only known to `release` (see [`release_thread_heap`]).
+/// An instance keeps its configuration even when it is recycled, so its id tells which configuration the heap has.
+#[cfg(thread_heaps)]
+#[derive(Debug, Copy, Clone)]
+struct CachedHeap {
+	heap: NonNull<u8>,
+	release: unsafe fn(NonNull<u8>),
+}
+
+#[cfg(thread_heaps)]
+impl CachedHeap {
+	unsafe fn release(self) {
+		unsafe { (self.release)(self.heap) };
+	}
+}
+
+#[cfg(thread_heaps)]
+impl ThreadHeapCache {
+	const fn new() -> Self {
+		Self {
+			instances: [0; THREAD_HEAP_CACHE_SIZE],
+			heaps: [None; THREAD_HEAP_CACHE_SIZE],
+		}
+	}
+
+	/// Returns the cached heap of `instance`, whose configuration is `C`.
+	#[inline(always)]
+	fn get<C: Config>(&self, instance: InstanceId) -> Option<NonNull<Heap<C>>> {
+		let slot = self.instances.iter().position(|&cached| cached == instance)?;
+		self.heaps[slot].map(|cached| cached.heap.cast())
+	}
+
+	/// Caches `heap` of `instance` in the first slot. Returns the heap that was pushed out of the last slot, which the
+	/// caller has to release.
+	fn insert<C: Config>(&mut self, instance: InstanceId, heap: NonNull<Heap<C>>) -> Option<CachedHeap> {
+		self.instances.rotate_right(1);
+		self.heaps.rotate_right(1);
+		self.instances[0] = instance;
+		self.heaps[0].replace(CachedHeap {
+			heap: heap.cast(),
+			release: release_thread_heap::<C>,
+		})
+	}
+
+	/// Releases all cached heaps (see [`release_thread_heap`]).
+	#[cfg(any(feature = "std", feature = "std-tls"))]
+	unsafe fn release_all(&mut self) {
+		for (instance, heap) in self.instances.iter_mut().zip(self.heaps.iter_mut()) {
+			*instance = 0;
+			if let Some(heap) = heap.take() {
+				unsafe { heap.release() };
+			}
+		}
+	}
+}
+
+/// The per-thread heaps. Can be accessed without locking, but may not be sent between threads.
 #[cfg(feature = "tls")]
 #[thread_local]
-static mut THREAD_HEAP: Option<NonNull<Heap>> = None;
+static THREAD_HEAP_CACHE: core::cell::UnsafeCell<ThreadHeapCache> = core::cell::UnsafeCell::new(ThreadHeapCache::new());
 
+#[cfg(feature = "std-tls")]
+std::thread_local! {
+	/// The per-thread heaps, which are released when the thread exits, see [`ThreadHeapSlot`].
+	static THREAD_HEAP_CACHE: ThreadHeapSlot = const {
+		ThreadHeapSlot(core::cell::UnsafeCell::new(ThreadHeapCache::new()))
+	};
+}
+
+/// Returns the heap of `instance` that the calling thread holds, if any.
 #[cfg(feature = "tls")]
-impl Emma {
-	fn thread_heap(&self) -> Option<NonNull<Heap>> {
-		unsafe {
-			if let Some(thread_heap) = THREAD_HEAP {
-				Some(thread_heap)
-			} else if let Some(thread_heap) = self.heap_manager.acquire_thread_heap() {
-				debug_assert_ne!(thread_heap.as_ref().id, 0);
-				THREAD_HEAP = Some(thread_heap);
-				Some(thread_heap)
-			} else {
-				None
+#[inline(always)]
+fn current_thread_heap<C: Config>(instance: &Instance<C>) -> Option<NonNull<Heap<C>>> {
+	unsafe { (*THREAD_HEAP_CACHE.get()).get(instance.id()) }
+}
+
+/// Returns the heap of `instance` that the calling thread holds, if any. The thread holds no heaps once its
+/// thread-local storage is being torn down.
+#[cfg(feature = "std-tls")]
+#[inline(always)]
+fn current_thread_heap<C: Config>(instance: &Instance<C>) -> Option<NonNull<Heap<C>>> {
+	THREAD_HEAP_CACHE
+		.try_with(|slot| unsafe { (*slot.0.get()).get(instance.id()) })
+		.ok()
+		.flatten()
+}
+
+/// Acquires a heap of `instance` for the calling thread and caches it, which may release another heap that the thread
+/// holds.
+#[cfg(feature = "tls")]
+#[cold]
+unsafe fn acquire_thread_heap<C: Config>(instance: &Instance<C>) -> Option<NonNull<Heap<C>>> {
+	unsafe {
+		let thread_heap = instance.heap_manager.acquire_thread_heap(NonNull::from(instance))?;
+		debug_assert_ne!(thread_heap.as_ref().id, 0);
+		let evicted = (*THREAD_HEAP_CACHE.get()).insert(instance.id(), thread_heap);
+		// Touching the guard registers its destructor. This fails if the thread is already being torn down, in which
+		// case the heap is only released once the kernel reports the thread as dead.
+		#[cfg(feature = "std")]
+		let _ = THREAD_EXIT_GUARD.try_with(|_| ());
+		if let Some(evicted) = evicted {
+			evicted.release();
+		}
+		Some(thread_heap)
+	}
+}
+
+/// Acquires a heap of `instance` for the calling thread and caches it, which may release another heap that the thread
+/// holds. Returns `None` if the thread-local storage of the calling thread is being torn down.
+#[cfg(feature = "std-tls")]
+#[cold]
+unsafe fn acquire_thread_heap<C: Config>(instance: &Instance<C>) -> Option<NonNull<Heap<C>>> {
+	THREAD_HEAP_CACHE
+		.try_with(|slot| unsafe {
+			let thread_heap = instance.heap_manager.acquire_thread_heap(NonNull::from(instance))?;
+			debug_assert_ne!(thread_heap.as_ref().id, 0);
+			if let Some(evicted) = (*slot.0.get()).insert(instance.id(), thread_heap) {
+				evicted.release();
 			}
+			Some(thread_heap)
+		})
+		.ok()
+		.flatten()
+}
+
+#[cfg(thread_heaps)]
+impl<C: Config> Emma<C> {
+	/// Returns the heap of this instance that the calling thread holds, acquiring one if it does not hold one yet.
+	#[inline(always)]
+	fn thread_heap(&self) -> Option<NonNull<Heap<C>>> {
+		let instance = self.instance()?;
+		current_thread_heap(instance).or_else(|| unsafe { acquire_thread_heap(instance) })
+	}
+}
+
+#[cfg(feature = "std-tls")]
+impl<C: Config> Emma<C> {
+	/// Allocates from a heap that is acquired for this allocation only, as the thread-local storage of the calling thread
+	/// is being torn down, so that it can no longer hold on to a heap.
+	#[cold]
+	unsafe fn alloc_after_thread_exit(&self, size: NonZero<usize>, alignment: NonZero<usize>) -> *mut u8 {
+		let Some(instance) = self.instance() else {
+			return ptr::null_mut();
+		};
+		unsafe {
+			let Some(mut heap) = instance.heap_manager.acquire_thread_heap(NonNull::from(instance)) else {
+				return ptr::null_mut();
+			};
+			let ret = heap.as_mut().alloc(size, alignment);
+			instance.heap_manager.release_thread_heap(heap);
+			ret
 		}
 	}
 }
 
-const NUM_SMALL_OBJECT_BINS: usize = ((2 * small_objects::MAXIMUM_OBJECT_ALIGNMENT - 8) / 8) as usize;
-const NUM_MEDIUM_OBJECT_BINS: usize = ((u32::ilog2(medium_objects::MAXIMUM_OBJECT_ALIGNMENT)
-	- u32::ilog2(small_objects::MAXIMUM_OBJECT_ALIGNMENT))
-	* 4) as usize;
-const NUM_LARGE_OBJECT_BINS: usize = ((u32::ilog2(large_objects::MAXIMUM_OBJECT_ALIGNMENT)
-	- u32::ilog2(medium_objects::MAXIMUM_OBJECT_ALIGNMENT))
-	* 4) as usize;
+#[cfg(feature = "std")]
+std::thread_local! {
+	/// Releases the heaps of a thread when it exits, see [`ThreadExitGuard`].
+	static THREAD_EXIT_GUARD: ThreadExitGuard = const { ThreadExitGuard };
+}
 
-assertc_eq!(
-	(NUM_MEDIUM_OBJECT_BINS - 1) as u32,
-	powerlaw_bin_from_size(
-		(medium_objects::MAXIMUM_OBJECT_ALIGNMENT
-			+ medium_objects::MAXIMUM_OBJECT_ALIGNMENT / 2
-			+ medium_objects::MAXIMUM_OBJECT_ALIGNMENT / 4) as usize
-	) - powerlaw_bin_from_size((small_objects::MAXIMUM_OBJECT_ALIGNMENT * 2) as usize)
-);
-assertc_eq!(
-	(NUM_LARGE_OBJECT_BINS - 1) as u32,
-	powerlaw_bin_from_size(
-		(large_objects::MAXIMUM_OBJECT_ALIGNMENT
-			+ large_objects::MAXIMUM_OBJECT_ALIGNMENT / 2
-			+ large_objects::MAXIMUM_OBJECT_ALIGNMENT / 4) as usize
-	) - powerlaw_bin_from_size((medium_objects::MAXIMUM_OBJECT_ALIGNMENT * 2) as usize)
-);
+/// Releases the heaps of the exiting thread when dropped (see [`release_thread_heap`]).
+#[cfg(feature = "std")]
+struct ThreadExitGuard;
+
+#[cfg(feature = "std")]
+impl Drop for ThreadExitGuard {
+	fn drop(&mut self) {
+		// Destructors that run later on may still allocate, in which case they acquire a (possibly different) heap again.
+		unsafe { (*THREAD_HEAP_CACHE.get()).release_all() };
+		#[cfg(feature = "trace")]
+		crate::trace::flush();
+	}
+}
+
+/// Holds the heaps of a thread with the `std-tls` feature, and releases them when the thread exits (see
+/// [`release_thread_heap`]).
+#[cfg(feature = "std-tls")]
+struct ThreadHeapSlot(core::cell::UnsafeCell<ThreadHeapCache>);
+
+#[cfg(feature = "std-tls")]
+impl Drop for ThreadHeapSlot {
+	fn drop(&mut self) {
+		// Destructors that run later on may still allocate, see [`Emma::alloc_after_thread_exit`].
+		unsafe { self.0.get_mut().release_all() };
+		#[cfg(feature = "trace")]
+		crate::trace::flush();
+	}
+}
+
+/// Flushes a heap that the calling thread no longer holds on to: Objects that were freed by other threads are
+/// collected, objects that were freed on behalf of other threads are handed back, empty pages are trimmed, and the
+/// heap is unlocked, so that the next thread can take it over right away.
+///
+/// `thread_heap` is a [`Heap`] of the configuration `C`, whose type is erased so that heaps of all configurations can
+/// be released alike (see [`CachedHeap`]). See [`heap_manager::HeapManager::release_thread_heap`].
+#[cfg(thread_heaps)]
+unsafe fn release_thread_heap<C: Config>(thread_heap: NonNull<u8>) {
+	let thread_heap = thread_heap.cast::<Heap<C>>();
+	unsafe {
+		thread_heap
+			.as_ref()
+			.instance
+			.as_ref()
+			.heap_manager
+			.release_thread_heap(thread_heap)
+	};
+}
 
 /// Provides allocation and deallocation capabilities. The actual allocation/deallocation is dispatched, depending of
 /// the size of the allocation.
 ///
-/// - small objects are allocated using [`small_objects`]
-/// - medium objects are allocated using [`medium_objects`]
+/// - small and medium objects are allocated using [`paged_objects`]
 /// - large objects are allocated using [`large_objects`]
 /// - huge objects are allocated directly using [`crate::mmap`]
 #[derive(Debug)]
-struct Heap {
-	/// An id to identify this heap. The id is guaranteed to not be zero (which means that the heap id zero can be used
-	/// to indicate no heap).
-	#[cfg(feature = "tls")]
+struct Heap<C: Config> {
+	/// An id to identify this heap, which is its address (see [`reclaimed_pages`]). The id is guaranteed to not be zero
+	/// (which means that the heap id zero can be used to indicate no heap).
+	#[cfg(any(thread_heaps, feature = "sharded"))]
 	id: HeapId,
+	/// The instance that this heap belongs to, whose arena pools it shares with its other heaps.
+	instance: NonNull<Instance<C>>,
 	/// A singly-linked list of free pages suitable for small objects. The next page is accessed via
-	/// [`small_objects::Page::next_page`].
-	small_object_reserve: Option<NonNull<small_objects::Page>>,
+	/// [`Page::next_page`].
+	small_object_reserve: Option<NonNull<Page<Small<C>>>>,
 	/// Each element of this array contains a singly-linked list of pages suitable for allocation of small objects of one
-	/// specific size. The next page is accessed via [`small_objects::Page::next_page`].
-	small_object_pages: [Option<NonNull<small_objects::Page>>; NUM_SMALL_OBJECT_BINS],
+	/// specific size. The next page is accessed via [`Page::next_page`]. Only the first [`Tiers::SMALL_OBJECT_BINS`]
+	/// elements are used.
+	small_object_pages: [Option<NonNull<Page<Small<C>>>>; MAX_SMALL_OBJECT_BINS],
+	/// A singly-linked list of the pages for small objects that had no space left. The next page is accessed via
+	/// [`Page::next_full`].
+	small_object_full: Option<NonNull<Page<Small<C>>>>,
 	/// A singly-linked list of free pages suitable for medium objects. The next page is accessed via
-	/// [`medium_objects::Page::next_page`].
-	medium_object_reserve: Option<NonNull<medium_objects::Page>>,
+	/// [`Page::next_page`].
+	medium_object_reserve: Option<NonNull<Page<Medium<C>>>>,
 	/// Each element of this array contains a singly-linked list of pages suitable for allocation of medium objects of
-	/// one specific size. The next page is accessed via [`medium_objects::Page::next_page`].
-	medium_object_pages: [Option<NonNull<medium_objects::Page>>; NUM_MEDIUM_OBJECT_BINS],
+	/// one specific size. The next page is accessed via [`Page::next_page`]. Only the first
+	/// [`Tiers::MEDIUM_OBJECT_BINS`] elements are used.
+	medium_object_pages: [Option<NonNull<Page<Medium<C>>>>; MAX_MEDIUM_OBJECT_BINS],
+	/// A singly-linked list of the pages for medium objects that had no space left. The next page is accessed via
+	/// [`Page::next_full`].
+	medium_object_full: Option<NonNull<Page<Medium<C>>>>,
 	/// Each element of this array contains a singly-linked list of pages suitable for allocation of large objects of one
-	/// specific size. The next page is accessed via [`large_objects::Page::next_page`].
-	large_object_pages: [Option<NonNull<large_objects::Page>>; NUM_LARGE_OBJECT_BINS],
+	/// specific size. The next page is accessed via [`Page::next_page`]. Only the first [`Tiers::LARGE_OBJECT_BINS`]
+	/// elements are used.
+	large_object_pages: [Option<NonNull<Page<Large<C>>>>; MAX_LARGE_OBJECT_BINS],
+	/// A singly-linked list of the pages for large objects that had no space left. The next page is accessed via
+	/// [`Page::next_full`].
+	large_object_full: Option<NonNull<Page<Large<C>>>>,
+	/// For each tier, a stack of full pages that other threads have freed objects on, which are to be returned to their
+	/// bins (see [`reclaimed_pages`]).
+	#[cfg(any(thread_heaps, feature = "sharded"))]
+	reclaimed_pages: [AtomicUsize; 3],
+	/// Buffers the objects that this heap frees on behalf of other heaps.
+	#[cfg(any(thread_heaps, feature = "sharded"))]
+	remote_frees: RemoteFrees,
+	/// Counts the calls to [`Heap::trim`] that considered collapsing arenas, so that each arena is visited once per
+	/// call.
+	trim_epoch: u32,
+	/// Decides which allocations from this heap are recorded in the heap profile.
+	#[cfg(feature = "heap-profile")]
+	sampler: crate::heap_profile::Sampler,
 }
 
-#[cfg(feature = "tls")]
+#[cfg(any(thread_heaps, feature = "sharded"))]
 type HeapId = u64;
 
-#[cfg(feature = "tls")]
+#[cfg(any(thread_heaps, feature = "sharded"))]
 type AtomicHeapId = AtomicU64;
 
-unsafe impl Send for Heap {}
+unsafe impl<C: Config> Send for Heap<C> {}
 
-#[cfg(not(feature = "tls"))]
-impl Heap {
-	/// Creates a new heap
-	const fn new() -> Self {
+#[cfg(not(any(thread_heaps, feature = "sharded")))]
+impl<C: Config> Heap<C> {
+	/// Creates a new heap for `instance`
+	const fn new(instance: NonNull<Instance<C>>) -> Self {
 		Self {
+			instance,
 			small_object_reserve: None,
-			small_object_pages: [None; NUM_SMALL_OBJECT_BINS],
+			small_object_pages: [None; MAX_SMALL_OBJECT_BINS],
 			medium_object_reserve: None,
-			medium_object_pages: [None; NUM_MEDIUM_OBJECT_BINS],
-			large_object_pages: [None; NUM_LARGE_OBJECT_BINS],
+			medium_object_pages: [None; MAX_MEDIUM_OBJECT_BINS],
+			large_object_pages: [None; MAX_LARGE_OBJECT_BINS],
+			small_object_full: None,
+			medium_object_full: None,
+			large_object_full: None,
+			trim_epoch: 0,
+			#[cfg(feature = "heap-profile")]
+			sampler: crate::heap_profile::Sampler::new(),
 		}
 	}
 }
 
-#[cfg(feature = "tls")]
-impl Heap {
-	/// Creates a new heap with a unique non-zero id
-	fn new() -> Self {
-		use core::sync::atomic::AtomicU64;
-
-		// We use `0` as the id if we do not hold a heap, so we may not use it as a normal heap id.
-		static HEAP_IDS: AtomicU64 = AtomicU64::new(1);
-
+#[cfg(any(thread_heaps, feature = "sharded"))]
+impl<C: Config> Heap<C> {
+	/// Creates a new heap, whose id must be assigned via [`Heap::assign_id`] once it has been moved to its final location
+	fn new(instance: NonNull<Instance<C>>) -> Self {
 		Self {
-			id: HEAP_IDS.fetch_add(1, core::sync::atomic::Ordering::Relaxed),
+			id: 0,
+			instance,
 			small_object_reserve: None,
-			small_object_pages: [None; NUM_SMALL_OBJECT_BINS],
+			small_object_pages: [None; MAX_SMALL_OBJECT_BINS],
 			medium_object_reserve: None,
-			medium_object_pages: [None; NUM_MEDIUM_OBJECT_BINS],
-			large_object_pages: [None; NUM_LARGE_OBJECT_BINS],
+			medium_object_pages: [None; MAX_MEDIUM_OBJECT_BINS],
+			large_object_pages: [None; MAX_LARGE_OBJECT_BINS],
+			small_object_full: None,
+			medium_object_full: None,
+			large_object_full: None,
+			reclaimed_pages: [const { AtomicUsize::new(0) }; 3],
+			remote_frees: RemoteFrees::new(),
+			trim_epoch: 0,
+			#[cfg(feature = "heap-profile")]
+			sampler: crate::heap_profile::Sampler::new(),
+		}
+	}
+
+	/// Assigns the heap its id, which is its address.
+	fn assign_id(&mut self) {
+		self.id = self as *mut Self as HeapId;
+	}
+
+	/// Returns the pages that have been handed back to the heap `heap`, which is this heap or an orphan it adopted, to
+	/// the bins of this heap.
+	#[cold]
+	unsafe fn reclaim(&mut self, heap: HeapId) {
+		let take = |tier: Tier| unsafe { reclaimed_pages::<C>(heap, tier).swap(0, Ordering::Acquire) };
+		unsafe {
+			page::reclaim(
+				NonNull::new(take(Tier::Small) as *mut Page<Small<C>>),
+				&mut self.small_object_pages,
+				|object_size| object_size as usize / 8 - 1,
+				heap,
+				self.id,
+			);
+			page::reclaim(
+				NonNull::new(take(Tier::Medium) as *mut Page<Medium<C>>),
+				&mut self.medium_object_pages,
+				|object_size| (powerlaw_bin_from_size(object_size as usize) - C::FIRST_MEDIUM_BIN) as usize,
+				heap,
+				self.id,
+			);
+			page::reclaim(
+				NonNull::new(take(Tier::Large) as *mut Page<Large<C>>),
+				&mut self.large_object_pages,
+				|object_size| (powerlaw_bin_from_size(object_size as usize) - C::FIRST_LARGE_BIN) as usize,
+				heap,
+				self.id,
+			);
+		}
+	}
+}
+
+#[cfg(thread_heaps)]
+impl<C: Config> Heap<C> {
+	/// Takes over all pages of `orphan`, whose thread has exited, so that its partially used pages are reused instead of
+	/// only ever collecting frees from other threads. `orphan` is left without any pages.
+	unsafe fn adopt(&mut self, orphan: &mut Heap<C>) {
+		unsafe {
+			page::adopt(
+				&mut self.small_object_reserve,
+				&mut orphan.small_object_reserve,
+				self.id,
+			);
+			for (to, from) in self.small_object_pages[..C::SMALL_OBJECT_BINS]
+				.iter_mut()
+				.zip(orphan.small_object_pages.iter_mut())
+			{
+				page::adopt(to, from, self.id);
+			}
+			page::adopt(
+				&mut self.medium_object_reserve,
+				&mut orphan.medium_object_reserve,
+				self.id,
+			);
+			for (to, from) in self.medium_object_pages[..C::MEDIUM_OBJECT_BINS]
+				.iter_mut()
+				.zip(orphan.medium_object_pages.iter_mut())
+			{
+				page::adopt(to, from, self.id);
+			}
+			for (to, from) in self.large_object_pages[..C::LARGE_OBJECT_BINS]
+				.iter_mut()
+				.zip(orphan.large_object_pages.iter_mut())
+			{
+				page::adopt(to, from, self.id);
+			}
+			page::adopt_full(&mut self.small_object_full, &mut orphan.small_object_full, self.id);
+			page::adopt_full(&mut self.medium_object_full, &mut orphan.medium_object_full, self.id);
+			page::adopt_full(&mut self.large_object_full, &mut orphan.large_object_full, self.id);
+			// Pages that are handed back to the orphan after this are passed on by whichever thread takes the orphan over.
+			self.reclaim(orphan.id);
+			orphan.remote_frees.flush();
+		}
+	}
+
+	/// Adopts the pages of one heap of the same instance whose thread has exited, if there is any (see
+	/// [`heap_manager::HeapManager::take_orphan`]).
+	#[cold]
+	unsafe fn adopt_orphan(&mut self, scan: bool) {
+		unsafe {
+			let heap_manager = &self.instance.as_ref().heap_manager;
+			if let Some(mut orphan) = heap_manager.take_orphan(scan, NonNull::from(&mut *self)) {
+				self.adopt(orphan.as_mut());
+				heap_manager.return_orphan(orphan);
+			}
 		}
 	}
 }
@@ -232,6 +798,21 @@ assertc_eq!(powerlaw_bin_from_size(0b1101000), 19u32);
 assertc_eq!(powerlaw_bin_from_size(0b1111000), 20u32);
 assertc_eq!(powerlaw_bin_from_size(0b10010000), 21u32);
 
+/// The (largest) object size of a bin, i.e., the inverse of [`powerlaw_bin_from_size`] for rounded up sizes.
+#[inline]
+const fn powerlaw_size_from_bin(bin: u32) -> usize {
+	((bin as usize % 4) + 4) << (bin / 4)
+}
+
+assertc_eq!(powerlaw_size_from_bin(0), 0b100usize);
+assertc_eq!(powerlaw_size_from_bin(5), 0b1010usize);
+assertc_eq!(powerlaw_size_from_bin(15), 0b111000usize);
+assertc_eq!(powerlaw_size_from_bin(21), 0b10100000usize);
+assertc_eq!(
+	powerlaw_size_from_bin(powerlaw_bin_from_size(0b1011000)),
+	0b1100000usize
+);
+
 #[inline]
 const fn powerlaw_bins_round_up_size(size: NonZero<usize>) -> NonZero<usize> {
 	debug_assert!(size.get() >= 8);
@@ -286,28 +867,134 @@ assertc_eq!(
 );
 assertc_eq!(powerlaw_bins_round_up_size(const_non_zero_usize(4080)).get(), 4096usize);
 
-impl Heap {
+/// The tiers that objects are sorted into by their size.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[repr(u8)]
+pub enum Tier {
+	Small = 0,
+	Medium = 1,
+	Large = 2,
+	Huge = 3,
+}
+
+impl Tier {
+	/// Returns the tier that serves objects of the given (padded) size.
+	#[allow(dead_code)]
+	fn of<C: Config>(size: NonZero<usize>) -> Tier {
+		if size.get().div_ceil(8) <= C::SMALL_OBJECT_BINS {
+			Tier::Small
+		} else {
+			let bin = powerlaw_bin_from_size(size.get());
+			if bin <= C::LAST_MEDIUM_BIN {
+				Tier::Medium
+			} else if bin <= C::LAST_LARGE_BIN {
+				Tier::Large
+			} else {
+				Tier::Huge
+			}
+		}
+	}
+}
+
+impl<C: Config> Heap<C> {
+	/// Forgets all pages of this heap, whose arenas are about to be unmapped (see [`Instance::reset`]). Objects that this
+	/// heap buffered on behalf of other heaps are dropped, too, as they belong to the same instance.
+	fn reset(&mut self) {
+		self.small_object_reserve = None;
+		self.small_object_pages = [None; MAX_SMALL_OBJECT_BINS];
+		self.small_object_full = None;
+		self.medium_object_reserve = None;
+		self.medium_object_pages = [None; MAX_MEDIUM_OBJECT_BINS];
+		self.medium_object_full = None;
+		self.large_object_pages = [None; MAX_LARGE_OBJECT_BINS];
+		self.large_object_full = None;
+		#[cfg(any(thread_heaps, feature = "sharded"))]
+		{
+			for reclaimed in self.reclaimed_pages.iter() {
+				reclaimed.store(0, Ordering::Relaxed);
+			}
+			self.remote_frees = RemoteFrees::new();
+		}
+	}
+
+	unsafe fn trim(&mut self) {
+		// Objects of this heap may be among the buffered ones, once it has adopted their pages.
+		#[cfg(any(thread_heaps, feature = "sharded"))]
+		unsafe {
+			self.remote_frees.flush();
+			self.reclaim(self.id);
+		}
+		unsafe {
+			page::sweep_full(&mut self.small_object_full);
+			page::sweep_full(&mut self.medium_object_full);
+			page::sweep_full(&mut self.large_object_full);
+		}
+		for (i, bin) in self.small_object_pages[..C::SMALL_OBJECT_BINS].iter_mut().enumerate() {
+			unsafe { paged_objects::trim(bin, &mut self.small_object_reserve, ((i + 1) * 8) as u32) };
+		}
+		for (i, bin) in self.medium_object_pages[..C::MEDIUM_OBJECT_BINS].iter_mut().enumerate() {
+			let object_size = powerlaw_size_from_bin(i as u32 + C::FIRST_MEDIUM_BIN);
+			unsafe { paged_objects::trim(bin, &mut self.medium_object_reserve, object_size as u32) };
+		}
+		for (i, bin) in self.large_object_pages[..C::LARGE_OBJECT_BINS].iter_mut().enumerate() {
+			let object_size = powerlaw_size_from_bin(i as u32 + C::FIRST_LARGE_BIN);
+			unsafe { large_objects::trim(bin, object_size as u32) };
+		}
+		unsafe {
+			let instance = self.instance.as_ref();
+			paged_objects::donate(&mut self.small_object_reserve, &instance.small_object_pool);
+			paged_objects::donate(&mut self.medium_object_reserve, &instance.medium_object_pool);
+		}
+
+		if huge_pages::collapse_enabled() {
+			self.trim_epoch = self.trim_epoch.wrapping_add(1);
+			for bin in self.small_object_pages[..C::SMALL_OBJECT_BINS].iter() {
+				unsafe { paged_objects::collapse(bin, self.trim_epoch) };
+			}
+			for bin in self.medium_object_pages[..C::MEDIUM_OBJECT_BINS].iter() {
+				unsafe { paged_objects::collapse(bin, self.trim_epoch) };
+			}
+			unsafe {
+				paged_objects::collapse_full(&self.small_object_full, self.trim_epoch);
+				paged_objects::collapse_full(&self.medium_object_full, self.trim_epoch);
+			}
+		}
+	}
+
 	unsafe fn alloc(&mut self, size: NonZero<usize>, alignment: NonZero<usize>) -> *mut u8 {
+		#[cfg(any(thread_heaps, feature = "sharded"))]
+		if self
+			.reclaimed_pages
+			.iter()
+			.any(|reclaimed| reclaimed.load(Ordering::Relaxed) != 0)
+		{
+			unsafe { self.reclaim(self.id) };
+		}
+
+		let instance = unsafe { self.instance.as_ref() };
 		let bin = size.get().div_ceil(8);
 		debug_assert!(bin > 0);
-		if bin <= self.small_object_pages.len() {
+		if bin <= C::SMALL_OBJECT_BINS {
+			// A new arena is only mapped once the reserve is empty, so adopting the pages of an orphan may avoid that.
+			#[cfg(thread_heaps)]
+			if self.small_object_reserve.is_none() && instance.heap_manager.has_orphans() {
+				unsafe { self.adopt_orphan(false) };
+			}
 			unsafe {
-				small_objects::alloc(
+				paged_objects::alloc(
 					&mut self.small_object_pages[bin - 1],
+					&mut self.small_object_full,
 					&mut self.small_object_reserve,
 					(bin * 8) as u32,
-					#[cfg(feature = "tls")]
+					&instance.small_object_pool,
+					instance.id(),
+					#[cfg(any(thread_heaps, feature = "sharded"))]
 					self.id,
 				)
 			}
 		} else {
 			let bin = powerlaw_bin_from_size(size.get());
-			if bin
-				<= powerlaw_bin_from_size(
-					(medium_objects::MAXIMUM_OBJECT_ALIGNMENT
-						+ medium_objects::MAXIMUM_OBJECT_ALIGNMENT / 2
-						+ medium_objects::MAXIMUM_OBJECT_ALIGNMENT / 4) as usize,
-				) {
+			if bin <= C::LAST_MEDIUM_BIN {
 				if (powerlaw_bins_round_up_size(size).get() as u32 as usize) < size.get() {
 					panic!("{}|{}", powerlaw_bins_round_up_size(size).get() as u32, size.get());
 				}
@@ -320,48 +1007,71 @@ impl Heap {
 						bin
 					);
 				}
+				#[cfg(thread_heaps)]
+				if self.medium_object_reserve.is_none() && instance.heap_manager.has_orphans() {
+					unsafe { self.adopt_orphan(false) };
+				}
 				unsafe {
-					medium_objects::alloc(
-						&mut self.medium_object_pages
-							[(bin - powerlaw_bin_from_size((small_objects::MAXIMUM_OBJECT_ALIGNMENT * 2) as usize)) as usize],
+					paged_objects::alloc(
+						&mut self.medium_object_pages[(bin - C::FIRST_MEDIUM_BIN) as usize],
+						&mut self.medium_object_full,
 						&mut self.medium_object_reserve,
 						powerlaw_bins_round_up_size(size).get() as u32,
-						#[cfg(feature = "tls")]
+						&instance.medium_object_pool,
+						instance.id(),
+						#[cfg(any(thread_heaps, feature = "sharded"))]
 						self.id,
 					)
 				}
-			} else if bin
-				<= powerlaw_bin_from_size(
-					(large_objects::MAXIMUM_OBJECT_ALIGNMENT
-						+ large_objects::MAXIMUM_OBJECT_ALIGNMENT / 2
-						+ large_objects::MAXIMUM_OBJECT_ALIGNMENT / 4) as usize,
-				) {
+			} else if bin <= C::LAST_LARGE_BIN {
 				debug_assert!(powerlaw_bins_round_up_size(size).get() as u32 as usize >= size.get());
 				debug_assert_eq!(
 					bin,
 					powerlaw_bin_from_size(powerlaw_bins_round_up_size(size).get() as u32 as usize)
 				);
+				#[cfg(thread_heaps)]
+				if instance.heap_manager.has_orphans() {
+					unsafe { self.adopt_orphan(false) };
+				}
 				unsafe {
 					large_objects::alloc(
-						&mut self.large_object_pages
-							[(bin - powerlaw_bin_from_size((medium_objects::MAXIMUM_OBJECT_ALIGNMENT * 2) as usize)) as usize],
+						&mut self.large_object_pages[(bin - C::FIRST_LARGE_BIN) as usize],
+						&mut self.large_object_full,
 						powerlaw_bins_round_up_size(size).get() as u32,
-						#[cfg(feature = "tls")]
+						instance.id(),
+						#[cfg(any(thread_heaps, feature = "sharded"))]
 						self.id,
 					)
 				}
 			} else {
 				let size = (size.get() + 4095) & !4095;
-				unsafe { alloc_aligned(NonZero::new(size).unwrap(), alignment, 3) }
-					.map(|ptr| ptr.as_ptr().cast())
-					.unwrap_or(ptr::null_mut())
+				if let Some(ptr) = huge_cache::take(NonZero::new(size).unwrap(), alignment, instance.id()) {
+					return ptr.as_ptr().cast();
+				}
+				unsafe {
+					registry::map::<C>(
+						NonZero::new(size).unwrap(),
+						alignment,
+						Tier::Huge,
+						c"emma:huge",
+						instance.id(),
+					)
+				}
+				.map(|(ptr, hugetlb)| {
+					if !hugetlb {
+						unsafe { huge_pages::advise_new_mapping(ptr, size, Tier::Huge) };
+					}
+					ptr.as_ptr().cast()
+				})
+				.unwrap_or(ptr::null_mut())
 			}
 		}
 	}
 
 	unsafe fn dealloc(
-		#[cfg(not(feature = "tls"))] &mut self,
-		#[cfg(feature = "tls")] id: HeapId,
+		#[cfg(not(any(thread_heaps, feature = "sharded")))] &mut self,
+		#[cfg(any(thread_heaps, feature = "sharded"))] id: HeapId,
+		#[cfg(any(thread_heaps, feature = "sharded"))] remote_frees: Option<&mut RemoteFrees>,
 		ptr: *mut u8,
 		size: NonZero<usize>,
 		_alignment: NonZero<usize>,
@@ -369,48 +1079,64 @@ impl Heap {
 		unsafe {
 			let bin = size.get().div_ceil(8);
 			debug_assert!(bin > 0);
-			if bin <= NUM_SMALL_OBJECT_BINS {
+			if bin <= C::SMALL_OBJECT_BINS {
 				debug_assert!(!ptr.is_null());
-				small_objects::Page::dealloc(
-					#[cfg(feature = "tls")]
-					id,
-					NonNull::new_unchecked(ptr),
-				);
+				#[cfg(not(any(thread_heaps, feature = "sharded")))]
+				if let Some(page) = paged_objects::dealloc::<Small<C>>(NonNull::new_unchecked(ptr)) {
+					page::return_to_bin(&mut self.small_object_pages[bin - 1], page);
+				}
+				#[cfg(any(thread_heaps, feature = "sharded"))]
+				paged_objects::dealloc::<Small<C>>(id, remote_frees, NonNull::new_unchecked(ptr));
 			} else {
 				let bin = powerlaw_bin_from_size(size.get());
-				if bin
-					<= powerlaw_bin_from_size(
-						(medium_objects::MAXIMUM_OBJECT_ALIGNMENT
-							+ medium_objects::MAXIMUM_OBJECT_ALIGNMENT / 2
-							+ medium_objects::MAXIMUM_OBJECT_ALIGNMENT / 4) as usize,
-					) {
-					medium_objects::Page::dealloc(
-						#[cfg(feature = "tls")]
-						id,
+				if bin <= C::LAST_MEDIUM_BIN {
+					#[cfg(not(any(thread_heaps, feature = "sharded")))]
+					if let Some(page) = paged_objects::dealloc::<Medium<C>>(NonNull::new_unchecked(ptr)) {
+						page::return_to_bin(
+							&mut self.medium_object_pages[(bin - C::FIRST_MEDIUM_BIN) as usize],
+							page,
+						);
+					}
+					#[cfg(any(thread_heaps, feature = "sharded"))]
+					paged_objects::dealloc::<Medium<C>>(id, remote_frees, NonNull::new_unchecked(ptr));
+				} else if bin <= C::LAST_LARGE_BIN {
+					#[cfg(not(any(thread_heaps, feature = "sharded")))]
+					if let Some(page) = large_objects::dealloc::<C>(
 						NonNull::new_unchecked(ptr),
-					);
-				} else if bin
-					<= powerlaw_bin_from_size(
-						(large_objects::MAXIMUM_OBJECT_ALIGNMENT
-							+ large_objects::MAXIMUM_OBJECT_ALIGNMENT / 2
-							+ large_objects::MAXIMUM_OBJECT_ALIGNMENT / 4) as usize,
+						powerlaw_bins_round_up_size(size).get() as u32,
 					) {
-					large_objects::Page::dealloc(
-						#[cfg(feature = "tls")]
+						// Objects that were grown in place are larger than the slots of their page, which determine its bin.
+						let bin = powerlaw_bin_from_size(large_objects::slot_size(page) as usize);
+						page::return_to_bin(&mut self.large_object_pages[(bin - C::FIRST_LARGE_BIN) as usize], page);
+					}
+					#[cfg(any(thread_heaps, feature = "sharded"))]
+					large_objects::dealloc::<C>(
 						id,
+						remote_frees,
 						NonNull::new_unchecked(ptr),
+						powerlaw_bins_round_up_size(size).get() as u32,
 					);
 				} else {
-					let size = (size.get() + 4095) & !4095;
-					munmap(NonNull::new(ptr.cast()).unwrap(), NonZero::new(size).unwrap()).unwrap();
+					// The registry knows the actual size of the mapping, which may be larger for hugetlb mappings.
+					// An unregistered object was not allocated by emma, or freed twice, which we cannot recover from but need not
+					// crash on either.
+					let address = NonNull::new_unchecked(ptr.cast());
+					let Some((size, hugetlb, owner)) = registry::mapping(address) else {
+						debug_assert!(false, "freeing an unregistered huge object");
+						return;
+					};
+					if hugetlb || !huge_cache::put(address, size, owner) {
+						registry::unmap(address);
+					}
 				}
 			}
 		}
 	}
 }
 
-unsafe impl alloc::alloc::GlobalAlloc for Emma {
-	unsafe fn alloc(&self, layout: core::alloc::Layout) -> *mut u8 {
+impl<C: Config> Emma<C> {
+	#[inline(always)]
+	unsafe fn alloc_impl(&self, layout: core::alloc::Layout) -> *mut u8 {
 		#[cfg(any(feature = "boundary-checks", debug_assertions))]
 		{
 			debug_assert!(layout.size() > 0);
@@ -419,14 +1145,58 @@ unsafe impl alloc::alloc::GlobalAlloc for Emma {
 
 		let layout = layout.pad_to_align();
 
-		#[cfg(not(feature = "tls"))]
+		#[cfg(not(any(thread_heaps, feature = "sharded")))]
 		unsafe {
-			self.heap.lock().alloc(
+			let Some(instance) = self.instance() else {
+				return ptr::null_mut();
+			};
+			let mut heap = instance.heap.lock();
+			let ret = heap.alloc(
 				NonZero::new(layout.size()).unwrap(),
 				NonZero::new(layout.align()).unwrap(),
-			)
+			);
+			#[cfg(feature = "heap-profile")]
+			if let Some(ret) = NonNull::new(ret)
+				&& heap.sampler.should_sample(layout.size())
+			{
+				crate::heap_profile::record_alloc(ret, layout.size());
+			}
+			ret
 		}
-		#[cfg(feature = "tls")]
+		#[cfg(feature = "sharded")]
+		if let Some(instance) = self.instance()
+			&& let Some(mut heap) = {
+				#[cfg(feature = "percpu")]
+				if let Some(bin) = shards::Shards::<C>::cached_bin(layout.size())
+					&& let Some(object) = instance.shards.take_cached(bin)
+				{
+					return object.as_ptr();
+				}
+				instance.shards.lock(NonNull::from(instance))
+			} {
+			let ret = unsafe {
+				heap.alloc(
+					NonZero::new(layout.size()).unwrap(),
+					NonZero::new(layout.align()).unwrap(),
+				)
+			};
+			#[cfg(feature = "percpu")]
+			if !ret.is_null()
+				&& let Some(bin) = shards::Shards::<C>::cached_bin(layout.size())
+			{
+				unsafe { instance.shards.refill(&mut heap, bin) };
+			}
+			#[cfg(feature = "heap-profile")]
+			if let Some(ret) = NonNull::new(ret)
+				&& heap.sampler.should_sample(layout.size())
+			{
+				crate::heap_profile::record_alloc(ret, layout.size());
+			}
+			ret
+		} else {
+			ptr::null_mut()
+		}
+		#[cfg(thread_heaps)]
 		if let Some(mut thread_heap) = self.thread_heap() {
 			let ret = unsafe {
 				thread_heap.as_mut().alloc(
@@ -438,13 +1208,28 @@ unsafe impl alloc::alloc::GlobalAlloc for Emma {
 				ret.is_null() || ret as usize > 4096,
 				"We should return a proper null-pointer"
 			);
+			#[cfg(feature = "heap-profile")]
+			if let Some(ret) = NonNull::new(ret)
+				&& unsafe { thread_heap.as_mut().sampler.should_sample(layout.size()) }
+			{
+				crate::heap_profile::record_alloc(ret, layout.size());
+			}
 			ret
 		} else {
+			#[cfg(feature = "std-tls")]
+			return unsafe {
+				self.alloc_after_thread_exit(
+					NonZero::new(layout.size()).unwrap(),
+					NonZero::new(layout.align()).unwrap(),
+				)
+			};
+			#[cfg(feature = "tls")]
 			ptr::null_mut()
 		}
 	}
 
-	unsafe fn realloc(&self, ptr: *mut u8, layout: core::alloc::Layout, new_size: usize) -> *mut u8 {
+	#[inline(always)]
+	unsafe fn realloc_impl(&self, ptr: *mut u8, layout: core::alloc::Layout, new_size: usize) -> *mut u8 {
 		#[cfg(any(feature = "boundary-checks", debug_assertions))]
 		{
 			assert_ne!(
@@ -465,78 +1250,104 @@ unsafe impl alloc::alloc::GlobalAlloc for Emma {
 		let layout = layout.pad_to_align();
 		let new_layout = unsafe { Layout::from_size_align_unchecked(new_size, layout.align()).pad_to_align() };
 
-		if layout.size() / 8 < NUM_SMALL_OBJECT_BINS {
+		if layout.size() / 8 < C::SMALL_OBJECT_BINS {
 			if layout.size() / 8 == new_layout.size() / 8 {
 				return ptr;
 			}
 		} else {
 			let old_bin = powerlaw_bin_from_size(layout.size());
-			if old_bin
-				<= powerlaw_bin_from_size(
-					(large_objects::MAXIMUM_OBJECT_ALIGNMENT
-						+ large_objects::MAXIMUM_OBJECT_ALIGNMENT / 2
-						+ large_objects::MAXIMUM_OBJECT_ALIGNMENT / 4) as usize,
-				) {
+			if old_bin <= C::LAST_LARGE_BIN {
 				let new_bin = powerlaw_bin_from_size(new_layout.size());
 				if old_bin == new_bin {
 					return ptr;
 				}
-			} else if new_layout.size()
-				> (large_objects::MAXIMUM_OBJECT_ALIGNMENT
-					+ large_objects::MAXIMUM_OBJECT_ALIGNMENT / 2
-					+ large_objects::MAXIMUM_OBJECT_ALIGNMENT / 4) as usize
-			{
-				debug_assert!(
-					powerlaw_bin_from_size(new_layout.size())
-						> powerlaw_bin_from_size(
-							(large_objects::MAXIMUM_OBJECT_ALIGNMENT
-								+ large_objects::MAXIMUM_OBJECT_ALIGNMENT / 2
-								+ large_objects::MAXIMUM_OBJECT_ALIGNMENT / 4) as usize,
-						)
-				);
 
-				let old_size = (layout.size() + 4095) & !4095;
-				let new_size = (new_layout.size() + 4095) & !4095;
-				match old_size.cmp(&new_size) {
-					core::cmp::Ordering::Less => {
-						if unsafe {
-							crate::mmap::mremap_resize(
-								NonNull::new_unchecked(ptr).cast(),
-								NonZero::new_unchecked(old_size),
-								NonZero::new_unchecked(new_size),
-							)
-							.is_ok()
-						} {
+				let max_medium_bin = C::LAST_MEDIUM_BIN;
+				let max_large_bin = C::LAST_LARGE_BIN;
+				if old_bin > max_medium_bin
+					&& old_bin < new_bin
+					&& new_bin <= max_large_bin
+					&& unsafe { self.grow_large_in_place(ptr, layout.size(), new_layout.size()) }
+				{
+					// A sampled object is freed and allocated again as far as the profile is concerned, which updates its size.
+					#[cfg(feature = "heap-profile")]
+					if crate::heap_profile::record_dealloc(ptr) {
+						crate::heap_profile::record_alloc(unsafe { NonNull::new_unchecked(ptr) }, new_layout.size());
+					}
+					return ptr;
+				}
+			} else if new_layout.size() > C::MAXIMUM_LARGE_OBJECT_SIZE {
+				debug_assert!(powerlaw_bin_from_size(new_layout.size()) > C::LAST_LARGE_BIN);
+
+				if let Some((old_size, hugetlb, _)) = registry::mapping(unsafe { NonNull::new_unchecked(ptr).cast() }) {
+					let old_size = old_size.get();
+					let new_size = (new_layout.size() + 4095) & !4095;
+					if hugetlb {
+						// hugetlb mappings can only be resized in multiples of their page size, so they keep their size instead
+						if new_size <= old_size {
 							return ptr;
 						}
-					}
-					core::cmp::Ordering::Equal => return ptr,
-					core::cmp::Ordering::Greater => {
-						unsafe {
-							crate::mmap::mremap_resize(
-								NonNull::new_unchecked(ptr).cast(),
-								NonZero::new_unchecked(old_size),
-								NonZero::new_unchecked(new_size),
-							)
-							.unwrap()
-						};
+					} else if new_size == old_size {
 						return ptr;
+					} else if let Some(new_ptr) = unsafe {
+						registry::remap::<C>(
+							NonNull::new_unchecked(ptr).cast(),
+							NonZero::new_unchecked(new_size),
+							NonZero::new_unchecked(layout.align()),
+						)
+					} {
+						// Moving the mapping transfers its page tables, which is much cheaper than copying its contents. A sampled
+						// object is freed and allocated again as far as the profile is concerned.
+						#[cfg(feature = "heap-profile")]
+						if crate::heap_profile::record_dealloc(ptr) {
+							crate::heap_profile::record_alloc(new_ptr.cast(), new_layout.size());
+						}
+						return new_ptr.as_ptr().cast();
 					}
 				}
 			}
 		}
 
-		let new_ptr = unsafe { self.alloc(new_layout) };
+		let new_ptr = unsafe { self.alloc_impl(new_layout) };
 		if !new_ptr.is_null() {
 			unsafe {
 				ptr::copy_nonoverlapping(ptr, new_ptr, core::cmp::min(layout.size(), new_size));
-				self.dealloc(ptr, layout);
+				self.dealloc_impl(ptr, layout);
 			}
 		}
 		new_ptr
 	}
 
-	unsafe fn dealloc(&self, ptr: *mut u8, layout: core::alloc::Layout) {
+	/// Tries to grow a large object without moving it, which requires the calling thread to own its arena.
+	unsafe fn grow_large_in_place(&self, ptr: *mut u8, old_size: usize, new_size: usize) -> bool {
+		let old_size = powerlaw_bins_round_up_size(unsafe { NonZero::new_unchecked(old_size) }).get() as u32;
+		let new_size = powerlaw_bins_round_up_size(unsafe { NonZero::new_unchecked(new_size) }).get() as u32;
+		let ptr = unsafe { NonNull::new_unchecked(ptr) };
+
+		// The object was allocated from this instance, so it has been mapped.
+		let instance = unsafe { self.mapped_instance().unwrap_unchecked() };
+		#[cfg(not(any(thread_heaps, feature = "sharded")))]
+		{
+			// The arena is only modified while the heap is locked.
+			let _heap = instance.heap.lock();
+			unsafe { large_objects::grow_in_place::<C>(ptr, old_size, new_size) }
+		}
+		#[cfg(feature = "sharded")]
+		unsafe {
+			// The arena is only modified by its owner, which needs to be locked.
+			instance.shards.lock(NonNull::from(instance)).is_some_and(|heap| {
+				Page::<Large<C>>::is_owned_by(heap.id, ptr) && large_objects::grow_in_place::<C>(ptr, old_size, new_size)
+			})
+		}
+		#[cfg(thread_heaps)]
+		unsafe {
+			current_thread_heap(instance).is_some_and(|heap| Page::<Large<C>>::is_owned_by(heap.as_ref().id, ptr))
+				&& large_objects::grow_in_place::<C>(ptr, old_size, new_size)
+		}
+	}
+
+	#[inline(always)]
+	unsafe fn dealloc_impl(&self, ptr: *mut u8, layout: core::alloc::Layout) {
 		#[cfg(any(feature = "boundary-checks", debug_assertions))]
 		{
 			assert_ne!(
@@ -554,21 +1365,60 @@ unsafe impl alloc::alloc::GlobalAlloc for Emma {
 
 		let layout = layout.pad_to_align();
 
-		#[cfg(not(feature = "tls"))]
+		#[cfg(feature = "heap-profile")]
+		crate::heap_profile::record_dealloc(ptr);
+
+		// The object was allocated from this instance, so it has been mapped.
+		let instance = unsafe { self.mapped_instance().unwrap_unchecked() };
+		#[cfg(not(any(thread_heaps, feature = "sharded")))]
 		unsafe {
-			self.heap.lock().dealloc(
+			instance.heap.lock().dealloc(
 				ptr,
 				NonZero::new(layout.size()).unwrap(),
 				NonZero::new(layout.align()).unwrap(),
 			)
 		}
-		#[cfg(feature = "tls")]
+		#[cfg(feature = "sharded")]
+		unsafe {
+			#[cfg(feature = "percpu")]
+			let cached_bin = shards::Shards::<C>::cached_bin(layout.size());
+			#[cfg(feature = "percpu")]
+			if let Some(bin) = cached_bin
+				&& instance.shards.put_cached(bin, NonNull::new_unchecked(ptr))
+			{
+				return;
+			}
+
+			let mut heap = instance.shards.lock(NonNull::from(instance));
+			let (id, remote_frees) = match heap.as_deref_mut() {
+				Some(heap) => (heap.id, Some(&mut heap.remote_frees)),
+				// As with a thread that does not hold a heap (see below), all objects are freed as foreign ones.
+				None => (0, None),
+			};
+			Heap::<C>::dealloc(
+				id,
+				remote_frees,
+				ptr,
+				NonZero::new(layout.size()).unwrap(),
+				NonZero::new(layout.align()).unwrap(),
+			);
+			// The cache of the current CPU is full, so half of it is freed along with the object.
+			#[cfg(feature = "percpu")]
+			if let Some(bin) = cached_bin
+				&& let Some(heap) = heap.as_deref_mut()
+			{
+				instance.shards.drain(heap, bin, shards::CACHE_BATCH);
+			}
+		}
+		#[cfg(thread_heaps)]
 		unsafe {
-			Heap::dealloc(
+			let thread_heap = current_thread_heap(instance);
+			Heap::<C>::dealloc(
 				// If we do not currently hold a heap, we can just use the NULL id that no allocated page should use.
 				// This will end up using the foreign deallocation scheme - but as this thread does not have a heap, it could
 				// not have allocated the object in the first place...
-				THREAD_HEAP.map(|h| h.as_ref().id).unwrap_or(0),
+				thread_heap.map(|h| h.as_ref().id).unwrap_or(0),
+				thread_heap.map(|mut h| &mut h.as_mut().remote_frees),
 				ptr,
 				NonZero::new(layout.size()).unwrap(),
 				NonZero::new(layout.align()).unwrap(),
@@ -577,6 +1427,61 @@ unsafe impl alloc::alloc::GlobalAlloc for Emma {
 	}
 }
 
+impl<C: Config> Drop for Emma<C> {
+	/// Releases all memory of the instance (see [`Emma::reset`]), which is then handed to the next [`Emma`] that needs
+	/// one. Objects allocated from the instance must no longer be used afterwards.
+	fn drop(&mut self) {
+		if let Some(instance) = NonNull::new(*self.instance.get_mut()) {
+			unsafe { Instance::recycle(instance) };
+		}
+	}
+}
+
+unsafe impl<C: Config> alloc::alloc::GlobalAlloc for Emma<C> {
+	unsafe fn alloc(&self, layout: core::alloc::Layout) -> *mut u8 {
+		let ret = unsafe { self.alloc_impl(layout) };
+		#[cfg(feature = "trace")]
+		self.record_trace(
+			crate::trace::timestamp(),
+			crate::trace::Kind::Alloc,
+			ret,
+			ptr::null_mut(),
+			layout.size(),
+			layout.align(),
+		);
+		ret
+	}
+
+	unsafe fn realloc(&self, ptr: *mut u8, layout: core::alloc::Layout, new_size: usize) -> *mut u8 {
+		#[cfg(feature = "trace")]
+		let timestamp = crate::trace::timestamp();
+		let ret = unsafe { self.realloc_impl(ptr, layout, new_size) };
+		#[cfg(feature = "trace")]
+		self.record_trace(
+			timestamp,
+			crate::trace::Kind::Realloc,
+			ptr,
+			ret,
+			new_size,
+			layout.align(),
+		);
+		ret
+	}
+
+	unsafe fn dealloc(&self, ptr: *mut u8, layout: core::alloc::Layout) {
+		#[cfg(feature = "trace")]
+		self.record_trace(
+			crate::trace::timestamp(),
+			crate::trace::Kind::Dealloc,
+			ptr,
+			ptr::null_mut(),
+			layout.size(),
+			layout.align(),
+		);
+		unsafe { self.dealloc_impl(ptr, layout) }
+	}
+}
+
 #[cfg(test)]
 mod test {
 	use core::alloc::GlobalAlloc;
@@ -599,6 +1504,6 @@ mod test {
 		}
 		unsafe { emma.dealloc(p, layout) };
 
-		// unsafe { emma.reset() };
+		unsafe { emma.reset() };
 	}
 }
@@ -0,0 +1,449 @@
+use core::num::NonZero;
+use core::ptr::{self, NonNull};
+use core::sync::atomic::{AtomicIsize, AtomicPtr, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+
+use syscalls::Errno;
+
+use super::config::{Config, DefaultConfig};
+use crate::mmap::{alloc_aligned, munmap};
+use crate::sys;
+
+/// The registration area for restartable sequences, as defined by the kernel ABI (`struct rseq`). The kernel keeps the
+/// id of the CPU that its thread runs on up to date in `cpu_id`, and aborts the critical section that `rseq_cs` points
+/// to if the thread is preempted, migrated or interrupted by a signal while executing it.
+#[derive(Debug)]
+#[repr(C, align(32))]
+struct KernelRseq {
+	cpu_id_start: u32,
+	cpu_id: AtomicU32,
+	rseq_cs: AtomicU64,
+	flags: u32,
+}
+
+/// An area of our own, for threads whose libc did not register one (see [`libc_rseq_area`]). Such areas are only used
+/// to look up the CPU, as they may still be registered for an exited thread (see [`revalidate_rseq`]), in which case
+/// the kernel would not abort critical sections of the thread that uses them. Areas are aligned to cache lines so that
+/// updates for one thread do not disturb another, which leaves room for fields of our own behind the [`RSEQ_LEN`] bytes
+/// that the kernel knows about.
+#[derive(Debug)]
+#[repr(C, align(64))]
+struct Rseq {
+	kernel: KernelRseq,
+	/// Counts the lookups of the area by its thread, see [`REVALIDATION_INTERVAL`].
+	lookups: AtomicU32,
+}
+
+/// The size of the original `struct rseq`, which every kernel that supports rseq accepts.
+const RSEQ_LEN: u32 = 32;
+/// The signature that precedes abort handlers, which must match the one that the area was registered with. This is
+/// glibc's, as critical sections only use the areas that glibc registered.
+const RSEQ_SIG: u32 = 0x5305_3053;
+/// The CPU id of an area that has not been updated by the kernel since it was registered.
+const RSEQ_CPU_ID_UNINITIALIZED: u32 = -1i32 as u32;
+/// The CPU id of an area that could not be registered, e.g., because the thread already registered another area that
+/// could not be found (see [`libc_rseq_area`]) or because the kernel does not support rseq. Such threads fall back to
+/// `getcpu`, which the vDSO answers without a system call on most architectures.
+const RSEQ_CPU_ID_REGISTRATION_FAILED: u32 = -2i32 as u32;
+/// The number of lookups after which a thread registers its area again. An area that a thread takes over from an exited
+/// thread with the same thread pointer is still registered for the exited thread, so its CPU id is stale until the
+/// area is registered again, which this bounds without a system call per lookup.
+const REVALIDATION_INTERVAL: u32 = 1024;
+
+/// The number of threads whose rseq areas can be found. Threads beyond that fall back to `getcpu`.
+const RSEQ_SLOTS: usize = 4096;
+/// The number of slots that are probed to find the area of a thread.
+const RSEQ_PROBES: usize = 8;
+
+/// The rseq areas of all threads, each of which belongs to the thread whose thread pointer is stored in the same slot
+/// of `threads`. Slots are never released, as there is no cheap way to learn that a thread has exited; a new thread
+/// with the same thread pointer takes the area over (see [`revalidate_rseq`]).
+struct RseqTable {
+	threads: [AtomicUsize; RSEQ_SLOTS],
+	areas: [Rseq; RSEQ_SLOTS],
+}
+
+/// Marks that rseq is not used in this process, because there is no thread pointer or the table could not be mapped.
+const RSEQ_UNAVAILABLE: *mut RseqTable = ptr::dangling_mut();
+
+/// The table of rseq areas, which is mapped when it is first needed.
+static RSEQ_TABLE: AtomicPtr<RseqTable> = AtomicPtr::new(ptr::null_mut());
+
+/// Marks that [`LIBC_RSEQ_OFFSET`] has not been resolved yet.
+const LIBC_RSEQ_UNRESOLVED: isize = isize::MIN;
+/// Marks that the libc did not register rseq areas for its threads.
+const LIBC_RSEQ_UNAVAILABLE: isize = isize::MIN + 1;
+
+/// The offset of the rseq area that glibc (2.35 and later) registers for each of its threads from the thread pointer.
+static LIBC_RSEQ_OFFSET: AtomicIsize = AtomicIsize::new(LIBC_RSEQ_UNRESOLVED);
+
+/// Returns the id of the CPU that the calling thread runs on, which may be outdated as soon as it is returned.
+#[inline]
+pub fn current_cpu() -> u32 {
+	if let Some(area) = libc_rseq_area() {
+		let cpu = area.cpu_id.load(Ordering::Relaxed);
+		if (cpu as i32) >= 0 {
+			return cpu;
+		}
+	} else if let Some(area) = rseq_area() {
+		// Only the owning thread updates the counter, so it need not be atomic with respect to other threads.
+		let lookups = area.lookups.load(Ordering::Relaxed).wrapping_add(1);
+		area.lookups.store(lookups, Ordering::Relaxed);
+		if lookups % REVALIDATION_INTERVAL == 0 {
+			revalidate(area);
+		}
+		let cpu = area.kernel.cpu_id.load(Ordering::Relaxed);
+		if (cpu as i32) >= 0 {
+			return cpu;
+		}
+	}
+	sys::getcpu()
+}
+
+/// Returns the rseq area that glibc registered for the calling thread, which it exports via `__rseq_offset` and
+/// `__rseq_size`. The area can be used for critical sections, as glibc registers it whenever it sets up a thread, and
+/// only unregisters it once the thread has exited. Where rseq is unavailable or has been disabled (e.g., via
+/// `GLIBC_TUNABLES=glibc.pthread.rseq=0`), `__rseq_size` is zero.
+#[inline]
+fn libc_rseq_area() -> Option<&'static KernelRseq> {
+	let mut offset = LIBC_RSEQ_OFFSET.load(Ordering::Relaxed);
+	if offset == LIBC_RSEQ_UNRESOLVED {
+		offset = resolve_libc_rseq();
+	}
+	if offset == LIBC_RSEQ_UNAVAILABLE {
+		None
+	} else {
+		Some(unsafe { &*(sys::thread_pointer().wrapping_add_signed(offset) as *const KernelRseq) })
+	}
+}
+
+#[cold]
+fn resolve_libc_rseq() -> isize {
+	let offset = if sys::has_thread_pointer()
+		&& let Some(size) = sys::interpreter_symbol(b"__rseq_size")
+		&& let Some(offset) = sys::interpreter_symbol(b"__rseq_offset")
+		&& unsafe { (size as *const u32).read() } != 0
+	{
+		unsafe { (offset as *const isize).read() }
+	} else {
+		LIBC_RSEQ_UNAVAILABLE
+	};
+	LIBC_RSEQ_OFFSET.store(offset, Ordering::Relaxed);
+	offset
+}
+
+/// A stack of objects that belongs to a single CPU, and is only ever modified by threads that run on that CPU, via the
+/// critical sections of [`pop`] and [`push`].
+#[derive(Debug)]
+#[repr(C)]
+pub struct CpuStack<const CAPACITY: usize> {
+	len: usize,
+	objects: [*mut u8; CAPACITY],
+}
+
+impl<const CAPACITY: usize> CpuStack<CAPACITY> {
+	pub const fn new() -> Self {
+		Self {
+			len: 0,
+			objects: [ptr::null_mut(); CAPACITY],
+		}
+	}
+
+	/// Forgets all objects on the stack.
+	///
+	/// # Safety
+	/// No thread may access the stack concurrently.
+	pub unsafe fn clear(&mut self) {
+		self.len = 0;
+	}
+}
+
+/// Pops an object from the stack of the CPU that the calling thread runs on, without taking a lock. The stack of CPU
+/// `i` is located `offset` bytes into the object that `slots[i]` points to. Returns `None` if the stack is empty, if
+/// the slot is still null, if the CPU has no slot, or if critical sections are unavailable (see [`libc_rseq_area`]).
+///
+/// # Safety
+/// `offset` must locate a [`CpuStack`] in every object that `slots` points to, all of which must stay mapped.
+#[inline]
+pub unsafe fn pop<T, const SLOTS: usize>(slots: &[AtomicPtr<T>; SLOTS], offset: usize) -> Option<NonNull<u8>> {
+	#[cfg(target_arch = "x86_64")]
+	{
+		let area = libc_rseq_area()?;
+		let object: *mut u8;
+		// The critical section starts at label 3, and commits by storing the new length of the stack right before label
+		// 4. Aborts restart it from label 2, which (re)arms it.
+		unsafe {
+			core::arch::asm!(
+				"2:",
+				"lea {stack}, [rip + 5f]",
+				"mov qword ptr [{area} + 8], {stack}",
+				"3:",
+				"xor {object:e}, {object:e}",
+				"mov {stack:e}, dword ptr [{area} + 4]",
+				"cmp {stack:e}, {slot_count}",
+				"jae 4f",
+				"mov {stack}, qword ptr [{slots} + {stack} * 8]",
+				"test {stack}, {stack}",
+				"jz 4f",
+				"add {stack}, {offset}",
+				"mov {len}, qword ptr [{stack}]",
+				"test {len}, {len}",
+				"jz 4f",
+				"mov {object}, qword ptr [{stack} + {len} * 8]",
+				"dec {len}",
+				"mov qword ptr [{stack}], {len}",
+				"4:",
+				".pushsection __rseq_cs, \"aw\"",
+				".balign 32",
+				"5:",
+				".long 0, 0",
+				".quad 3b, 4b - 3b, 6f",
+				".popsection",
+				"jmp 7f",
+				".byte 0x0f, 0xb9, 0x3d",
+				".long {signature}",
+				"6:",
+				"jmp 2b",
+				"7:",
+				area = in(reg) area,
+				slots = in(reg) slots.as_ptr(),
+				offset = in(reg) offset,
+				slot_count = const SLOTS,
+				signature = const RSEQ_SIG,
+				stack = out(reg) _,
+				len = out(reg) _,
+				object = out(reg) object,
+				options(nostack),
+			);
+		}
+		NonNull::new(object)
+	}
+	#[cfg(not(target_arch = "x86_64"))]
+	{
+		let _ = (slots, offset);
+		None
+	}
+}
+
+/// Pushes `object` onto the stack of the CPU that the calling thread runs on (see [`pop`]), unless it already holds
+/// `capacity` objects. Returns `false` if the object was not pushed.
+///
+/// # Safety
+/// The same as for [`pop`]. Additionally, the stacks must have room for `capacity` objects.
+#[inline]
+pub unsafe fn push<T, const SLOTS: usize>(
+	slots: &[AtomicPtr<T>; SLOTS],
+	offset: usize,
+	capacity: usize,
+	object: NonNull<u8>,
+) -> bool {
+	#[cfg(target_arch = "x86_64")]
+	{
+		let Some(area) = libc_rseq_area() else {
+			return false;
+		};
+		let pushed: u32;
+		// The same critical section as in `pop`, which stores the object before committing.
+		unsafe {
+			core::arch::asm!(
+				"2:",
+				"lea {stack}, [rip + 5f]",
+				"mov qword ptr [{area} + 8], {stack}",
+				"3:",
+				"xor {pushed:e}, {pushed:e}",
+				"mov {stack:e}, dword ptr [{area} + 4]",
+				"cmp {stack:e}, {slot_count}",
+				"jae 4f",
+				"mov {stack}, qword ptr [{slots} + {stack} * 8]",
+				"test {stack}, {stack}",
+				"jz 4f",
+				"add {stack}, {offset}",
+				"mov {len}, qword ptr [{stack}]",
+				"cmp {len}, {capacity}",
+				"jae 4f",
+				"mov qword ptr [{stack} + {len} * 8 + 8], {object}",
+				"inc {len}",
+				"mov {pushed:e}, 1",
+				"mov qword ptr [{stack}], {len}",
+				"4:",
+				".pushsection __rseq_cs, \"aw\"",
+				".balign 32",
+				"5:",
+				".long 0, 0",
+				".quad 3b, 4b - 3b, 6f",
+				".popsection",
+				"jmp 7f",
+				".byte 0x0f, 0xb9, 0x3d",
+				".long {signature}",
+				"6:",
+				"jmp 2b",
+				"7:",
+				area = in(reg) area,
+				slots = in(reg) slots.as_ptr(),
+				offset = in(reg) offset,
+				capacity = in(reg) capacity,
+				object = in(reg) object.as_ptr(),
+				slot_count = const SLOTS,
+				signature = const RSEQ_SIG,
+				stack = out(reg) _,
+				len = out(reg) _,
+				pushed = out(reg) pushed,
+				options(nostack),
+			);
+		}
+		pushed != 0
+	}
+	#[cfg(not(target_arch = "x86_64"))]
+	{
+		let _ = (slots, offset, capacity, object);
+		false
+	}
+}
+
+/// Returns the rseq area of the calling thread, which is registered when it is first looked up.
+#[inline]
+fn rseq_area() -> Option<&'static Rseq> {
+	let table = rseq_table()?;
+	let thread = unsafe { sys::thread_pointer() };
+	let start = thread.wrapping_mul(0x9e37_79b9_7f4a_7c15_u64 as usize) >> (usize::BITS - RSEQ_SLOTS.ilog2());
+	for i in 0..RSEQ_PROBES {
+		let slot = (start + i) % RSEQ_SLOTS;
+		let owner = table.threads[slot].load(Ordering::Relaxed);
+		if owner == thread {
+			return Some(&table.areas[slot]);
+		} else if owner == 0
+			&& table.threads[slot]
+				.compare_exchange(0, thread, Ordering::Relaxed, Ordering::Relaxed)
+				.is_ok()
+		{
+			let area = &table.areas[slot];
+			area.kernel.cpu_id.store(RSEQ_CPU_ID_UNINITIALIZED, Ordering::Relaxed);
+			if unsafe { sys::rseq(NonNull::from(area).cast(), RSEQ_LEN, 0, RSEQ_SIG) }.is_err() {
+				area
+					.kernel
+					.cpu_id
+					.store(RSEQ_CPU_ID_REGISTRATION_FAILED, Ordering::Relaxed);
+			}
+			return Some(area);
+		}
+	}
+	None
+}
+
+/// Makes sure that the rseq area of the calling thread is registered by this very thread. An area that is taken over
+/// from an exited thread is not registered anew when it is looked up, so its CPU id may be stale until this is called,
+/// or until the thread has looked it up [`REVALIDATION_INTERVAL`] times.
+#[cold]
+pub fn revalidate_rseq() {
+	if libc_rseq_area().is_none()
+		&& let Some(area) = rseq_area()
+	{
+		revalidate(area);
+	}
+}
+
+#[cold]
+fn revalidate(area: &Rseq) {
+	// The thread that claimed the area could not register it, and neither can any thread that takes it over.
+	if area.kernel.cpu_id.load(Ordering::Relaxed) == RSEQ_CPU_ID_REGISTRATION_FAILED {
+		return;
+	}
+	match unsafe { sys::rseq(NonNull::from(area).cast(), RSEQ_LEN, 0, RSEQ_SIG) } {
+		// The area was registered by this thread already, or just now.
+		Ok(()) | Err(Errno::EBUSY) => (),
+		Err(_) => area
+			.kernel
+			.cpu_id
+			.store(RSEQ_CPU_ID_REGISTRATION_FAILED, Ordering::Relaxed),
+	}
+}
+
+#[inline]
+fn rseq_table() -> Option<&'static RseqTable> {
+	let table = RSEQ_TABLE.load(Ordering::Acquire);
+	if table.is_null() {
+		map_rseq_table()
+	} else if table == RSEQ_UNAVAILABLE {
+		None
+	} else {
+		Some(unsafe { &*table })
+	}
+}
+
+#[cold]
+fn map_rseq_table() -> Option<&'static RseqTable> {
+	let size = NonZero::new((size_of::<RseqTable>() + 4095) & !4095).unwrap();
+	// Threads are told apart by their thread pointer, which is only set up by a libc (or equivalent runtime).
+	let table = if !sys::has_thread_pointer() {
+		RSEQ_UNAVAILABLE
+	} else {
+		unsafe {
+			alloc_aligned(
+				size,
+				NonZero::new(align_of::<RseqTable>()).unwrap(),
+				DefaultConfig::ALLOC_ALIGNED_RETRIES,
+				c"emma:rseq",
+			)
+			.map_or(RSEQ_UNAVAILABLE, |table| table.cast::<RseqTable>().as_ptr())
+		}
+	};
+
+	let table = match RSEQ_TABLE.compare_exchange(ptr::null_mut(), table, Ordering::AcqRel, Ordering::Acquire) {
+		Ok(_) => table,
+		Err(winner) => {
+			if table != RSEQ_UNAVAILABLE {
+				let res = unsafe { munmap(NonNull::new_unchecked(table).cast(), size) };
+				debug_assert!(res.is_ok());
+			}
+			winner
+		}
+	};
+	if table == RSEQ_UNAVAILABLE {
+		None
+	} else {
+		Some(unsafe { &*table })
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn current_cpu_matches_getcpu() {
+		// The thread may be migrated between both calls, but not every single time.
+		assert!((0..100).any(|_| current_cpu() == sys::getcpu()));
+	}
+
+	#[test]
+	#[cfg(target_arch = "x86_64")]
+	fn push_and_pop() {
+		// Critical sections require the rseq areas of glibc, which the test harness links against.
+		assert!(libc_rseq_area().is_some());
+
+		// All CPUs share the stack, so that the thread may be migrated between calls. Only this thread uses it.
+		let mut stack = CpuStack::<2>::new();
+		let slots: [AtomicPtr<CpuStack<2>>; 1024] = core::array::from_fn(|_| AtomicPtr::new(&mut stack));
+		let values = [0u8; 2];
+		let objects = [NonNull::from(&values[0]), NonNull::from(&values[1])];
+		unsafe {
+			assert_eq!(pop(&slots, 0), None);
+			assert!(push(&slots, 0, 2, objects[0]));
+			assert!(push(&slots, 0, 2, objects[1]));
+			assert!(!push(&slots, 0, 2, objects[0]));
+			assert_eq!(pop(&slots, 0), Some(objects[1]));
+			assert_eq!(pop(&slots, 0), Some(objects[0]));
+			assert_eq!(pop(&slots, 0), None);
+		}
+	}
+
+	#[test]
+	fn rseq_areas_are_per_thread() {
+		let area = rseq_area().map(|area| area as *const Rseq as usize);
+		let other = std::thread::spawn(|| rseq_area().map(|area| area as *const Rseq as usize))
+			.join()
+			.unwrap();
+		assert!(area.is_some());
+		assert_ne!(area, other);
+		assert_eq!(area, rseq_area().map(|area| area as *const Rseq as usize));
+	}
+}
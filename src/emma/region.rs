@@ -0,0 +1,281 @@
+//! Regions hand out objects by bumping a pointer through arenas of their own, and release all of them at once when they
+//! are dropped. The arenas are passed between the regions of an [`Emma`] via its pool of region arenas, which is
+//! separate from the pools of the small and medium object tiers, as their arenas carry metadata.
+
+use core::cell::Cell;
+use core::ffi::c_void;
+use core::num::NonZero;
+use core::ptr::NonNull;
+
+use super::Emma;
+use super::arena::pool::ArenaPool;
+use super::config::{Config, DefaultConfig};
+use crate::mmap::{MAdviseAdvice, alloc_aligned, madvise, munmap};
+
+/// The first bytes of every chunk of a region, which is either an arena or an object too large for one.
+#[derive(Debug)]
+#[repr(C)]
+struct Chunk {
+	/// The chunk that was added to the region before this one.
+	previous: Option<NonNull<Chunk>>,
+	/// The size of the mapping, which is [`Config::ARENA_SIZE`] exactly if the chunk is an arena.
+	size: usize,
+}
+
+const CHUNK_HEADER_SIZE: usize = size_of::<Chunk>();
+
+/// Creates a pool for empty region arenas, which is shared between all regions of an instance. While an arena is in
+/// the pool, its first bytes hold the next arena, in place of the chunk header.
+pub const fn new_pool<C: Config>() -> ArenaPool {
+	ArenaPool::new(C::ARENA_SIZE as usize, 0)
+}
+
+/// Unmaps all arenas in `pool`, which must not race with any push or pop.
+pub unsafe fn unmap_pool<C: Config>(pool: &ArenaPool) {
+	while let Some(arena) = pool.pop() {
+		unsafe { munmap(arena, NonZero::new_unchecked(C::ARENA_SIZE as usize)).unwrap() };
+	}
+}
+
+/// An allocator that bump-allocates objects from arenas (see [`Config::ARENA_SIZE`]), and frees all of them at once
+/// when it is dropped (or [reset](Region::reset)). Freeing an individual object does nothing, but the most recently
+/// allocated object can be grown and shrunk in place.
+///
+/// The arenas are returned to the [`Emma`] that the region was created for, whose regions reuse them. Their physical
+/// memory is released lazily (`MADV_FREE`), and their address space is only returned to the OS by
+/// [`Emma::reset`](super::Emma::reset) or when the [`Emma`] is dropped. Objects that do not fit into an arena are
+/// mapped individually, and unmapped with the region.
+///
+/// A region is not [`Sync`], and cannot serve as the global allocator. With the `allocator-api` feature, `&Region`
+/// implements [`Allocator`](core::alloc::Allocator), so that collections can borrow a region.
+#[derive(Debug)]
+pub struct Region<'a, C: Config = DefaultConfig> {
+	emma: &'a Emma<C>,
+	/// The chunk that objects are currently carved from, which is the head of the list of all chunks.
+	chunk: Cell<Option<NonNull<Chunk>>>,
+	/// The address of the first free byte in the current chunk. Starts out past `end`, so that even zero-sized objects
+	/// take the slow path until there is a chunk.
+	top: Cell<usize>,
+	/// The address just after the current chunk.
+	end: Cell<usize>,
+	/// The address of the most recently allocated object, which can be resized in place.
+	last: Cell<usize>,
+}
+
+unsafe impl<C: Config> Send for Region<'_, C> {}
+
+impl<'a, C: Config> Region<'a, C> {
+	const ARENA_SIZE: usize = C::ARENA_SIZE as usize;
+
+	/// Creates a region that takes its arenas from (and returns them to) `emma`. No memory is mapped until the first
+	/// object is allocated.
+	pub const fn new(emma: &'a Emma<C>) -> Self {
+		Self {
+			emma,
+			chunk: Cell::new(None),
+			top: Cell::new(1),
+			end: Cell::new(0),
+			last: Cell::new(0),
+		}
+	}
+
+	/// Allocates an object, or returns `None` if no memory could be obtained.
+	#[inline]
+	fn bump(&self, layout: core::alloc::Layout) -> Option<NonNull<u8>> {
+		if let Some(start) = self.top.get().checked_next_multiple_of(layout.align())
+			&& let Some(top) = start.checked_add(layout.size())
+			&& top <= self.end.get()
+		{
+			self.top.set(top);
+			self.last.set(start);
+			return Some(unsafe { NonNull::new_unchecked(start as *mut u8) });
+		}
+		self.bump_slow(layout)
+	}
+
+	#[cold]
+	fn bump_slow(&self, layout: core::alloc::Layout) -> Option<NonNull<u8>> {
+		let offset = CHUNK_HEADER_SIZE.next_multiple_of(layout.align());
+		let required = offset.checked_add(layout.size())?;
+
+		if required <= Self::ARENA_SIZE {
+			let instance = self.emma.instance()?;
+			let arena = match instance.region_pool.pop() {
+				Some(arena) => arena,
+				None => unsafe {
+					alloc_aligned(
+						NonZero::new_unchecked(Self::ARENA_SIZE),
+						NonZero::new_unchecked(Self::ARENA_SIZE),
+						C::ALLOC_ALIGNED_RETRIES,
+						c"emma:region",
+					)?
+				},
+			}
+			.cast::<Chunk>();
+			unsafe {
+				arena.write(Chunk {
+					previous: self.chunk.get(),
+					size: Self::ARENA_SIZE,
+				})
+			};
+
+			let start = arena.as_ptr() as usize + offset;
+			self.chunk.set(Some(arena));
+			self.top.set(start + layout.size());
+			self.end.set(arena.as_ptr() as usize + Self::ARENA_SIZE);
+			self.last.set(start);
+			Some(unsafe { NonNull::new_unchecked(start as *mut u8) })
+		} else {
+			// The object gets a chunk of its own, which is linked behind the current chunk, so that the free space of the
+			// current chunk remains available.
+			let alignment = layout.align().max(4096);
+			let size = required.checked_next_multiple_of(alignment)?;
+			let chunk = unsafe {
+				alloc_aligned(
+					NonZero::new_unchecked(size),
+					NonZero::new_unchecked(alignment),
+					C::ALLOC_ALIGNED_RETRIES,
+					c"emma:region",
+				)?
+				.cast::<Chunk>()
+			};
+			match self.chunk.get() {
+				Some(current) => unsafe {
+					chunk.write(Chunk {
+						previous: current.as_ref().previous,
+						size,
+					});
+					(*current.as_ptr()).previous = Some(chunk);
+				},
+				None => {
+					unsafe { chunk.write(Chunk { previous: None, size }) };
+					self.chunk.set(Some(chunk));
+					self.top.set(chunk.as_ptr() as usize + size);
+					self.end.set(chunk.as_ptr() as usize + size);
+				}
+			}
+
+			// Objects in a chunk of their own are never resized in place.
+			self.last.set(0);
+			Some(unsafe { chunk.byte_add(offset).cast() })
+		}
+	}
+
+	/// Resizes an object to `new_size` bytes, which happens in place if the object was the most recently allocated one
+	/// and its arena has enough space left, or if the object shrinks. Otherwise, the object is copied to a new location.
+	///
+	/// # Safety
+	/// `ptr` must have been allocated from this region with `layout`.
+	unsafe fn resize(&self, ptr: NonNull<u8>, layout: core::alloc::Layout, new_size: usize) -> Option<NonNull<u8>> {
+		let start = ptr.as_ptr() as usize;
+		if start == self.last.get() && start + layout.size() == self.top.get() && new_size <= self.end.get() - start {
+			self.top.set(start + new_size);
+			return Some(ptr);
+		} else if new_size <= layout.size() {
+			return Some(ptr);
+		}
+
+		let new_layout = core::alloc::Layout::from_size_align(new_size, layout.align()).ok()?;
+		let new_ptr = self.bump(new_layout)?;
+		unsafe { new_ptr.copy_from_nonoverlapping(ptr, layout.size()) };
+		Some(new_ptr)
+	}
+
+	/// Frees all objects at once, but keeps the current arena for the objects that are allocated next.
+	pub fn reset(&mut self) {
+		let Some(chunk) = self.chunk.get() else {
+			return;
+		};
+
+		unsafe {
+			if chunk.as_ref().size == Self::ARENA_SIZE {
+				let previous = core::mem::take(&mut (*chunk.as_ptr()).previous);
+				self.release(previous);
+				self.top.set(chunk.as_ptr() as usize + CHUNK_HEADER_SIZE);
+				self.last.set(0);
+			} else {
+				self.release(Some(chunk));
+				self.chunk.set(None);
+				self.top.set(1);
+				self.end.set(0);
+				self.last.set(0);
+			}
+		}
+	}
+
+	/// Returns the arenas in the list of chunks starting at `chunk` to the pool, and unmaps all other chunks.
+	unsafe fn release(&self, mut chunk: Option<NonNull<Chunk>>) {
+		while let Some(current) = chunk {
+			unsafe {
+				let Chunk { previous, size } = *current.as_ptr();
+				chunk = previous;
+				// Arenas are only taken once the instance has been mapped.
+				if size == Self::ARENA_SIZE
+					&& let Some(instance) = self.emma.mapped_instance()
+				{
+					let _ = madvise(current.cast::<c_void>(), Self::ARENA_SIZE, MAdviseAdvice::FREE);
+					instance.region_pool.push(current.cast());
+				} else {
+					munmap(current.cast(), NonZero::new_unchecked(size)).unwrap();
+				}
+			}
+		}
+	}
+}
+
+impl<C: Config> Drop for Region<'_, C> {
+	fn drop(&mut self) {
+		unsafe { self.release(self.chunk.get()) };
+	}
+}
+
+unsafe impl<C: Config> alloc::alloc::GlobalAlloc for Region<'_, C> {
+	unsafe fn alloc(&self, layout: core::alloc::Layout) -> *mut u8 {
+		self.bump(layout).map_or(core::ptr::null_mut(), NonNull::as_ptr)
+	}
+
+	unsafe fn dealloc(&self, _ptr: *mut u8, _layout: core::alloc::Layout) {}
+
+	unsafe fn realloc(&self, ptr: *mut u8, layout: core::alloc::Layout, new_size: usize) -> *mut u8 {
+		unsafe { self.resize(NonNull::new_unchecked(ptr), layout, new_size) }.map_or(core::ptr::null_mut(), NonNull::as_ptr)
+	}
+}
+
+#[cfg(feature = "allocator-api")]
+unsafe impl<C: Config> core::alloc::Allocator for &Region<'_, C> {
+	fn allocate(&self, layout: core::alloc::Layout) -> Result<NonNull<[u8]>, core::alloc::AllocError> {
+		self
+			.bump(layout)
+			.map(|ptr| NonNull::slice_from_raw_parts(ptr, layout.size()))
+			.ok_or(core::alloc::AllocError)
+	}
+
+	unsafe fn deallocate(&self, _ptr: NonNull<u8>, _layout: core::alloc::Layout) {}
+
+	unsafe fn grow(
+		&self,
+		ptr: NonNull<u8>,
+		old_layout: core::alloc::Layout,
+		new_layout: core::alloc::Layout,
+	) -> Result<NonNull<[u8]>, core::alloc::AllocError> {
+		let new_ptr = if (ptr.as_ptr() as usize).is_multiple_of(new_layout.align()) {
+			unsafe { self.resize(ptr, old_layout, new_layout.size()) }
+		} else {
+			self.bump(new_layout).inspect(|new_ptr| unsafe {
+				new_ptr.copy_from_nonoverlapping(ptr, old_layout.size().min(new_layout.size()));
+			})
+		};
+		new_ptr
+			.map(|ptr| NonNull::slice_from_raw_parts(ptr, new_layout.size()))
+			.ok_or(core::alloc::AllocError)
+	}
+
+	unsafe fn shrink(
+		&self,
+		ptr: NonNull<u8>,
+		old_layout: core::alloc::Layout,
+		new_layout: core::alloc::Layout,
+	) -> Result<NonNull<[u8]>, core::alloc::AllocError> {
+		unsafe { self.grow(ptr, old_layout, new_layout) }
+	}
+}
@@ -0,0 +1,328 @@
+//! Keeps track of every arena and every huge object that emma obtained from the OS, so that the memory can be
+//! inspected after the fact, e.g., to determine how much of it is actually resident.
+//!
+//! The registry is global, i.e., shared between all [`Emma`](super::Emma) instances, but each mapping is tagged with
+//! the instance that owns it. It is split into shards by address, each behind a lock of its own, so that threads that
+//! map and unmap concurrently rarely contend. No system call is made while a shard is locked.
+
+use core::ffi::{CStr, c_void};
+use core::num::NonZero;
+use core::ptr::NonNull;
+
+use super::config::{Config, DefaultConfig};
+use super::instance::InstanceId;
+use super::{Tier, huge_pages};
+use crate::address_map::AddressMap;
+use crate::mmap::{Reservation, alloc_aligned, alloc_aligned_hugetlb, mincore, munmap, realloc_aligned};
+use crate::sync::Futex;
+
+/// The number of pages queried per `mincore` call, which is also the size of the buffer on the stack.
+const MINCORE_BATCH_SIZE: usize = 1024;
+
+/// The number of shards that the registry is split into, which must be a power of two.
+const SHARDS: usize = 64;
+/// The number of mappings that are collected from a shard before it is unlocked to make system calls for them.
+const BATCH_SIZE: usize = 64;
+
+/// The amount of address space that is reserved at once for arenas.
+const ARENA_RESERVATION_CHUNK_SIZE: usize = 1024 * 1024 * 1024;
+
+static MAPPINGS: [Futex<AddressMap<Mapping>>; SHARDS] = [const { Futex::new(AddressMap::new()) }; SHARDS];
+/// Arenas of all tiers are carved from a common reservation, which keeps them close together and makes creating an
+/// arena a single `mprotect` call. The reservation is shared by all configurations, so it retries as often as the
+/// default one does.
+static ARENA_RESERVATION: Futex<Reservation> = Futex::new(Reservation::new(
+	NonZero::new(ARENA_RESERVATION_CHUNK_SIZE).unwrap(),
+	DefaultConfig::ALLOC_ALIGNED_RETRIES,
+));
+
+#[derive(Debug, Copy, Clone)]
+struct Mapping {
+	size: usize,
+	tier: Tier,
+	hugetlb: bool,
+	owner: InstanceId,
+	/// Set while the mapping is being moved by [`remap`].
+	moving: bool,
+}
+
+/// Returns the index of the shard that the mapping at `address` is registered in.
+#[inline]
+fn shard_index(address: usize) -> usize {
+	// Fibonacci hashing, dropping the lowest bits first as they are always zero due to the page alignment of mappings.
+	(address >> 12).wrapping_mul(0x9E37_79B9_7F4A_7C15) >> (usize::BITS - SHARDS.trailing_zeros())
+}
+
+#[inline]
+fn shard(address: usize) -> &'static Futex<AddressMap<Mapping>> {
+	&MAPPINGS[shard_index(address)]
+}
+
+/// The number of bytes of each tier that are backed by physical memory, as reported by [`Emma::resident_bytes`].
+///
+/// [`Emma::resident_bytes`]: super::Emma::resident_bytes
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct ResidentBytes {
+	/// Resident bytes in arenas for small objects.
+	pub small: usize,
+	/// Resident bytes in arenas for medium objects.
+	pub medium: usize,
+	/// Resident bytes in arenas for large objects.
+	pub large: usize,
+	/// Resident bytes of huge objects, which are mapped individually.
+	pub huge: usize,
+}
+
+impl ResidentBytes {
+	/// The sum over all tiers.
+	pub fn total(&self) -> usize {
+		self.small + self.medium + self.large + self.huge
+	}
+
+	fn tier_mut(&mut self, tier: Tier) -> &mut usize {
+		match tier {
+			Tier::Small => &mut self.small,
+			Tier::Medium => &mut self.medium,
+			Tier::Large => &mut self.large,
+			Tier::Huge => &mut self.huge,
+		}
+	}
+}
+
+/// Allocates a mapping for the given tier and registers it as owned by `owner`, whose configuration is `C`. The mapping
+/// is taken from the hugetlb pool if so configured (see [`huge_pages::hugetlb_page_size`]), in which case the second
+/// element of the returned tuple is `true`.
+pub unsafe fn map<C: Config>(
+	size: NonZero<usize>,
+	alignment: NonZero<usize>,
+	tier: Tier,
+	name: &CStr,
+	owner: InstanceId,
+) -> Option<(NonNull<c_void>, bool)> {
+	let hugetlb_mapping = huge_pages::hugetlb_page_size(tier, size.get()).and_then(|page_size| {
+		let size = NonZero::new(size.get().checked_next_multiple_of(page_size.bytes().get())?)?;
+		let mapping = unsafe { alloc_aligned_hugetlb(size, alignment, page_size.bytes(), C::ALLOC_ALIGNED_RETRIES) };
+		huge_pages::record_hugetlb(mapping.is_some());
+		mapping.map(|mapping| (mapping, size))
+	});
+	let (mapping, size, hugetlb) = if let Some((mapping, size)) = hugetlb_mapping {
+		(mapping, size, true)
+	} else if let Some(mapping) = (tier != Tier::Huge)
+		.then(|| unsafe { ARENA_RESERVATION.lock().take(size, alignment) })
+		.flatten()
+		.filter(|&mapping| unsafe { Reservation::commit(mapping, size, name).is_some() })
+	{
+		(mapping, size, false)
+	} else {
+		(
+			unsafe { alloc_aligned(size, alignment, C::ALLOC_ALIGNED_RETRIES, name)? },
+			size,
+			false,
+		)
+	};
+
+	if shard(mapping.as_ptr() as usize).lock().insert(
+		mapping.as_ptr() as usize,
+		Mapping {
+			size: size.get(),
+			tier,
+			hugetlb,
+			owner,
+			moving: false,
+		},
+	) {
+		Some((mapping, hugetlb))
+	} else {
+		// The registry could not grow, so we treat this like any other failure to obtain memory.
+		unsafe { munmap(mapping, size).unwrap() };
+		None
+	}
+}
+
+/// Unregisters and unmaps a mapping obtained from [`map`].
+pub unsafe fn unmap(address: NonNull<c_void>) {
+	let removed = shard(address.as_ptr() as usize)
+		.lock()
+		.remove(address.as_ptr() as usize);
+	let mapping = removed.expect("unmapping an unregistered mapping");
+	unsafe { munmap(address, NonZero::new_unchecked(mapping.size)).unwrap() };
+}
+
+/// Returns the actual size of a mapping obtained from [`map`], whether it was taken from the hugetlb pool, and its
+/// owner.
+pub fn mapping(address: NonNull<c_void>) -> Option<(NonZero<usize>, bool, InstanceId)> {
+	let address = address.as_ptr() as usize;
+	shard(address).lock().get(address).map(|mapping| {
+		(
+			unsafe { NonZero::new_unchecked(mapping.size) },
+			mapping.hugetlb,
+			mapping.owner,
+		)
+	})
+}
+
+/// Returns the owner of the mapping that the object at `address` lies in, which is either a huge object, or an object
+/// within an arena of `arena_size` bytes.
+#[cfg(feature = "heap-profile")]
+pub fn owner(address: usize, arena_size: usize) -> Option<InstanceId> {
+	let owner = |address| shard(address).lock().get(address).map(|mapping| mapping.owner);
+	owner(address).or_else(|| owner(address & !(arena_size - 1)))
+}
+
+/// Unregisters and unmaps all mappings that are owned by `owner`. Each shard is unlocked while the mappings removed
+/// from it are unmapped.
+pub unsafe fn unmap_owned(owner: InstanceId) {
+	for shard in &MAPPINGS {
+		loop {
+			let mut batch = [(0, 0); BATCH_SIZE];
+			let mut len = 0;
+			shard.lock().retain(|address, mapping| {
+				if mapping.owner != owner || len == BATCH_SIZE {
+					return true;
+				}
+				batch[len] = (address, mapping.size);
+				len += 1;
+				false
+			});
+
+			for &(address, size) in &batch[..len] {
+				unsafe {
+					munmap(
+						NonNull::new_unchecked(address as *mut c_void),
+						NonZero::new_unchecked(size),
+					)
+					.unwrap()
+				};
+			}
+			if len < BATCH_SIZE {
+				break;
+			}
+		}
+	}
+}
+
+/// Resizes a mapping obtained from [`map`] for the configuration `C`, which may move it to a new address that satisfies
+/// `alignment` (see [`realloc_aligned`]). Must not be used for hugetlb mappings. Returns `None` if the mapping could
+/// neither be resized nor moved, in which case it stays registered at its old address.
+///
+/// No shard is locked while the mapping is resized. Instead, its entry is marked as moving meanwhile, and the new
+/// address is registered before the mapping is moved there. Once the mapping has moved, its old address may be mapped
+/// and registered by someone else, so the old entry is only removed if it is still marked as moving.
+pub unsafe fn remap<C: Config>(
+	address: NonNull<c_void>,
+	new_size: NonZero<usize>,
+	alignment: NonZero<usize>,
+) -> Option<NonNull<c_void>> {
+	let old_shard = shard(address.as_ptr() as usize);
+	let mapping = {
+		let mut mappings = old_shard.lock();
+		let mapping = *mappings.get(address.as_ptr() as usize)?;
+		debug_assert!(!mapping.hugetlb && !mapping.moving);
+		// Replacing an existing entry cannot fail.
+		mappings.insert(
+			address.as_ptr() as usize,
+			Mapping {
+				moving: true,
+				..mapping
+			},
+		);
+		mapping
+	};
+	let new_mapping = Mapping {
+		size: new_size.get(),
+		..mapping
+	};
+
+	let mut claimed = None;
+	let new_address = unsafe {
+		realloc_aligned(
+			address,
+			NonZero::new_unchecked(mapping.size),
+			new_size,
+			alignment,
+			C::ALLOC_ALIGNED_RETRIES,
+			|target| {
+				let inserted = shard(target.as_ptr() as usize)
+					.lock()
+					.insert(target.as_ptr() as usize, new_mapping);
+				if inserted {
+					claimed = Some(target);
+				}
+				inserted
+			},
+		)
+	};
+
+	match new_address {
+		Some(new_address) if new_address == address => {
+			// The mapping was resized in place, so its old address was never free to be registered by anyone else.
+			old_shard.lock().insert(address.as_ptr() as usize, new_mapping);
+		}
+		Some(_) => {
+			let mut mappings = old_shard.lock();
+			if mappings
+				.get(address.as_ptr() as usize)
+				.is_some_and(|mapping| mapping.moving)
+			{
+				mappings.remove(address.as_ptr() as usize);
+			}
+		}
+		None => {
+			if let Some(target) = claimed {
+				shard(target.as_ptr() as usize).lock().remove(target.as_ptr() as usize);
+			}
+			old_shard.lock().insert(address.as_ptr() as usize, mapping);
+		}
+	}
+	new_address
+}
+
+/// Counts the resident bytes of all registered mappings that are owned by `owner`.
+///
+/// The mappings are collected in batches, and their shard is unlocked while the kernel is queried. A mapping that is
+/// unmapped concurrently is skipped or counted partially, and a shard that grows meanwhile may count mappings twice,
+/// which only happens if the instance is in use meanwhile.
+pub fn resident_bytes(owner: InstanceId) -> ResidentBytes {
+	let mut resident = ResidentBytes::default();
+	let mut vec = [0u8; MINCORE_BATCH_SIZE];
+
+	for shard in &MAPPINGS {
+		let mut index = 0;
+		loop {
+			let mut batch = [(0, 0, Tier::Huge); BATCH_SIZE];
+			let mut len = 0;
+			{
+				let mappings = shard.lock();
+				while index < mappings.capacity() && len < BATCH_SIZE {
+					if let Some((address, mapping)) = mappings.slot(index).filter(|(_, mapping)| mapping.owner == owner) {
+						batch[len] = (address, mapping.size, mapping.tier);
+						len += 1;
+					}
+					index += 1;
+				}
+			}
+			if len == 0 {
+				break;
+			}
+
+			for &(address, size, tier) in &batch[..len] {
+				let mut offset = 0;
+				while offset < size {
+					let length = (size - offset).min(MINCORE_BATCH_SIZE * 4096);
+					let pages = length.div_ceil(4096);
+					let address = unsafe { NonNull::new_unchecked((address + offset) as *mut c_void) };
+					match unsafe { mincore(address, length, &mut vec[..pages]) } {
+						Ok(()) => {
+							*resident.tier_mut(tier) += vec[..pages].iter().filter(|&&page| page & 1 != 0).count() * 4096;
+						}
+						// The mapping was unmapped meanwhile, which is not worth a panic either.
+						Err(_) => break,
+					}
+					offset += length;
+				}
+			}
+		}
+	}
+
+	resident
+}
@@ -0,0 +1,264 @@
+use core::num::NonZero;
+use core::ptr::{self, NonNull};
+use core::sync::atomic::{AtomicPtr, Ordering};
+
+use super::Heap;
+use super::config::Config;
+#[cfg(feature = "percpu")]
+use super::config::Tiers;
+use super::instance::Instance;
+#[cfg(feature = "percpu")]
+use super::percpu::{self, CpuStack};
+use crate::mmap::{alloc_aligned, munmap};
+use crate::sync::{Futex, FutexGuard};
+
+/// The number of shards. With the `percpu` feature, CPUs with larger ids share the shards of smaller ones.
+#[cfg(feature = "percpu")]
+const SHARDS: usize = 256;
+/// The number of shards. Threads are spread over them by their id, so fewer shards are contended more often, while each
+/// additional shard holds on to its own partially used pages.
+#[cfg(not(feature = "percpu"))]
+const SHARDS: usize = 64;
+
+/// The number of shards that are tried, starting with the one of the calling thread, before it waits for its own.
+const PROBES: usize = 4;
+
+/// The number of small object bins whose objects are cached per CPU, i.e., objects of up to 256 bytes are cached.
+#[cfg(feature = "percpu")]
+const CACHED_BINS: usize = 32;
+/// The number of objects that are cached per bin and CPU.
+#[cfg(feature = "percpu")]
+const CACHE_CAPACITY: usize = 16;
+/// The number of objects that are moved between a cache and its heap at once.
+#[cfg(feature = "percpu")]
+pub const CACHE_BATCH: usize = CACHE_CAPACITY / 2;
+
+/// Heaps that are each guarded by a futex, and are mapped once they are first used. Heaps are never unmapped, as their
+/// ids are their addresses (see [`Heap::assign_id`]). Objects are freed by whichever shard the freeing thread locked,
+/// which hands objects of other shards back to them, just as thread heaps do with the `tls` feature.
+///
+/// With the `percpu` feature, each thread uses the shard of the CPU it runs on (see [`super::percpu::current_cpu`]).
+/// The futex is still needed, as allocating takes far too long to be done in a restartable sequence, but it is only
+/// contended if a thread is preempted or migrated to another CPU while it holds a shard. Small objects are allocated
+/// from and freed to a cache of the CPU without taking the futex at all (see [`Shards::take_cached`]).
+#[derive(Debug)]
+pub struct Shards<C: Config> {
+	shards: [AtomicPtr<Shard<C>>; SHARDS],
+}
+
+#[derive(Debug)]
+struct Shard<C: Config> {
+	heap: Futex<Heap<C>>,
+	/// Freed small objects, which threads running on the CPU of this shard push and pop via restartable sequences, one
+	/// stack per bin. The objects still count as allocated as far as their pages are concerned.
+	#[cfg(feature = "percpu")]
+	cache: [CpuStack<CACHE_CAPACITY>; CACHED_BINS],
+}
+
+impl<C: Config> Shards<C> {
+	pub const fn new() -> Self {
+		Self {
+			shards: [const { AtomicPtr::new(ptr::null_mut()) }; SHARDS],
+		}
+	}
+
+	/// Locks the shard of the calling thread, or one of the next few shards if it is contended. Heaps are mapped for
+	/// `instance`, which these shards belong to. Returns `None` if a heap could not be mapped.
+	#[inline]
+	pub fn lock(&self, instance: NonNull<Instance<C>>) -> Option<FutexGuard<'_, Heap<C>>> {
+		if let Some(guard) = self.heap(current_shard(), instance)?.try_lock() {
+			return Some(guard);
+		}
+		self.lock_contended(instance)
+	}
+
+	#[cold]
+	fn lock_contended(&self, instance: NonNull<Instance<C>>) -> Option<FutexGuard<'_, Heap<C>>> {
+		// The thread may have been migrated since its CPU was determined.
+		#[cfg(feature = "percpu")]
+		super::percpu::revalidate_rseq();
+		let shard = current_shard();
+		for i in 0..PROBES {
+			if let Some(guard) = self.heap((shard + i) % SHARDS, instance)?.try_lock() {
+				return Some(guard);
+			}
+		}
+		Some(self.heap(shard, instance)?.lock())
+	}
+
+	/// Calls `f` with each heap that has been mapped so far, while holding its lock.
+	pub fn for_each(&self, mut f: impl FnMut(&mut Heap<C>)) {
+		for shard in self.shards.iter() {
+			if let Some(shard) = unsafe { shard.load(Ordering::Acquire).as_ref() } {
+				f(&mut shard.heap.lock());
+			}
+		}
+	}
+
+	/// Forgets all heaps (see [`Heap::reset`]) and the objects that are cached for each CPU.
+	///
+	/// # Safety
+	/// No other thread may use the shards concurrently.
+	pub unsafe fn reset(&self) {
+		for shard in self.shards.iter() {
+			if let Some(shard) = unsafe { shard.load(Ordering::Acquire).as_mut() } {
+				shard.heap.get_mut().reset();
+				#[cfg(feature = "percpu")]
+				for stack in shard.cache.iter_mut() {
+					unsafe { stack.clear() };
+				}
+			}
+		}
+	}
+
+	#[inline]
+	fn heap(&self, shard: usize, instance: NonNull<Instance<C>>) -> Option<&Futex<Heap<C>>> {
+		let slot = &self.shards[shard];
+		match unsafe { slot.load(Ordering::Acquire).as_ref() } {
+			Some(shard) => Some(&shard.heap),
+			None => Self::map_shard(slot, instance).map(|shard| &shard.heap),
+		}
+	}
+
+	#[cold]
+	fn map_shard(slot: &AtomicPtr<Shard<C>>, instance: NonNull<Instance<C>>) -> Option<&Shard<C>> {
+		let size = NonZero::new((size_of::<Shard<C>>() + 4095) & !4095).unwrap();
+		let mut shard = unsafe {
+			alloc_aligned(
+				size,
+				NonZero::new(align_of::<Shard<C>>()).unwrap(),
+				C::ALLOC_ALIGNED_RETRIES,
+				c"emma:heap-meta",
+			)?
+			.cast::<Shard<C>>()
+		};
+		unsafe {
+			shard.write(Shard {
+				heap: Futex::new(Heap::new(instance)),
+				#[cfg(feature = "percpu")]
+				cache: [const { CpuStack::new() }; CACHED_BINS],
+			});
+			shard.as_mut().heap.get_mut().assign_id();
+		}
+
+		match slot.compare_exchange(ptr::null_mut(), shard.as_ptr(), Ordering::AcqRel, Ordering::Acquire) {
+			Ok(_) => Some(unsafe { shard.as_ref() }),
+			Err(winner) => unsafe {
+				// Another thread mapped a shard for this slot in the meantime, so ours has never been used.
+				let res = munmap(shard.cast(), size);
+				debug_assert!(res.is_ok());
+				Some(&*winner)
+			},
+		}
+	}
+}
+
+/// The per-CPU caches of small objects, which are only used if the libc registered rseq areas that allow for critical
+/// sections (see [`percpu::pop`]), and otherwise always miss. CPUs beyond the number of shards have no cache.
+#[cfg(feature = "percpu")]
+impl<C: Config> Shards<C> {
+	/// Returns the index of the cache for objects of the given (padded) size, if they are cached, which requires them to
+	/// be small objects. Objects are not cached with the `heap-profile` feature, as allocations are sampled while their
+	/// heap is locked.
+	#[inline]
+	pub fn cached_bin(size: usize) -> Option<usize> {
+		(!cfg!(feature = "heap-profile") && size <= CACHED_BINS.min(C::SMALL_OBJECT_BINS) * 8).then(|| size.div_ceil(8) - 1)
+	}
+
+	#[inline]
+	fn cache_offset(bin: usize) -> usize {
+		core::mem::offset_of!(Shard<C>, cache) + bin * size_of::<CpuStack<CACHE_CAPACITY>>()
+	}
+
+	/// Takes an object from the cache of the current CPU for the cached bin `bin`, without locking any heap.
+	#[inline]
+	pub fn take_cached(&self, bin: usize) -> Option<NonNull<u8>> {
+		debug_assert!(bin < CACHED_BINS);
+		unsafe { percpu::pop(&self.shards, Self::cache_offset(bin)) }
+	}
+
+	/// Puts a freed object into the cache of the current CPU for the cached bin `bin`, without locking any heap. Returns
+	/// `false` if the cache is full.
+	#[inline]
+	pub fn put_cached(&self, bin: usize, object: NonNull<u8>) -> bool {
+		debug_assert!(bin < CACHED_BINS);
+		unsafe { percpu::push(&self.shards, Self::cache_offset(bin), CACHE_CAPACITY, object) }
+	}
+
+	/// Allocates a batch of objects for the cached bin `bin` from `heap`, and puts them into the cache of the current CPU
+	/// while it has room, after the cache missed.
+	pub unsafe fn refill(&self, heap: &mut Heap<C>, bin: usize) {
+		let size = NonZero::new((bin + 1) * 8).unwrap();
+		let alignment = NonZero::new(8).unwrap();
+		for _ in 0..CACHE_BATCH {
+			let Some(object) = NonNull::new(unsafe { heap.alloc(size, alignment) }) else {
+				return;
+			};
+			if !self.put_cached(bin, object) {
+				unsafe { Heap::<C>::dealloc(heap.id, Some(&mut heap.remote_frees), object.as_ptr(), size, alignment) };
+				return;
+			}
+		}
+	}
+
+	/// Frees up to `count` objects from the cache of the current CPU for the cached bin `bin` via `heap`.
+	pub unsafe fn drain(&self, heap: &mut Heap<C>, bin: usize, count: usize) {
+		let size = NonZero::new((bin + 1) * 8).unwrap();
+		let alignment = NonZero::new(8).unwrap();
+		for _ in 0..count {
+			let Some(object) = self.take_cached(bin) else {
+				return;
+			};
+			unsafe { Heap::<C>::dealloc(heap.id, Some(&mut heap.remote_frees), object.as_ptr(), size, alignment) };
+		}
+	}
+
+	/// Frees all objects in the caches of the current CPU, so that their pages can be trimmed. The caches of other CPUs
+	/// can only be modified by threads that run on them.
+	pub fn flush_cache(&self, instance: NonNull<Instance<C>>) {
+		if let Some(mut heap) = self.lock(instance) {
+			for bin in 0..CACHED_BINS {
+				unsafe { self.drain(&mut heap, bin, CACHE_CAPACITY) };
+			}
+		}
+	}
+}
+
+/// Returns the shard of the calling thread, which is determined by its CPU with the `percpu` feature, and by its thread
+/// id otherwise.
+#[inline]
+fn current_shard() -> usize {
+	#[cfg(feature = "percpu")]
+	let id = super::percpu::current_cpu();
+	#[cfg(not(feature = "percpu"))]
+	let id = crate::sys::gettid();
+	id as usize % SHARDS
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use crate::emma::DefaultConfig;
+
+	#[test]
+	fn contended_shards_are_skipped() {
+		let shards = Shards::<DefaultConfig>::new();
+		// Nothing is allocated, so the heaps never look at their instance.
+		let first = shards.lock(NonNull::dangling()).unwrap();
+		let second = shards.lock(NonNull::dangling()).unwrap();
+		assert_ne!(first.id, second.id);
+	}
+
+	#[test]
+	#[cfg(all(feature = "percpu", target_arch = "x86_64"))]
+	fn cache_objects_per_cpu() {
+		let shards = Shards::<DefaultConfig>::new();
+		let mut object = 0u64;
+		let object = NonNull::from(&mut object).cast();
+		// The thread may be migrated between mapping the shard of its CPU and using its cache, but not every single time.
+		assert!((0..100).any(|_| {
+			drop(shards.lock(NonNull::dangling()));
+			shards.put_cached(1, object) && shards.take_cached(1) == Some(object)
+		}));
+	}
+}
@@ -0,0 +1,229 @@
+//! A sampling heap profiler in the spirit of tcmalloc: on average, one allocation is sampled every
+//! [`sampling interval`](set_sampling_interval) bytes, and the call stack of each sampled allocation is kept until the
+//! allocation is released again. The live samples can be written out in the gperftools heap profile format, which is
+//! understood by `pprof`.
+//!
+//! All state of the profiler is global, i.e., shared between all [`Emma`](crate::Emma) instances.
+
+use core::fmt::Write;
+use core::ptr::NonNull;
+use core::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+
+use crate::address_map::AddressMap;
+use crate::sync::Futex;
+
+mod stack;
+
+/// The maximum number of return addresses stored per sample.
+const MAXIMUM_STACK_DEPTH: usize = 32;
+pub const DEFAULT_SAMPLING_INTERVAL: usize = 512 * 1024;
+const FILTER_SIZE: usize = 4096;
+/// The number of samples that are copied out of the sample table at once while writing a profile.
+const DUMP_BATCH_SIZE: usize = 16;
+
+static SAMPLING_INTERVAL: AtomicUsize = AtomicUsize::new(DEFAULT_SAMPLING_INTERVAL);
+static SAMPLES: Futex<AddressMap<Sample>> = Futex::new(AddressMap::new());
+/// Counts the live samples per bucket of their addresses, so that deallocating an object that was not sampled (which
+/// is almost all of them) does not need to take the lock on [`SAMPLES`].
+static FILTER: [AtomicU32; FILTER_SIZE] = [const { AtomicU32::new(0) }; FILTER_SIZE];
+
+#[derive(Debug, Copy, Clone)]
+struct Sample {
+	size: usize,
+	depth: usize,
+	stack: [usize; MAXIMUM_STACK_DEPTH],
+}
+
+impl Sample {
+	const EMPTY: Sample = Sample {
+		size: 0,
+		depth: 0,
+		stack: [0; MAXIMUM_STACK_DEPTH],
+	};
+}
+
+#[inline]
+fn filter_bucket(address: usize) -> &'static AtomicU32 {
+	&FILTER[(address >> 4).wrapping_mul(0x9E37_79B9_7F4A_7C15) >> (usize::BITS - FILTER_SIZE.ilog2())]
+}
+
+/// Sets the average number of allocated bytes between two samples. An interval of `0` disables sampling.
+pub fn set_sampling_interval(bytes: usize) {
+	SAMPLING_INTERVAL.store(bytes, Ordering::Relaxed);
+}
+
+/// Decides which allocations of a heap are sampled.
+#[derive(Debug)]
+pub struct Sampler {
+	bytes_until_sample: usize,
+	/// xorshift state used to jitter the distance between samples, which is zero until the first allocation seeds it
+	state: u64,
+}
+
+impl Sampler {
+	pub const fn new() -> Self {
+		Self {
+			bytes_until_sample: 0,
+			state: 0,
+		}
+	}
+
+	/// Accounts for an allocation of `size` bytes and returns whether it should be sampled.
+	#[inline]
+	pub fn should_sample(&mut self, size: usize) -> bool {
+		if let Some(remaining) = self.bytes_until_sample.checked_sub(size) {
+			self.bytes_until_sample = remaining;
+			false
+		} else {
+			self.restart(size)
+		}
+	}
+
+	#[cold]
+	fn restart(&mut self, size: usize) -> bool {
+		let interval = SAMPLING_INTERVAL.load(Ordering::Relaxed);
+		if interval == 0 {
+			// Check back later, in case sampling is re-enabled.
+			self.bytes_until_sample = DEFAULT_SAMPLING_INTERVAL;
+			return false;
+		}
+
+		// The first allocation of a heap draws the distance to the first sample from a seed that differs between heaps, so
+		// that heaps do not sample in lockstep, and then counts towards that distance.
+		let first = self.state == 0;
+		if first {
+			self.state = seed(self as *const Sampler as u64 ^ crate::sys::monotonic_nanos());
+		}
+
+		self.state ^= self.state << 13;
+		self.state ^= self.state >> 7;
+		self.state ^= self.state << 17;
+		self.bytes_until_sample = interval / 2 + (self.state % interval as u64) as usize;
+		if first { self.should_sample(size) } else { true }
+	}
+}
+
+/// Mixes `value` into a non-zero seed for the xorshift generator of a [`Sampler`] (via the finalizer of splitmix64).
+fn seed(value: u64) -> u64 {
+	let mut z = value.wrapping_add(0x9E37_79B9_7F4A_7C15);
+	z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+	z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+	(z ^ (z >> 31)).max(1)
+}
+
+/// Records the current call stack for the freshly allocated object at `ptr`.
+#[inline(never)]
+pub fn record_alloc(ptr: NonNull<u8>, size: usize) {
+	let mut sample = Sample { size, ..Sample::EMPTY };
+	sample.depth = stack::capture(&mut sample.stack);
+
+	let address = ptr.as_ptr() as usize;
+	if SAMPLES.lock().insert(address, sample) {
+		filter_bucket(address).fetch_add(1, Ordering::Relaxed);
+	}
+}
+
+/// Forgets the sample for the object at `ptr`, if there is one, and returns whether there was.
+#[inline]
+pub fn record_dealloc(ptr: *mut u8) -> bool {
+	let address = ptr as usize;
+	filter_bucket(address).load(Ordering::Relaxed) != 0 && forget_sample(address)
+}
+
+#[cold]
+fn forget_sample(address: usize) -> bool {
+	let forgotten = SAMPLES.lock().remove(address).is_some();
+	if forgotten {
+		filter_bucket(address).fetch_sub(1, Ordering::Relaxed);
+	}
+	forgotten
+}
+
+/// Forgets the samples of all objects for which `f` returns `true`, e.g., because they have been released in bulk.
+pub fn forget_samples(mut f: impl FnMut(usize) -> bool) {
+	SAMPLES.lock().retain(|address, _| {
+		if !f(address) {
+			return true;
+		}
+		filter_bucket(address).fetch_sub(1, Ordering::Relaxed);
+		false
+	});
+}
+
+/// Writes all live samples in the gperftools heap profile format.
+///
+/// The lock on the samples is not held while calling into `writer`, which may therefore allocate. As a consequence, the
+/// profile is not an atomic snapshot if other threads (de)allocate memory concurrently.
+pub fn dump<W: Write>(writer: &mut W) -> core::fmt::Result {
+	let (count, bytes) = {
+		let samples = SAMPLES.lock();
+		(0..samples.capacity())
+			.filter_map(|index| samples.slot(index))
+			.fold((0usize, 0usize), |(count, bytes), (_, sample)| {
+				(count + 1, bytes + sample.size)
+			})
+	};
+	writeln!(
+		writer,
+		"heap profile: {count}: {bytes} [{count}: {bytes}] @ heap_v2/{}",
+		SAMPLING_INTERVAL.load(Ordering::Relaxed)
+	)?;
+
+	let mut index = 0;
+	loop {
+		let mut batch = [Sample::EMPTY; DUMP_BATCH_SIZE];
+		let mut batch_len = 0;
+		let done = {
+			let samples = SAMPLES.lock();
+			while batch_len < DUMP_BATCH_SIZE && index < samples.capacity() {
+				if let Some((_, sample)) = samples.slot(index) {
+					batch[batch_len] = *sample;
+					batch_len += 1;
+				}
+				index += 1;
+			}
+			index >= samples.capacity()
+		};
+
+		for sample in &batch[..batch_len] {
+			write!(writer, "1: {} [1: {}] @", sample.size, sample.size)?;
+			for address in &sample.stack[..sample.depth] {
+				write!(writer, " {address:#x}")?;
+			}
+			writeln!(writer)?;
+		}
+
+		if done {
+			break;
+		}
+	}
+
+	writeln!(writer)?;
+	writeln!(writer, "MAPPED_LIBRARIES:")?;
+	write_memory_map(writer)
+}
+
+/// Copies `/proc/self/maps` to `writer`, which `pprof` uses to symbolize the addresses.
+fn write_memory_map<W: Write>(writer: &mut W) -> core::fmt::Result {
+	let Ok(fd) = (unsafe { crate::sys::open_readonly(c"/proc/self/maps") }) else {
+		return Ok(());
+	};
+
+	let mut result = Ok(());
+	let mut buffer = [0u8; 4096];
+	while let Ok(len @ 1..) = unsafe { crate::sys::read(fd, &mut buffer) } {
+		result = buffer[..len].utf8_chunks().try_for_each(|chunk| {
+			writer.write_str(chunk.valid())?;
+			if !chunk.invalid().is_empty() {
+				writer.write_char(char::REPLACEMENT_CHARACTER)?;
+			}
+			Ok(())
+		});
+		if result.is_err() {
+			break;
+		}
+	}
+
+	unsafe { crate::sys::close(fd).unwrap() };
+	result
+}
@@ -0,0 +1,121 @@
+/// The largest distance between two consecutive frame pointers that is considered plausible.
+const MAXIMUM_FRAME_SIZE: usize = 1024 * 1024;
+/// The largest distance between the stack pointer and any frame pointer that is considered plausible.
+const MAXIMUM_STACK_SPAN: usize = 64 * 1024 * 1024;
+
+/// Captures the return addresses of the current call stack by walking the chain of frame pointers, and returns the
+/// number of addresses written to `stack`.
+///
+/// This only yields meaningful results if the program (including the standard library) was compiled with frame
+/// pointers, e.g., via `-C force-frame-pointers=yes`. Code built without them may use the frame pointer register for
+/// other purposes, so frame records are copied via `process_vm_readv`, which fails rather than faulting on memory that
+/// is not readable, such as guard pages and reserved address space. This costs a system call per frame, which only
+/// sampled allocations pay. The walk stops at the first frame record that cannot be read or does not look plausible.
+///
+/// Frame pointers are only walked on x86_64 and aarch64, whose frame records both hold the previous frame pointer
+/// followed by the return address. On other architectures, no addresses are captured.
+#[inline(always)]
+pub fn capture(stack: &mut [usize]) -> usize {
+	match frame_and_stack_pointer() {
+		Some((fp, sp)) => walk(stack, fp, sp, crate::sys::getpid()),
+		None => 0,
+	}
+}
+
+#[cfg(target_arch = "x86_64")]
+#[inline(always)]
+fn frame_and_stack_pointer() -> Option<(usize, usize)> {
+	let fp: usize;
+	let sp: usize;
+	unsafe {
+		core::arch::asm!("mov {}, rbp", out(reg) fp, options(nomem, nostack, preserves_flags));
+		core::arch::asm!("mov {}, rsp", out(reg) sp, options(nomem, nostack, preserves_flags));
+	}
+	Some((fp, sp))
+}
+
+#[cfg(target_arch = "aarch64")]
+#[inline(always)]
+fn frame_and_stack_pointer() -> Option<(usize, usize)> {
+	let fp: usize;
+	let sp: usize;
+	unsafe {
+		core::arch::asm!("mov {}, x29", out(reg) fp, options(nomem, nostack, preserves_flags));
+		core::arch::asm!("mov {}, sp", out(reg) sp, options(nomem, nostack, preserves_flags));
+	}
+	Some((fp, sp))
+}
+
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+#[inline(always)]
+fn frame_and_stack_pointer() -> Option<(usize, usize)> {
+	None
+}
+
+/// Walks the frame records starting at `fp` of the process `pid`, which is the calling one.
+#[inline(always)]
+fn walk(stack: &mut [usize], mut fp: usize, sp: usize, pid: crate::sys::Pid) -> usize {
+	let mut depth = 0;
+	while depth < stack.len() {
+		if fp < sp || fp - sp > MAXIMUM_STACK_SPAN || !fp.is_multiple_of(align_of::<usize>()) {
+			break;
+		}
+
+		// The previous frame pointer, followed by the return address.
+		let mut record = [0usize; 2];
+		let record_bytes =
+			unsafe { core::slice::from_raw_parts_mut(record.as_mut_ptr().cast::<u8>(), size_of_val(&record)) };
+		if crate::sys::read_own_memory(pid, fp, record_bytes) != Ok(size_of_val(&record)) {
+			break;
+		}
+		let [next, return_address] = record;
+		if return_address == 0 {
+			break;
+		}
+		stack[depth] = return_address;
+		depth += 1;
+
+		if next <= fp || next - fp > MAXIMUM_FRAME_SIZE {
+			break;
+		}
+		fp = next;
+	}
+
+	depth
+}
+
+#[cfg(test)]
+mod test {
+	use core::num::NonZero;
+
+	use super::*;
+	use crate::mmap::{MMapFlags, MMapProt, mmap, mprotect, munmap};
+
+	#[test]
+	fn stop_at_unreadable_frame_records() {
+		unsafe {
+			let len = NonZero::new(2 * 4096).unwrap();
+			let mapping = mmap(
+				None,
+				len,
+				MMapProt::READ | MMapProt::WRITE,
+				MMapFlags::PRIVATE | MMapFlags::ANONYMOUS,
+				None,
+				0,
+			)
+			.unwrap();
+			mprotect(mapping.byte_add(4096), NonZero::new(4096).unwrap(), MMapProt::empty()).unwrap();
+
+			// The last frame record on the readable page links to one on the page that cannot be read.
+			let base = mapping.as_ptr() as usize;
+			let fp = base + 4096 - 16;
+			(fp as *mut [usize; 2]).write([base + 4096 + 64, 0x1234]);
+
+			let mut stack = [0; 4];
+			assert_eq!(walk(&mut stack, fp, base, crate::sys::getpid()), 1);
+			assert_eq!(stack[0], 0x1234);
+
+			munmap(mapping, len).unwrap();
+		}
+	}
+}
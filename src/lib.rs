@@ -9,12 +9,30 @@
 
 #![cfg_attr(not(test), no_std)]
 #![cfg_attr(feature = "tls", feature(thread_local))]
+#![cfg_attr(feature = "allocator-api", feature(allocator_api))]
+
+#[cfg(all(feature = "tls", feature = "std-tls"))]
+compile_error!("The `tls` and `std-tls` features cannot be combined; use `std` for thread-exit cleanup with `tls`.");
+#[cfg(all(thread_heaps, feature = "sharded"))]
+compile_error!(
+	"The `sharded` (and `percpu`) feature cannot be combined with `tls`, `std` or `std-tls`, which already gives each thread a heap."
+);
 
 extern crate alloc;
+#[cfg(all(any(feature = "std", feature = "std-tls"), not(test)))]
+extern crate std;
 
+mod address_map;
+#[cfg(feature = "heap-profile")]
+mod heap_profile;
 mod mmap;
 mod sync;
 mod sys;
+#[cfg(feature = "trace")]
+pub mod trace;
 
 mod emma;
-pub use emma::{DefaultEmma, Emma};
+pub use emma::{
+	CompactConfig, Config, DefaultConfig, DefaultEmma, Emma, HugeObjectCacheStats, HugePagePolicy, HugePageStats,
+	HugetlbPageSize, Region, ResidentBytes, Tier,
+};
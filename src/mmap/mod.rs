@@ -1,10 +1,13 @@
 #![allow(dead_code)]
 
+mod reservation;
 mod syscalls;
-use core::ffi::c_void;
+use core::ffi::{CStr, c_void};
 use core::num::NonZero;
 use core::ptr::NonNull;
+use core::sync::atomic::{AtomicBool, Ordering};
 
+pub use self::reservation::Reservation;
 pub use self::syscalls::*;
 
 #[inline]
@@ -70,10 +73,10 @@ unsafe fn move_mapping_up(
 unsafe fn mmap_aligned_rec(
 	size: NonZero<usize>,
 	alignment: NonZero<usize>,
+	prot: MMapProt,
 	recursive_retries: usize,
 ) -> Option<NonNull<c_void>> {
 	unsafe {
-		let prot = MMapProt::READ | MMapProt::WRITE;
 		let flags = MMapFlags::PRIVATE | MMapFlags::ANONYMOUS | MMapFlags::NORESERVE;
 		let mapping = mmap(None, size, prot, flags, None, 0).ok()?;
 
@@ -96,7 +99,7 @@ unsafe fn mmap_aligned_rec(
 			}
 
 			if recursive_retries > 0 {
-				let ret = mmap_aligned_rec(size, alignment, recursive_retries - 1);
+				let ret = mmap_aligned_rec(size, alignment, prot, recursive_retries - 1);
 				munmap(mapping, size).unwrap();
 				ret
 			} else {
@@ -111,22 +114,25 @@ unsafe fn mmap_aligned_rec(
 }
 
 /// Tries to allocate suitably aligned storage from the OS. As this may fail initially, the function will retry up to
-/// `recursive_retries` times.
+/// `recursive_retries` times. The mapping is labeled with `name` (see [`set_name`]).
 ///
 /// This function allocates virtual memory, not physical memory.
 pub unsafe fn alloc_aligned(
 	size: NonZero<usize>,
 	alignment: NonZero<usize>,
 	recursive_retries: usize,
+	name: &CStr,
 ) -> Option<NonNull<c_void>> {
 	debug_assert!(alignment.is_power_of_two());
 	debug_assert_eq!(size.get() & (alignment.get() - 1), 0);
 
-	unsafe { mmap_aligned_rec(size, alignment, recursive_retries) }
+	let mapping = unsafe { mmap_aligned_rec(size, alignment, MMapProt::READ | MMapProt::WRITE, recursive_retries)? };
+	unsafe { set_name(mapping, size, name) };
+	Some(mapping)
 }
 
-/// Tries to allocate storage at the exact location provided.
-pub unsafe fn alloc_at(address: NonNull<c_void>, size: NonZero<usize>) -> Option<NonNull<c_void>> {
+/// Tries to allocate storage at the exact location provided. The mapping is labeled with `name` (see [`set_name`]).
+pub unsafe fn alloc_at(address: NonNull<c_void>, size: NonZero<usize>, name: &CStr) -> Option<NonNull<c_void>> {
 	let prot = MMapProt::READ | MMapProt::WRITE;
 	let flags = MMapFlags::PRIVATE | MMapFlags::ANONYMOUS | MMapFlags::NORESERVE | MMapFlags::FIXED_NOREPLACE;
 	let ret = unsafe { mmap(Some(address), size, prot, flags, None, 0).ok()? };
@@ -134,9 +140,120 @@ pub unsafe fn alloc_at(address: NonNull<c_void>, size: NonZero<usize>) -> Option
 		ret, address,
 		"Emma is not compatible with linux kernels that do not recognize MAP_FIXED_NOREPLACE (pre 4.17)."
 	);
+	unsafe { set_name(ret, size, name) };
 	Some(ret)
 }
 
+/// Resizes a mapping obtained from [`alloc_aligned`], moving it elsewhere if it cannot be resized in place. Moving
+/// transfers the page tables, so no memory is copied. The mapping keeps its name.
+///
+/// To move the mapping, a range that satisfies `alignment` is reserved first, for which up to `recursive_retries`
+/// retries are made (see [`alloc_aligned`]). `claim` is called with the reserved range before the mapping is moved into
+/// it, and may veto the move by returning `false`, in which case the mapping is left as it is.
+pub unsafe fn realloc_aligned(
+	address: NonNull<c_void>,
+	old_size: NonZero<usize>,
+	new_size: NonZero<usize>,
+	alignment: NonZero<usize>,
+	recursive_retries: usize,
+	claim: impl FnOnce(NonNull<c_void>) -> bool,
+) -> Option<NonNull<c_void>> {
+	debug_assert!(alignment.is_power_of_two());
+
+	unsafe {
+		if mremap_resize(address, old_size, new_size).is_ok() {
+			return Some(address);
+		}
+
+		let target = mmap_aligned_rec(new_size, alignment, MMapProt::empty(), recursive_retries)?;
+		if claim(target) && mremap_fixed(address, old_size, new_size, target).is_ok() {
+			Some(target)
+		} else {
+			munmap(target, new_size).unwrap();
+			None
+		}
+	}
+}
+
+/// Tries to allocate suitably aligned storage that is backed by huge pages of `page_size` (2 MiB or 1 GiB) from the
+/// hugetlb pool. `size` must be a multiple of `page_size`. Fails if the pool does not hold enough free huge pages.
+///
+/// Unlike [`alloc_aligned`], this reserves physical memory up front, so that accessing the mapping cannot fail later
+/// on. Hugetlb mappings cannot be named.
+pub unsafe fn alloc_aligned_hugetlb(
+	size: NonZero<usize>,
+	alignment: NonZero<usize>,
+	page_size: NonZero<usize>,
+	recursive_retries: usize,
+) -> Option<NonNull<c_void>> {
+	debug_assert!(alignment.is_power_of_two());
+	debug_assert_eq!(size.get() % page_size.get(), 0);
+
+	let prot = MMapProt::READ | MMapProt::WRITE;
+	let flags = MMapFlags::PRIVATE
+		| MMapFlags::ANONYMOUS
+		| MMapFlags::HUGETLB
+		| match page_size.get() {
+			0x20_0000 => MMapFlags::HUGE_2MB,
+			0x4000_0000 => MMapFlags::HUGE_1GB,
+			_ => panic!("unsupported huge page size"),
+		};
+	if alignment <= page_size {
+		// hugetlb mappings are always aligned to their page size
+		return unsafe { mmap(None, size, prot, flags, None, 0).ok() };
+	}
+
+	// Find an aligned range that is currently free. The range is not kept reserved, as failing to replace it via
+	// `MAP_FIXED` (e.g., because the hugetlb pool is empty) may leave a hole in the reservation that is then unsafe to
+	// unmap. Instead, we retry if another mapping is placed into the range before we get to it.
+	unsafe {
+		let reservation_size = size.checked_add(alignment.get())?;
+		let reservation = mmap(
+			None,
+			reservation_size,
+			MMapProt::empty(),
+			MMapFlags::PRIVATE | MMapFlags::ANONYMOUS | MMapFlags::NORESERVE,
+			None,
+			0,
+		)
+		.ok()?;
+		munmap(reservation, reservation_size).unwrap();
+
+		let address = reservation.map_addr(|addr| addr.get().next_multiple_of(alignment.get()).try_into().unwrap());
+		match mmap(Some(address), size, prot, flags | MMapFlags::FIXED_NOREPLACE, None, 0) {
+			Ok(mapping) => {
+				assert_eq!(
+					mapping, address,
+					"Emma is not compatible with linux kernels that do not recognize MAP_FIXED_NOREPLACE (pre 4.17)."
+				);
+				Some(mapping)
+			}
+			Err(::syscalls::Errno::EEXIST) if recursive_retries > 0 => {
+				alloc_aligned_hugetlb(size, alignment, page_size, recursive_retries - 1)
+			}
+			Err(_) => None,
+		}
+	}
+}
+
+/// Set once the kernel rejected naming a mapping, so that we do not keep issuing syscalls that are bound to fail.
+static ANON_NAMES_UNSUPPORTED: AtomicBool = AtomicBool::new(false);
+
+/// Labels an anonymous mapping, so that it shows up as `[anon:<name>]` in `/proc/<pid>/maps` and `/proc/<pid>/smaps`.
+///
+/// Does nothing if the kernel was built without `CONFIG_ANON_VMA_NAME` (or predates 5.17).
+pub unsafe fn set_name(address: NonNull<c_void>, size: NonZero<usize>, name: &CStr) {
+	if ANON_NAMES_UNSUPPORTED.load(Ordering::Relaxed) {
+		return;
+	}
+
+	match unsafe { prctl_set_vma_anon_name(address, size.get(), name) } {
+		Ok(()) => (),
+		Err(::syscalls::Errno::EINVAL) => ANON_NAMES_UNSUPPORTED.store(true, Ordering::Relaxed),
+		Err(err) => debug_assert!(false, "naming a mapping failed: {err}"),
+	}
+}
+
 #[cfg(test)]
 mod test {
 	use super::*;
@@ -146,7 +263,7 @@ mod test {
 		let alignment = NonZero::new(alignment).unwrap();
 
 		unsafe {
-			let region = alloc_aligned(size, alignment, 3).unwrap();
+			let region = alloc_aligned(size, alignment, 3, c"emma:test").unwrap();
 			munmap(region, size).unwrap();
 		}
 	}
@@ -165,4 +282,48 @@ mod test {
 	fn mmap_aligned_100x1g() {
 		mmap_aligned_and_unmap(100, 1024 * 1024 * 1024);
 	}
+
+	#[test]
+	fn realloc_aligned_moves() {
+		let size = NonZero::new(2 * 1024 * 1024).unwrap();
+		let new_size = NonZero::new(8 * 1024 * 1024).unwrap();
+		let alignment = NonZero::new(2 * 1024 * 1024).unwrap();
+		unsafe {
+			let region = alloc_aligned(size, alignment, 3, c"emma:test").unwrap();
+			region.cast::<usize>().write(42);
+			region.byte_add(size.get() - 8).cast::<usize>().write(43);
+
+			// Prevent the region from growing in place.
+			let blocker = alloc_at(region.byte_add(size.get()), NonZero::new(4096).unwrap(), c"emma:test");
+
+			let moved = realloc_aligned(region, size, new_size, alignment, 3, |_| true).unwrap();
+			assert_ne!(moved, region);
+			assert_eq!(moved.as_ptr() as usize % alignment.get(), 0);
+			assert_eq!(moved.cast::<usize>().read(), 42);
+			assert_eq!(moved.byte_add(size.get() - 8).cast::<usize>().read(), 43);
+			moved.byte_add(new_size.get() - 8).cast::<usize>().write(44);
+
+			munmap(moved, new_size).unwrap();
+			if let Some(blocker) = blocker {
+				munmap(blocker, NonZero::new(4096).unwrap()).unwrap();
+			}
+		}
+	}
+
+	#[test]
+	fn named_mapping() {
+		let size = NonZero::new(16 * 4096).unwrap();
+		unsafe {
+			let region = alloc_aligned(size, NonZero::new(4096).unwrap(), 3, c"emma:named-test").unwrap();
+			let maps = std::fs::read_to_string("/proc/self/maps").unwrap();
+			let line = maps
+				.lines()
+				.find(|line| line.starts_with(&std::format!("{:x}-", region.as_ptr() as usize)))
+				.unwrap();
+			if !ANON_NAMES_UNSUPPORTED.load(Ordering::Relaxed) {
+				assert!(line.ends_with("[anon:emma:named-test]"), "{line}");
+			}
+			munmap(region, size).unwrap();
+		}
+	}
 }
@@ -0,0 +1,146 @@
+use core::ffi::{CStr, c_void};
+use core::num::NonZero;
+use core::ptr::NonNull;
+
+use super::{MMapFlags, MMapProt, mmap, mmap_aligned_rec, mprotect, munmap, set_name};
+
+/// A range of address space that is reserved up front as inaccessible (`PROT_NONE`) memory, from which aligned regions
+/// are carved by making them accessible with a single `mprotect` call.
+///
+/// The reservation grows in chunks of `chunk_size` bytes. Each chunk is placed directly behind the previous one if
+/// possible, so that carved regions are usually contiguous, which also allows the kernel to merge them into a single
+/// VMA. Regions are never returned to the reservation, but may be unmapped as usual.
+#[derive(Debug)]
+pub struct Reservation {
+	chunk_size: NonZero<usize>,
+	/// The start of the unused part of the current chunk.
+	next: usize,
+	/// The end of the current chunk.
+	end: usize,
+	/// How often reserving an aligned chunk is retried (see [`alloc_aligned`](super::alloc_aligned)).
+	recursive_retries: usize,
+}
+
+impl Reservation {
+	/// Creates an empty reservation, which reserves chunks of `chunk_size` bytes once regions are carved from it,
+	/// retrying up to `recursive_retries` times to obtain an aligned chunk. `chunk_size` must be a power of two.
+	pub const fn new(chunk_size: NonZero<usize>, recursive_retries: usize) -> Self {
+		assert!(chunk_size.is_power_of_two());
+
+		Self {
+			chunk_size,
+			next: 0,
+			end: 0,
+			recursive_retries,
+		}
+	}
+
+	/// Carves a region of `size` bytes, aligned to `alignment`, from the reservation and makes it readable and
+	/// writable. The region is labeled with `name` (see [`set_name`]). `alignment` may not exceed the chunk size.
+	///
+	/// This function allocates virtual memory, not physical memory.
+	pub unsafe fn carve(
+		&mut self,
+		size: NonZero<usize>,
+		alignment: NonZero<usize>,
+		name: &CStr,
+	) -> Option<NonNull<c_void>> {
+		let region = unsafe { self.take(size, alignment)? };
+		unsafe { Self::commit(region, size, name)? };
+		Some(region)
+	}
+
+	/// Takes a region of `size` bytes, aligned to `alignment`, from the reservation, which remains inaccessible until it
+	/// is [committed](Reservation::commit). This only requires a system call if the reservation must grow, so that a
+	/// reservation shared behind a lock can be committed from after releasing the lock. `alignment` may not exceed the
+	/// chunk size.
+	pub unsafe fn take(&mut self, size: NonZero<usize>, alignment: NonZero<usize>) -> Option<NonNull<c_void>> {
+		debug_assert!(alignment.is_power_of_two());
+		debug_assert!(alignment <= self.chunk_size);
+
+		let mut start = self.next.next_multiple_of(alignment.get());
+		if start + size.get() > self.end {
+			unsafe { self.grow(size)? };
+			start = self.next.next_multiple_of(alignment.get());
+		}
+		self.next = start + size.get();
+
+		Some(unsafe { NonNull::new_unchecked(start as *mut c_void) })
+	}
+
+	/// Makes a region obtained from [`take`](Reservation::take) readable and writable, and labels it with `name` (see
+	/// [`set_name`]). If this fails, the region remains reserved, but is never handed out again.
+	pub unsafe fn commit(region: NonNull<c_void>, size: NonZero<usize>, name: &CStr) -> Option<()> {
+		unsafe { mprotect(region, size, MMapProt::READ | MMapProt::WRITE).ok()? };
+		unsafe { set_name(region, size, name) };
+		Some(())
+	}
+
+	/// Reserves a new chunk that is large enough for at least `size` bytes.
+	#[cold]
+	unsafe fn grow(&mut self, size: NonZero<usize>) -> Option<()> {
+		let chunk_size = NonZero::new(size.get().checked_next_multiple_of(self.chunk_size.get())?)?;
+		let flags = MMapFlags::PRIVATE | MMapFlags::ANONYMOUS | MMapFlags::NORESERVE;
+
+		// Try to extend the current chunk first.
+		if let Some(end) = NonNull::new(self.end as *mut c_void)
+			&& let Ok(chunk) = unsafe {
+				mmap(
+					Some(end),
+					chunk_size,
+					MMapProt::empty(),
+					flags | MMapFlags::FIXED_NOREPLACE,
+					None,
+					0,
+				)
+			} {
+			if chunk == end {
+				self.end += chunk_size.get();
+				return Some(());
+			}
+			// Pre 4.17 kernels treat `MAP_FIXED_NOREPLACE` as a hint only.
+			unsafe { munmap(chunk, chunk_size).unwrap() };
+		}
+
+		// The rest of the current chunk is abandoned. It remains reserved, but costs nothing but address space.
+		let chunk = unsafe { mmap_aligned_rec(chunk_size, self.chunk_size, MMapProt::empty(), self.recursive_retries)? };
+		self.next = chunk.as_ptr() as usize;
+		self.end = self.next + chunk_size.get();
+		Some(())
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn carve_contiguous_regions() {
+		const REGION_SIZE: usize = 64 * 1024;
+
+		let mut reservation = Reservation::new(NonZero::new(4 * REGION_SIZE).unwrap(), 3);
+		let size = NonZero::new(REGION_SIZE).unwrap();
+		let regions: std::vec::Vec<_> = (0..10)
+			.map(|_| unsafe { reservation.carve(size, size, c"emma:test").unwrap() })
+			.collect();
+
+		for region in regions.iter() {
+			assert_eq!(region.as_ptr() as usize % REGION_SIZE, 0);
+			unsafe {
+				region.cast::<u8>().write_bytes(0x42, REGION_SIZE);
+			}
+		}
+		// Within a chunk, regions are contiguous. Whether the chunks are as well depends on the other tests.
+		for chunk in regions.chunks(4) {
+			assert!(
+				chunk
+					.windows(2)
+					.all(|w| w[0].as_ptr() as usize + REGION_SIZE == w[1].as_ptr() as usize)
+			);
+		}
+
+		for &region in regions.iter() {
+			unsafe { munmap(region, size).unwrap() };
+		}
+	}
+}
@@ -0,0 +1,11 @@
+use core::ffi::c_void;
+use core::ptr::NonNull;
+
+/// `int mincore(void addr[.length], size_t length, unsigned char *vec);`
+#[inline]
+pub unsafe fn mincore(addr: NonNull<c_void>, length: usize, vec: &mut [u8]) -> Result<(), syscalls::Errno> {
+	debug_assert!(vec.len() >= length.div_ceil(4096));
+	syscalls::syscall!(syscalls::Sysno::mincore, addr.as_ptr(), length, vec.as_mut_ptr()).map(|ret| {
+		debug_assert_eq!(ret, 0);
+	})
+}
@@ -2,11 +2,17 @@
 #![allow(unused_imports)]
 
 mod madvise;
+mod mincore;
 mod mmap;
+mod mprotect;
 mod mremap;
 mod munmap;
+mod prctl;
 
 pub use madvise::{MAdviseAdvice, madvise};
+pub use mincore::mincore;
 pub use mmap::{MMapFlags, MMapProt, mmap};
-pub use mremap::mremap_resize;
+pub use mprotect::mprotect;
+pub use mremap::{mremap_fixed, mremap_move, mremap_resize};
 pub use munmap::munmap;
+pub use prctl::prctl_set_vma_anon_name;
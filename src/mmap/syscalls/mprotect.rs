@@ -0,0 +1,13 @@
+use core::ffi::c_void;
+use core::num::NonZero;
+use core::ptr::NonNull;
+
+use super::MMapProt;
+
+/// `int mprotect(void addr[.len], size_t len, int prot);`
+#[inline]
+pub unsafe fn mprotect(addr: NonNull<c_void>, len: NonZero<usize>, prot: MMapProt) -> Result<(), syscalls::Errno> {
+	syscalls::syscall!(syscalls::Sysno::mprotect, addr.as_ptr(), len.get(), prot.bits()).map(|ret| {
+		debug_assert_eq!(ret, 0);
+	})
+}
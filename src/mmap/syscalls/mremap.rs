@@ -19,3 +19,44 @@ pub unsafe fn mremap_resize(
 		debug_assert_eq!(ret as *const c_void, address.as_ptr().cast_const());
 	})
 }
+
+/// `void *mremap(void old_address[.old_size], size_t old_size, size_t new_size, MREMAP_MAYMOVE);`
+#[inline]
+pub unsafe fn mremap_move(
+	address: NonNull<c_void>,
+	old_size: NonZero<usize>,
+	new_size: NonZero<usize>,
+) -> Result<NonNull<c_void>, syscalls::Errno> {
+	syscalls::syscall!(
+		syscalls::Sysno::mremap,
+		address.as_ptr(),
+		old_size.get(),
+		new_size.get(),
+		linux_raw_sys::general::MREMAP_MAYMOVE
+	)
+	.map(|val| NonNull::new(val as *mut c_void).expect("Successfull remapping should always return nonnull pointer"))
+}
+
+/// `void *mremap(void old_address[.old_size], size_t old_size, size_t new_size, MREMAP_MAYMOVE | MREMAP_FIXED, void
+/// new_address[.new_size]);`
+///
+/// Any mapping in the target range is replaced.
+#[inline]
+pub unsafe fn mremap_fixed(
+	address: NonNull<c_void>,
+	old_size: NonZero<usize>,
+	new_size: NonZero<usize>,
+	new_address: NonNull<c_void>,
+) -> Result<(), syscalls::Errno> {
+	syscalls::syscall!(
+		syscalls::Sysno::mremap,
+		address.as_ptr(),
+		old_size.get(),
+		new_size.get(),
+		linux_raw_sys::general::MREMAP_MAYMOVE | linux_raw_sys::general::MREMAP_FIXED,
+		new_address.as_ptr()
+	)
+	.map(|ret| {
+		debug_assert_eq!(ret as *const c_void, new_address.as_ptr().cast_const());
+	})
+}
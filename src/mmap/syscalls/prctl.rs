@@ -0,0 +1,18 @@
+use core::ffi::{CStr, c_void};
+use core::ptr::NonNull;
+
+/// `int prctl(PR_SET_VMA, PR_SET_VMA_ANON_NAME, void addr[.size], size_t size, const char *name);`
+#[inline]
+pub unsafe fn prctl_set_vma_anon_name(addr: NonNull<c_void>, size: usize, name: &CStr) -> Result<(), syscalls::Errno> {
+	syscalls::syscall!(
+		syscalls::Sysno::prctl,
+		linux_raw_sys::prctl::PR_SET_VMA,
+		linux_raw_sys::prctl::PR_SET_VMA_ANON_NAME,
+		addr.as_ptr(),
+		size,
+		name.as_ptr()
+	)
+	.map(|ret| {
+		debug_assert_eq!(ret, 0);
+	})
+}
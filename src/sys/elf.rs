@@ -0,0 +1,156 @@
+//! Looks up symbols in the ELF objects that the kernel maps into every process, i.e., the vDSO and the dynamic loader.
+//! Both are located via the auxiliary vector, which is read from `/proc/self/auxv` as there may be no libc to ask.
+
+/// Returns the value of the entry `key` of the auxiliary vector, unless it is missing or zero.
+pub fn auxv(key: u32) -> Option<usize> {
+	use linux_raw_sys::auxvec::AT_NULL;
+
+	// The auxiliary vector holds a few dozen pairs of words, which fit into the buffer many times over.
+	let mut buffer = [0usize; 256];
+	let bytes =
+		unsafe { core::slice::from_raw_parts_mut(buffer.as_mut_ptr().cast::<u8>(), buffer.len() * size_of::<usize>()) };
+	let fd = unsafe { super::open_readonly(c"/proc/self/auxv").ok()? };
+	let mut len = 0;
+	while len < bytes.len() {
+		match unsafe { super::read(fd, &mut bytes[len..]) } {
+			Ok(0) | Err(_) => break,
+			Ok(read) => len += read,
+		}
+	}
+	let _ = unsafe { super::close(fd) };
+
+	buffer[..len / size_of::<usize>()]
+		.chunks_exact(2)
+		.take_while(|entry| entry[0] != AT_NULL as usize)
+		.find(|entry| entry[0] == key as usize)
+		.map(|entry| entry[1])
+		.filter(|&value| value != 0)
+}
+
+/// Returns the address of the symbol `name` that the dynamic loader (`AT_BASE`) defines, which is where glibc keeps the
+/// variables that it shares with other code about the threads it manages. Statically linked programs have no dynamic
+/// loader.
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+pub fn interpreter_symbol(name: &[u8]) -> Option<usize> {
+	let base = auxv(linux_raw_sys::auxvec::AT_BASE)?;
+	unsafe { lookup(base, name) }
+}
+
+/// There is no lookup on other architectures.
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+pub fn interpreter_symbol(_name: &[u8]) -> Option<usize> {
+	None
+}
+
+/// The tag of the GNU-style hash table, which `linux-raw-sys` does not define.
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+const DT_GNU_HASH: u32 = 0x6fff_fef5;
+
+/// Looks up the symbol `name` in the dynamic symbol table of the ELF object whose header is mapped at `base`.
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+pub unsafe fn lookup(base: usize, name: &[u8]) -> Option<usize> {
+	use linux_raw_sys::elf_uapi::{
+		DT_HASH, DT_NULL, DT_STRTAB, DT_SYMTAB, Elf64_Dyn, Elf64_Ehdr, Elf64_Phdr, Elf64_Sym, PT_DYNAMIC, PT_LOAD,
+	};
+
+	let header = unsafe { &*(base as *const Elf64_Ehdr) };
+	if header.e_ident[..4] != *b"\x7fELF" {
+		return None;
+	}
+	let program_headers = unsafe {
+		core::slice::from_raw_parts(
+			(base + header.e_phoff as usize) as *const Elf64_Phdr,
+			header.e_phnum as usize,
+		)
+	};
+
+	// The header is mapped as part of the first loadable segment, whose virtual address determines the bias of all
+	// others.
+	let mut bias = None;
+	let mut dynamic = None;
+	for program_header in program_headers {
+		match program_header.p_type {
+			PT_LOAD if bias.is_none() => {
+				bias = Some(
+					base
+						.wrapping_add(program_header.p_offset as usize)
+						.wrapping_sub(program_header.p_vaddr as usize),
+				)
+			}
+			PT_DYNAMIC => dynamic = Some(program_header.p_vaddr as usize),
+			_ => {}
+		}
+	}
+	let bias = bias?;
+	let mut dynamic = bias.wrapping_add(dynamic?) as *const Elf64_Dyn;
+
+	let (mut hash, mut gnu_hash, mut symbols, mut strings) = (None, None, None, None);
+	loop {
+		let entry = unsafe { &*dynamic };
+		// The dynamic loader relocates its own dynamic section, while the vDSO's is left as it is.
+		let address = unsafe { entry.d_un.d_ptr } as usize;
+		let address = if address >= base {
+			address
+		} else {
+			bias.wrapping_add(address)
+		};
+		match entry.d_tag as u32 {
+			DT_NULL => break,
+			DT_HASH => hash = Some(address as *const u32),
+			DT_GNU_HASH => gnu_hash = Some(address as *const u32),
+			DT_SYMTAB => symbols = Some(address as *const Elf64_Sym),
+			DT_STRTAB => strings = Some(address as *const u8),
+			_ => {}
+		}
+		dynamic = unsafe { dynamic.add(1) };
+	}
+	let (symbols, strings) = (symbols?, strings?);
+	let matches = |symbol: &Elf64_Sym| {
+		symbol.st_shndx != 0
+			&& unsafe { core::ffi::CStr::from_ptr(strings.add(symbol.st_name as usize).cast()) }.to_bytes() == name
+	};
+
+	let symbol = if let Some(hash) = hash {
+		// The second word of the hash table is the number of symbols.
+		let symbols = unsafe { core::slice::from_raw_parts(symbols, hash.add(1).read() as usize) };
+		symbols.iter().find(|symbol| matches(symbol))
+	} else {
+		unsafe { gnu_lookup(gnu_hash?, symbols, name, matches) }
+	}?;
+	Some(bias.wrapping_add(symbol.st_value as usize))
+}
+
+/// Looks up `name` in a GNU-style hash table, which does not state the number of symbols, but chains the symbols of
+/// each bucket, marking the last one of each chain by setting the lowest bit of its hash.
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+unsafe fn gnu_lookup<'a>(
+	table: *const u32,
+	symbols: *const linux_raw_sys::elf_uapi::Elf64_Sym,
+	name: &[u8],
+	matches: impl Fn(&linux_raw_sys::elf_uapi::Elf64_Sym) -> bool,
+) -> Option<&'a linux_raw_sys::elf_uapi::Elf64_Sym> {
+	let hash = name
+		.iter()
+		.fold(5381u32, |hash, &byte| hash.wrapping_mul(33).wrapping_add(byte as u32));
+	unsafe {
+		let [buckets, first_symbol, bloom_words, _bloom_shift] = table.cast::<[u32; 4]>().read();
+		let bucket_table = table.add(4 + bloom_words as usize * (size_of::<usize>() / size_of::<u32>()));
+		let chains = bucket_table.add(buckets as usize);
+
+		let mut index = bucket_table.add((hash % buckets) as usize).read();
+		if index < first_symbol {
+			return None;
+		}
+		loop {
+			let chain = chains.add((index - first_symbol) as usize).read();
+			let symbol = &*symbols.add(index as usize);
+			if chain | 1 == hash | 1 && matches(symbol) {
+				return Some(symbol);
+			}
+			if chain & 1 != 0 {
+				return None;
+			}
+			index += 1;
+		}
+	}
+}
@@ -1,6 +1,9 @@
 #![allow(dead_code)]
 #![allow(unused_imports)]
 
+mod elf;
 mod syscalls;
+mod vdso;
 
+pub use elf::interpreter_symbol;
 pub use syscalls::*;
@@ -1,4 +1,4 @@
-use core::ffi::c_int;
+use core::ffi::{c_int, c_void};
 
 use const_format::assertc_eq;
 
@@ -28,3 +28,187 @@ pub unsafe fn kill(pid: c_int, sig: c_int) -> Result<(), syscalls::Errno> {
 		debug_assert_eq!(ret, 0);
 	})
 }
+
+/// `int openat(AT_FDCWD, const char *pathname, O_RDONLY | O_CLOEXEC);`
+pub unsafe fn open_readonly(path: &core::ffi::CStr) -> Result<c_int, syscalls::Errno> {
+	unsafe {
+		syscalls::syscall!(
+			syscalls::Sysno::openat,
+			linux_raw_sys::general::AT_FDCWD,
+			path.as_ptr(),
+			linux_raw_sys::general::O_RDONLY | linux_raw_sys::general::O_CLOEXEC
+		)
+		.map(|fd| fd as c_int)
+	}
+}
+
+/// `ssize_t read(int fd, void buf[.count], size_t count);`
+pub unsafe fn read(fd: c_int, buf: &mut [u8]) -> Result<usize, syscalls::Errno> {
+	unsafe { syscalls::syscall!(syscalls::Sysno::read, fd, buf.as_mut_ptr(), buf.len()) }
+}
+
+/// `ssize_t write(int fd, const void buf[.count], size_t count);`
+pub unsafe fn write(fd: c_int, buf: &[u8]) -> Result<usize, syscalls::Errno> {
+	unsafe { syscalls::syscall!(syscalls::Sysno::write, fd, buf.as_ptr(), buf.len()) }
+}
+
+/// `int close(int fd);`
+pub unsafe fn close(fd: c_int) -> Result<(), syscalls::Errno> {
+	unsafe {
+		syscalls::syscall!(syscalls::Sysno::close, fd).map(|ret| {
+			debug_assert_eq!(ret, 0);
+		})
+	}
+}
+
+/// Copies the memory at `address` of the calling process into `buf`, which fails with `EFAULT` instead of faulting if
+/// the memory is not readable. Returns the number of bytes copied, which may be short if only the start of the memory
+/// is readable.
+///
+/// `ssize_t process_vm_readv(getpid(), &local, 1, &remote, 1, 0);`
+pub fn read_own_memory(pid: Pid, address: usize, buf: &mut [u8]) -> Result<usize, syscalls::Errno> {
+	let local = linux_raw_sys::general::iovec {
+		iov_base: buf.as_mut_ptr().cast(),
+		iov_len: buf.len() as _,
+	};
+	let remote = linux_raw_sys::general::iovec {
+		iov_base: address as *mut _,
+		iov_len: buf.len() as _,
+	};
+	unsafe {
+		syscalls::syscall!(
+			syscalls::Sysno::process_vm_readv,
+			pid,
+			&local as *const linux_raw_sys::general::iovec,
+			1,
+			&remote as *const linux_raw_sys::general::iovec,
+			1,
+			0
+		)
+	}
+}
+
+/// Returns the time of `CLOCK_MONOTONIC_COARSE` in milliseconds, which is cheap to query but only as precise as the
+/// scheduler tick.
+pub fn monotonic_coarse_millis() -> u64 {
+	let time = clock_gettime(linux_raw_sys::general::CLOCK_MONOTONIC_COARSE);
+	time.tv_sec as u64 * 1000 + time.tv_nsec as u64 / 1_000_000
+}
+
+/// Returns the time of `CLOCK_MONOTONIC` in nanoseconds.
+pub fn monotonic_nanos() -> u64 {
+	let time = clock_gettime(linux_raw_sys::general::CLOCK_MONOTONIC);
+	time.tv_sec as u64 * 1_000_000_000 + time.tv_nsec as u64
+}
+
+/// `int clock_gettime(clockid_t clockid, struct timespec *tp);`, answered by the vDSO if possible.
+fn clock_gettime(clock: u32) -> linux_raw_sys::general::__kernel_timespec {
+	let mut time = linux_raw_sys::general::__kernel_timespec { tv_sec: 0, tv_nsec: 0 };
+	if let Some(clock_gettime) = super::vdso::clock_gettime() {
+		let ret = unsafe { clock_gettime(clock as c_int, &mut time) };
+		debug_assert_eq!(ret, 0);
+	} else {
+		let ret = unsafe {
+			syscalls::syscall!(
+				syscalls::Sysno::clock_gettime,
+				clock,
+				&mut time as *mut linux_raw_sys::general::__kernel_timespec
+			)
+		};
+		debug_assert!(ret.is_ok());
+	}
+	time
+}
+
+/// `int getcpu(unsigned int *cpu, NULL, NULL);`, answered by the vDSO if possible.
+pub fn getcpu() -> u32 {
+	let mut cpu = 0u32;
+	if let Some(getcpu) = super::vdso::getcpu() {
+		let ret = unsafe { getcpu(&mut cpu, core::ptr::null_mut(), core::ptr::null_mut()) };
+		debug_assert_eq!(ret, 0);
+	} else {
+		let ret = unsafe { syscalls::syscall!(syscalls::Sysno::getcpu, &mut cpu as *mut u32, 0, 0) };
+		debug_assert!(ret.is_ok());
+	}
+	cpu
+}
+
+/// `int rseq(struct rseq *rseq, u32 rseq_len, int flags, u32 sig);`
+pub unsafe fn rseq(rseq: core::ptr::NonNull<c_void>, len: u32, flags: c_int, sig: u32) -> Result<(), syscalls::Errno> {
+	unsafe {
+		syscalls::syscall!(syscalls::Sysno::rseq, rseq.as_ptr(), len, flags, sig).map(|ret| {
+			debug_assert_eq!(ret, 0);
+		})
+	}
+}
+
+/// Returns whether the calling thread has a thread pointer, which only a libc (or an equivalent runtime) sets up. There
+/// is none on architectures other than x86_64 and aarch64, as far as emma is concerned.
+pub fn has_thread_pointer() -> bool {
+	#[cfg(target_arch = "x86_64")]
+	{
+		arch_get_fs() != 0
+	}
+	#[cfg(target_arch = "aarch64")]
+	{
+		unsafe { thread_pointer() != 0 }
+	}
+	#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+	{
+		false
+	}
+}
+
+/// Returns the base address of the `fs` segment of the calling thread, which is zero unless a thread pointer has been
+/// set up.
+///
+/// `int arch_prctl(ARCH_GET_FS, unsigned long *addr);`
+#[cfg(target_arch = "x86_64")]
+fn arch_get_fs() -> usize {
+	const ARCH_GET_FS: c_int = 0x1003;
+
+	let mut fs = 0usize;
+	unsafe {
+		let ret = syscalls::syscall!(syscalls::Sysno::arch_prctl, ARCH_GET_FS, &mut fs as *mut usize);
+		debug_assert!(ret.is_ok());
+	}
+	fs
+}
+
+/// Returns the thread pointer of the calling thread, which is stored at the start of its thread control block.
+///
+/// # Safety
+/// The thread pointer must have been set up (see [`has_thread_pointer`]), as any libc does for all threads.
+#[cfg(target_arch = "x86_64")]
+#[inline(always)]
+pub unsafe fn thread_pointer() -> usize {
+	let thread_pointer: usize;
+	unsafe {
+		core::arch::asm!("mov {}, fs:0", out(reg) thread_pointer, options(nostack, readonly, preserves_flags));
+	}
+	thread_pointer
+}
+
+/// Returns the thread pointer of the calling thread (`tpidr_el0`), which is zero unless it has been set up.
+///
+/// # Safety
+/// Always safe to call on aarch64, but unsafe for consistency with other architectures.
+#[cfg(target_arch = "aarch64")]
+#[inline(always)]
+pub unsafe fn thread_pointer() -> usize {
+	let thread_pointer: usize;
+	unsafe {
+		core::arch::asm!("mrs {}, tpidr_el0", out(reg) thread_pointer, options(nomem, nostack, preserves_flags));
+	}
+	thread_pointer
+}
+
+/// There is no thread pointer on other architectures (see [`has_thread_pointer`]).
+///
+/// # Safety
+/// Must not be called, as [`has_thread_pointer`] is always `false`.
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+#[inline(always)]
+pub unsafe fn thread_pointer() -> usize {
+	unsafe { core::hint::unreachable_unchecked() }
+}
@@ -0,0 +1,87 @@
+//! Resolves functions of the vDSO, a shared library that the kernel maps into every process, so that some system calls
+//! can be answered without entering the kernel. The vDSO is located via the auxiliary vector (see [`super::elf`]). If
+//! it cannot be found, the callers fall back to system calls.
+
+use core::ffi::{c_int, c_void};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use linux_raw_sys::general::__kernel_timespec;
+
+/// The names of the functions that are resolved, in the order of [`FUNCTIONS`].
+#[cfg(target_arch = "x86_64")]
+const NAMES: [Option<&[u8]>; 2] = [Some(b"__vdso_clock_gettime"), Some(b"__vdso_getcpu")];
+/// The vDSO of arm64 has no `getcpu`.
+#[cfg(target_arch = "aarch64")]
+const NAMES: [Option<&[u8]>; 2] = [Some(b"__kernel_clock_gettime"), None];
+
+const CLOCK_GETTIME: usize = 0;
+const GETCPU: usize = 1;
+
+static RESOLVED: AtomicBool = AtomicBool::new(false);
+/// The addresses of the resolved functions, or `0` for functions that are not available.
+static FUNCTIONS: [AtomicUsize; 2] = [const { AtomicUsize::new(0) }; 2];
+
+/// `int clock_gettime(clockid_t clockid, struct timespec *tp);`
+pub type ClockGettime = unsafe extern "C" fn(c_int, *mut __kernel_timespec) -> c_int;
+/// `int getcpu(unsigned int *cpu, unsigned int *node, void *tcache);`
+pub type Getcpu = unsafe extern "C" fn(*mut u32, *mut u32, *mut c_void) -> c_int;
+
+/// Returns the `clock_gettime` of the vDSO, if there is one.
+#[inline]
+pub fn clock_gettime() -> Option<ClockGettime> {
+	let address = function(CLOCK_GETTIME);
+	(address != 0).then(|| unsafe { core::mem::transmute::<usize, ClockGettime>(address) })
+}
+
+/// Returns the `getcpu` of the vDSO, if there is one.
+#[inline]
+pub fn getcpu() -> Option<Getcpu> {
+	let address = function(GETCPU);
+	(address != 0).then(|| unsafe { core::mem::transmute::<usize, Getcpu>(address) })
+}
+
+#[inline]
+fn function(index: usize) -> usize {
+	if !RESOLVED.load(Ordering::Acquire) {
+		resolve();
+	}
+	FUNCTIONS[index].load(Ordering::Relaxed)
+}
+
+/// Resolves all functions. Threads that race to do so store the same addresses.
+#[cold]
+fn resolve() {
+	#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+	if let Some(base) = super::elf::auxv(linux_raw_sys::auxvec::AT_SYSINFO_EHDR) {
+		for (function, name) in FUNCTIONS.iter().zip(NAMES) {
+			let address = name.and_then(|name| unsafe { super::elf::lookup(base, name) });
+			function.store(address.unwrap_or(0), Ordering::Relaxed);
+		}
+	}
+	RESOLVED.store(true, Ordering::Release);
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	#[cfg(target_arch = "x86_64")]
+	fn resolve_functions() {
+		let clock_gettime = clock_gettime().unwrap();
+		let mut time = __kernel_timespec { tv_sec: 0, tv_nsec: 0 };
+		assert_eq!(
+			unsafe { clock_gettime(linux_raw_sys::general::CLOCK_MONOTONIC as c_int, &mut time) },
+			0
+		);
+		assert!(time.tv_sec > 0 || time.tv_nsec > 0);
+
+		let getcpu = getcpu().unwrap();
+		let mut cpu = u32::MAX;
+		assert_eq!(
+			unsafe { getcpu(&mut cpu, core::ptr::null_mut(), core::ptr::null_mut()) },
+			0
+		);
+		assert_ne!(cpu, u32::MAX);
+	}
+}
@@ -0,0 +1,375 @@
+//! Records every allocation, deallocation and reallocation as a compact binary record, which is written to a file
+//! descriptor using raw `write` syscalls.
+//!
+//! # Format
+//! All values are stored in native (little) endianness. A trace starts with a 16 byte header:
+//!
+//! | offset | type      | content                                |
+//! |--------|-----------|----------------------------------------|
+//! | 0      | `[u8; 8]` | magic bytes `EMMATRCE`                 |
+//! | 8      | `u32`     | format version, currently [`VERSION`]  |
+//! | 12     | `u32`     | size of each record, currently 48      |
+//!
+//! The header is followed by any number of records:
+//!
+//! | offset | type  | content                                                                                 |
+//! |--------|-------|-----------------------------------------------------------------------------------------|
+//! | 0      | `u64` | timestamp: the time stamp counter (`rdtsc`) on x86_64, `CLOCK_MONOTONIC` ns otherwise  |
+//! | 8      | `u64` | alloc: returned pointer, dealloc: released pointer, realloc: old pointer                 |
+//! | 16     | `u64` | realloc: returned pointer, otherwise 0                                                  |
+//! | 24     | `u64` | alloc: requested size, dealloc: size of the released object, realloc: new size          |
+//! | 32     | `u32` | requested alignment                                                                     |
+//! | 36     | `u32` | thread id of the calling thread                                                         |
+//! | 40     | `u8`  | kind: 1 = alloc, 2 = dealloc, 3 = realloc                                               |
+//! | 41     | `u8`  | tier serving the (new) size: 0 = small, 1 = medium, 2 = large, 3 = huge                 |
+//! | 42     | 6     | zero padding                                                                            |
+//!
+//! Failed allocations and reallocations are recorded with a null pointer as their result.
+//!
+//! Records are buffered per thread and each buffer is flushed with a single `write` call, so records of different
+//! threads are interleaved in chunks and only ordered by timestamp within each chunk. Use a regular file opened with
+//! `O_APPEND` to prevent chunks from being split.
+
+use core::ffi::c_int;
+use core::num::NonZero;
+use core::ptr::{self, NonNull};
+use core::sync::atomic::{AtomicPtr, AtomicU32, AtomicU64, Ordering};
+
+use const_format::{assertc, assertc_eq};
+
+use crate::mmap::alloc_aligned;
+use crate::sync::Futex;
+use crate::{Config, DefaultConfig};
+
+/// The version of the trace format.
+pub const VERSION: u32 = 1;
+const MAGIC: [u8; 8] = *b"EMMATRCE";
+/// The size of each mapping used to buffer records.
+const BUFFER_SIZE: usize = 64 * 1024;
+const BUFFER_CAPACITY: usize = (BUFFER_SIZE - 2 * size_of::<usize>()) / size_of::<Record>();
+/// The number of threads whose buffers can be found. Beyond that, threads take over the buffers of other threads.
+const SLOTS: usize = 1024;
+/// The number of slots that are probed to find the buffer of a thread.
+const PROBES: usize = 8;
+
+/// The state of the trace: the file descriptor that it is written to in the low half, or `-1` if tracing is disabled,
+/// and the generation of the trace in the high half, which [`start`] increments.
+static TRACE: AtomicU64 = AtomicU64::new(u32::MAX as u64);
+/// The table of per-thread buffers, which is mapped when it is first needed, or `RECORDERS_UNAVAILABLE`.
+static RECORDERS: AtomicPtr<Recorders> = AtomicPtr::new(ptr::null_mut());
+/// Marks that the table of buffers could not be mapped.
+const RECORDERS_UNAVAILABLE: *mut Recorders = ptr::dangling_mut();
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[repr(u8)]
+pub(crate) enum Kind {
+	Alloc = 1,
+	Dealloc = 2,
+	Realloc = 3,
+}
+
+#[derive(Debug, Copy, Clone)]
+#[repr(C)]
+pub(crate) struct Record {
+	pub timestamp: u64,
+	pub ptr: u64,
+	pub new_ptr: u64,
+	pub size: u64,
+	pub alignment: u32,
+	pub tid: u32,
+	pub kind: Kind,
+	pub tier: u8,
+	pub padding: [u8; 6],
+}
+
+assertc_eq!(size_of::<Record>(), 48usize);
+
+#[repr(C)]
+struct Header {
+	magic: [u8; 8],
+	version: u32,
+	record_size: u32,
+}
+
+assertc_eq!(size_of::<Header>(), 16usize);
+
+#[derive(Debug)]
+struct Buffer {
+	len: usize,
+	/// The generation of the trace that the buffered records belong to.
+	generation: usize,
+	records: [Record; BUFFER_CAPACITY],
+}
+
+assertc!(size_of::<Buffer>() <= BUFFER_SIZE);
+
+/// Splits the state of the trace into its file descriptor and generation.
+#[inline]
+fn state(ordering: Ordering) -> (c_int, usize) {
+	let state = TRACE.load(ordering);
+	(state as u32 as c_int, (state >> 32) as usize)
+}
+
+/// Returns whether a trace is currently being recorded.
+#[inline]
+pub(crate) fn is_active() -> bool {
+	state(Ordering::Relaxed).0 >= 0
+}
+
+/// Returns the current value of the time stamp counter.
+#[cfg(target_arch = "x86_64")]
+#[inline]
+pub(crate) fn timestamp() -> u64 {
+	unsafe { core::arch::x86_64::_rdtsc() }
+}
+
+/// Returns the current time of `CLOCK_MONOTONIC` in nanoseconds, as there is no time stamp counter to read.
+#[cfg(not(target_arch = "x86_64"))]
+#[inline]
+pub(crate) fn timestamp() -> u64 {
+	crate::sys::monotonic_nanos()
+}
+
+/// Returns the thread id of the calling thread.
+#[inline]
+pub(crate) fn tid() -> u32 {
+	#[cfg(feature = "tls")]
+	{
+		#[thread_local]
+		static mut TID: u32 = 0;
+
+		unsafe {
+			if TID == 0 {
+				TID = crate::sys::gettid();
+			}
+			TID
+		}
+	}
+	#[cfg(feature = "std-tls")]
+	{
+		std::thread_local! {
+			static TID: core::cell::Cell<u32> = const { core::cell::Cell::new(0) };
+		}
+
+		TID
+			.try_with(|tid| {
+				if tid.get() == 0 {
+					tid.set(crate::sys::gettid());
+				}
+				tid.get()
+			})
+			.unwrap_or_else(|_| crate::sys::gettid())
+	}
+	#[cfg(not(any(feature = "tls", feature = "std-tls")))]
+	crate::sys::gettid()
+}
+
+/// Writes the trace header to `fd` and starts recording into it.
+pub(crate) unsafe fn start(fd: c_int) -> Result<(), syscalls::Errno> {
+	let header = Header {
+		magic: MAGIC,
+		version: VERSION,
+		record_size: size_of::<Record>() as u32,
+	};
+	unsafe {
+		write_all(
+			fd,
+			core::slice::from_raw_parts((&raw const header).cast::<u8>(), size_of::<Header>()),
+		)?
+	};
+	let generation = (TRACE.load(Ordering::Relaxed) >> 32).wrapping_add(1);
+	TRACE.store(generation << 32 | fd as u32 as u64, Ordering::Release);
+	Ok(())
+}
+
+/// Writes the records buffered by all threads and stops recording. Records of this trace that other threads buffer
+/// concurrently are discarded, even if another trace has been started by the time their buffer is flushed.
+pub(crate) fn stop() {
+	if let Some(recorders) = recorders() {
+		for slot in recorders.slots.iter() {
+			if slot.tid.load(Ordering::Relaxed) != 0 {
+				slot.recorder.lock().flush();
+			}
+		}
+	}
+	TRACE.fetch_or(u32::MAX as u64, Ordering::Release);
+}
+
+/// Buffers `record` in the buffer of the calling thread.
+#[inline]
+pub(crate) fn record(record: Record) {
+	with_recorder(|recorder| recorder.record(record));
+}
+
+/// Writes the records buffered by the calling thread to the trace.
+pub(crate) fn flush() {
+	with_recorder(Recorder::flush);
+}
+
+unsafe fn write_all(fd: c_int, mut bytes: &[u8]) -> Result<(), syscalls::Errno> {
+	while !bytes.is_empty() {
+		match unsafe { crate::sys::write(fd, bytes) } {
+			Ok(written) => bytes = &bytes[written..],
+			Err(syscalls::Errno::EINTR) => (),
+			Err(err) => return Err(err),
+		}
+	}
+	Ok(())
+}
+
+/// The buffers of all threads, each of which belongs to the thread whose id is stored in the same slot. Slots are never
+/// released, as there is no cheap way to learn that a thread has exited. Instead, a thread whose slots are all taken
+/// flushes the buffer of another thread and takes it over.
+struct Recorders {
+	slots: [Slot; SLOTS],
+}
+
+struct Slot {
+	/// The id of the thread that owns the buffer, or `0` if the slot is free.
+	tid: AtomicU32,
+	recorder: Futex<Recorder>,
+}
+
+/// Calls `f` with the buffer of the calling thread.
+#[inline]
+fn with_recorder(f: impl FnOnce(&mut Recorder)) {
+	let Some(recorders) = recorders() else {
+		return;
+	};
+
+	let tid = tid();
+	let start = (tid as usize).wrapping_mul(0x9e37_79b9_7f4a_7c15_u64 as usize) >> (usize::BITS - SLOTS.ilog2());
+	for i in 0..PROBES {
+		let slot = &recorders.slots[(start + i) % SLOTS];
+		let owner = slot.tid.load(Ordering::Relaxed);
+		if owner == tid
+			|| (owner == 0
+				&& slot
+					.tid
+					.compare_exchange(0, tid, Ordering::Relaxed, Ordering::Relaxed)
+					.is_ok())
+		{
+			let mut recorder = slot.recorder.lock();
+			// Another thread may have taken the slot over in the meantime.
+			if slot.tid.load(Ordering::Relaxed) == tid {
+				f(&mut recorder);
+				return;
+			}
+		}
+	}
+
+	// All probed slots belong to other threads, which may well have exited.
+	let slot = &recorders.slots[start];
+	let mut recorder = slot.recorder.lock();
+	recorder.flush();
+	slot.tid.store(tid, Ordering::Relaxed);
+	f(&mut recorder);
+}
+
+#[inline]
+fn recorders() -> Option<&'static Recorders> {
+	let recorders = RECORDERS.load(Ordering::Acquire);
+	if recorders.is_null() {
+		map_recorders()
+	} else if recorders == RECORDERS_UNAVAILABLE {
+		None
+	} else {
+		Some(unsafe { &*recorders })
+	}
+}
+
+#[cold]
+fn map_recorders() -> Option<&'static Recorders> {
+	let size = NonZero::new((size_of::<Recorders>() + 4095) & !4095).unwrap();
+	// The mapping is zero-initialized, which makes every slot free, with an unlocked recorder that has no buffer.
+	let recorders = unsafe {
+		alloc_aligned(
+			size,
+			NonZero::new(4096).unwrap(),
+			DefaultConfig::ALLOC_ALIGNED_RETRIES,
+			c"emma:trace",
+		)
+		.map_or(RECORDERS_UNAVAILABLE, |recorders| {
+			recorders.cast::<Recorders>().as_ptr()
+		})
+	};
+
+	match RECORDERS.compare_exchange(ptr::null_mut(), recorders, Ordering::AcqRel, Ordering::Acquire) {
+		Ok(_) if recorders == RECORDERS_UNAVAILABLE => None,
+		Ok(_) => Some(unsafe { &*recorders }),
+		Err(winner) => {
+			if recorders != RECORDERS_UNAVAILABLE {
+				let res = unsafe { crate::mmap::munmap(NonNull::new_unchecked(recorders).cast(), size) };
+				debug_assert!(res.is_ok());
+			}
+			(winner != RECORDERS_UNAVAILABLE).then(|| unsafe { &*winner })
+		}
+	}
+}
+
+/// Buffers the records of one thread.
+#[derive(Debug)]
+struct Recorder {
+	buffer: Option<NonNull<Buffer>>,
+}
+
+unsafe impl Send for Recorder {}
+
+impl Recorder {
+	#[inline]
+	fn record(&mut self, record: Record) {
+		let Some(mut buffer) = self.buffer.or_else(|| self.map_buffer()) else {
+			// Without a buffer, the record is lost, which is preferable to failing the allocation.
+			return;
+		};
+
+		let buffer = unsafe { buffer.as_mut() };
+		let (_, generation) = state(Ordering::Acquire);
+		if buffer.generation != generation {
+			// The buffered records belong to a trace that has been stopped.
+			buffer.len = 0;
+			buffer.generation = generation;
+		}
+		buffer.records[buffer.len] = record;
+		buffer.len += 1;
+		if buffer.len == BUFFER_CAPACITY {
+			self.flush();
+		}
+	}
+
+	#[cold]
+	fn map_buffer(&mut self) -> Option<NonNull<Buffer>> {
+		let buffer = unsafe {
+			alloc_aligned(
+				NonZero::new(BUFFER_SIZE).unwrap(),
+				NonZero::new(4096).unwrap(),
+				DefaultConfig::ALLOC_ALIGNED_RETRIES,
+				c"emma:trace",
+			)?
+			.cast::<Buffer>()
+		};
+		// The mapping is zero-initialized, so the buffer starts out empty.
+		self.buffer = Some(buffer);
+		Some(buffer)
+	}
+
+	/// Writes all buffered records to the trace, unless they belong to a trace that has been stopped.
+	fn flush(&mut self) {
+		let Some(mut buffer) = self.buffer else {
+			return;
+		};
+
+		let buffer = unsafe { buffer.as_mut() };
+		let (fd, generation) = state(Ordering::Acquire);
+		if fd >= 0 && buffer.generation == generation && buffer.len > 0 {
+			// There is nobody to report a failure to, so the records are dropped instead.
+			let _ = unsafe {
+				write_all(
+					fd,
+					core::slice::from_raw_parts(buffer.records.as_ptr().cast::<u8>(), buffer.len * size_of::<Record>()),
+				)
+			};
+		}
+		buffer.len = 0;
+	}
+}
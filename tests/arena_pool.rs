@@ -0,0 +1,49 @@
+use std::alloc::Layout;
+use std::collections::HashSet;
+
+use emma::{Config, DefaultEmma};
+
+extern crate alloc;
+use alloc::alloc::GlobalAlloc;
+
+static EMMA: DefaultEmma = DefaultEmma::new();
+
+const ARENA_SIZE: usize = emma::DefaultConfig::ARENA_SIZE as usize;
+
+fn arenas_of(objs: &[usize]) -> HashSet<usize> {
+	objs.iter().map(|&p| p & !(ARENA_SIZE - 1)).collect()
+}
+
+fn alloc_many(count: usize, layout: Layout) -> Vec<usize> {
+	(0..count)
+		.map(|_| unsafe {
+			let p = EMMA.alloc(layout);
+			assert!(!p.is_null());
+			p as usize
+		})
+		.collect()
+}
+
+#[test]
+fn empty_arenas_are_shared() {
+	let layout = Layout::from_size_align(64, 8).unwrap();
+	let objects_per_arena = ARENA_SIZE / layout.size();
+
+	let objs = alloc_many(3 * objects_per_arena, layout);
+	let donor_arenas = arenas_of(&objs);
+	for &p in objs.iter() {
+		unsafe { EMMA.dealloc(p as *mut u8, layout) };
+	}
+	// All but one arena's worth of empty pages are donated to the pool.
+	EMMA.trim();
+
+	let objs = std::thread::spawn(move || alloc_many(2 * objects_per_arena, layout))
+		.join()
+		.unwrap();
+	let arenas = arenas_of(&objs);
+	assert!(arenas.is_subset(&donor_arenas), "{arenas:x?} {donor_arenas:x?}");
+
+	for p in objs {
+		unsafe { EMMA.dealloc(p as *mut u8, layout) };
+	}
+}
@@ -0,0 +1,40 @@
+use std::alloc::Layout;
+
+use emma::DefaultEmma;
+
+extern crate alloc;
+use alloc::alloc::GlobalAlloc;
+
+static EMMA: DefaultEmma = DefaultEmma::new();
+
+#[test]
+fn collapse_dense_arenas() {
+	EMMA.set_huge_page_collapse(true);
+	let layout = Layout::from_size_align(64, 8).unwrap();
+
+	let objs: Vec<_> = (0..200_000)
+		.map(|i| unsafe {
+			let p = EMMA.alloc(layout);
+			assert!(!p.is_null());
+			p.cast::<usize>().write(i);
+			p
+		})
+		.collect();
+
+	let before = EMMA.huge_page_stats();
+	EMMA.trim();
+	EMMA.trim();
+	assert_eq!(
+		EMMA.huge_page_stats(),
+		before,
+		"arenas are only collapsed after staying dense"
+	);
+	// Whether collapsing succeeds depends on the kernel (version and THP configuration), so only the objects are checked.
+	EMMA.trim();
+
+	for (i, &p) in objs.iter().enumerate() {
+		assert_eq!(unsafe { p.cast::<usize>().read() }, i);
+		unsafe { EMMA.dealloc(p, layout) };
+	}
+	EMMA.trim();
+}
@@ -0,0 +1,95 @@
+use std::alloc::Layout;
+
+use emma::{CompactConfig, DefaultEmma, Emma};
+
+extern crate alloc;
+use alloc::alloc::GlobalAlloc;
+
+type CompactEmma = Emma<CompactConfig>;
+
+#[test]
+fn compact_arenas_hold_objects_of_up_to_448_kib() {
+	let emma = CompactEmma::new();
+	let default = DefaultEmma::new();
+	let large = Layout::from_size_align(448 * 1024, 8).unwrap();
+	let huge = Layout::from_size_align(448 * 1024 + 1, 8).unwrap();
+
+	unsafe {
+		let p = emma.alloc(large);
+		assert!(!p.is_null());
+		p.write_bytes(0x42, large.size());
+		let resident = emma.resident_bytes();
+		assert!(resident.large >= large.size(), "{resident:?}");
+		assert_eq!(resident.huge, 0, "{resident:?}");
+
+		let q = emma.alloc(huge);
+		assert!(!q.is_null());
+		q.write_bytes(0x42, huge.size());
+		let resident = emma.resident_bytes();
+		assert!(resident.huge >= huge.size(), "{resident:?}");
+
+		// The default configuration serves the same object from an arena.
+		let r = default.alloc(huge);
+		assert!(!r.is_null());
+		r.write_bytes(0x42, huge.size());
+		let resident = default.resident_bytes();
+		assert!(resident.large >= huge.size(), "{resident:?}");
+		assert_eq!(resident.huge, 0, "{resident:?}");
+
+		emma.dealloc(p, large);
+		emma.dealloc(q, huge);
+		default.dealloc(r, huge);
+	}
+}
+
+#[test]
+fn compact_realloc_keeps_contents_across_tiers() {
+	let emma = CompactEmma::new();
+	let sizes = [24, 200, 4 * 1024, 40 * 1024, 300 * 1024, 448 * 1024, 1024 * 1024, 100];
+
+	unsafe {
+		let mut layout = Layout::from_size_align(16, 8).unwrap();
+		let mut p = emma.alloc(layout);
+		assert!(!p.is_null());
+		p.write_bytes(0x42, layout.size());
+		for size in sizes {
+			p = emma.realloc(p, layout, size);
+			assert!(!p.is_null(), "{size}");
+			let retained = layout.size().min(size);
+			assert!((0..retained).all(|i| *p.add(i) == 0x42), "{size}");
+			layout = Layout::from_size_align(size, 8).unwrap();
+			p.write_bytes(0x42, size);
+		}
+		emma.dealloc(p, layout);
+	}
+}
+
+#[test]
+fn configurations_are_used_side_by_side() {
+	let layouts = [16, 1024, 16 * 1024, 256 * 1024].map(|size| Layout::from_size_align(size, 8).unwrap());
+
+	std::thread::scope(|scope| {
+		for _ in 0..4 {
+			scope.spawn(|| {
+				for round in 0..20u8 {
+					// Dropped instances are recycled, but only for an instance of the same configuration.
+					let default = DefaultEmma::new();
+					let compact = CompactEmma::new();
+					for layout in layouts {
+						unsafe {
+							let p = default.alloc(layout);
+							let q = compact.alloc(layout);
+							assert!(!p.is_null() && !q.is_null());
+							p.write_bytes(round, layout.size());
+							q.write_bytes(!round, layout.size());
+							assert!((0..layout.size()).all(|i| *p.add(i) == round));
+							assert!((0..layout.size()).all(|i| *q.add(i) == !round));
+							default.dealloc(p, layout);
+							compact.dealloc(q, layout);
+						}
+					}
+				}
+			});
+		}
+	});
+}
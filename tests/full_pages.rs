@@ -0,0 +1,52 @@
+use std::alloc::Layout;
+use std::collections::HashSet;
+use std::sync::mpsc;
+
+use emma::DefaultEmma;
+
+extern crate alloc;
+use alloc::alloc::GlobalAlloc;
+
+static EMMA: DefaultEmma = DefaultEmma::new();
+
+#[test]
+fn full_pages_are_reused_after_frees() {
+	const COUNT: usize = 4096;
+	const OBJECTS_PER_PAGE: usize = 32 * 1024 / 64;
+	let layout = Layout::from_size_align(64, 8).unwrap();
+
+	let (request, requests) = mpsc::channel::<()>();
+	let (objects_sender, objects) = mpsc::channel::<Vec<usize>>();
+	let producer = std::thread::spawn(move || {
+		while requests.recv().is_ok() {
+			let objs = (0..COUNT)
+				.map(|_| unsafe {
+					let p = EMMA.alloc(layout);
+					assert!(!p.is_null());
+					p as usize
+				})
+				.collect();
+			objects_sender.send(objs).unwrap();
+		}
+	});
+
+	request.send(()).unwrap();
+	let first = objects.recv().unwrap();
+	for &p in first.iter() {
+		unsafe { EMMA.dealloc(p as *mut u8, layout) };
+	}
+	EMMA.trim();
+
+	// The pages that were filled up have been handed back, so only the page that still had space is used besides them.
+	request.send(()).unwrap();
+	let second = objects.recv().unwrap();
+	let first: HashSet<usize> = first.into_iter().collect();
+	let reused = second.iter().filter(|p| first.contains(p)).count();
+	assert!(reused >= COUNT - OBJECTS_PER_PAGE, "{reused}");
+
+	for p in second {
+		unsafe { EMMA.dealloc(p as *mut u8, layout) };
+	}
+	drop(request);
+	producer.join().unwrap();
+}
@@ -0,0 +1,114 @@
+#![cfg(feature = "heap-profile")]
+
+use std::alloc::Layout;
+use std::sync::Mutex;
+
+use emma::DefaultEmma;
+
+extern crate alloc;
+use alloc::alloc::GlobalAlloc;
+
+static EMMA: DefaultEmma = DefaultEmma::new();
+/// The samples are shared by all instances, so the tests must not sample concurrently.
+static LOCK: Mutex<()> = Mutex::new(());
+
+fn sampled_objects(profile: &str) -> usize {
+	let header = profile.lines().next().unwrap();
+	assert!(header.starts_with("heap profile: "), "{header}");
+	assert!(header.ends_with(" @ heap_v2/1"), "{header}");
+	assert!(profile.contains("\nMAPPED_LIBRARIES:\n"));
+
+	let count = header["heap profile: ".len()..]
+		.split(':')
+		.next()
+		.unwrap()
+		.parse()
+		.unwrap();
+	assert_eq!(
+		profile
+			.lines()
+			.skip(1)
+			.take_while(|line| !line.is_empty())
+			.filter(|line| line.starts_with("1: "))
+			.count(),
+		count
+	);
+	count
+}
+
+/// Returns the sizes of the sampled objects.
+fn sampled_sizes() -> Vec<usize> {
+	let mut profile = String::new();
+	EMMA.dump_heap_profile(&mut profile).unwrap();
+	sampled_objects(&profile);
+	profile
+		.lines()
+		.skip(1)
+		.take_while(|line| !line.is_empty())
+		.map(|line| line["1: ".len()..].split(' ').next().unwrap().parse().unwrap())
+		.collect()
+}
+
+#[test]
+fn sample_and_dump() {
+	const COUNT: usize = 1000;
+
+	let _lock = LOCK.lock().unwrap();
+	EMMA.set_heap_profile_sampling_interval(1);
+	let layout = Layout::from_size_align(1000, 8).unwrap();
+
+	let objs: Vec<_> = (0..COUNT).map(|_| unsafe { EMMA.alloc(layout) }).collect();
+	let mut profile = String::new();
+	EMMA.dump_heap_profile(&mut profile).unwrap();
+	assert!(sampled_objects(&profile) >= COUNT / 4);
+
+	for p in objs.into_iter() {
+		unsafe { EMMA.dealloc(p, layout) };
+	}
+	let mut profile = String::new();
+	EMMA.dump_heap_profile(&mut profile).unwrap();
+	assert_eq!(sampled_objects(&profile), 0);
+}
+
+#[test]
+fn remapped_objects_keep_their_samples() {
+	let _lock = LOCK.lock().unwrap();
+	EMMA.set_heap_profile_sampling_interval(1);
+	let layout = Layout::from_size_align(2 * 1024 * 1024, 4096).unwrap();
+	let new_size = 16 * 1024 * 1024;
+
+	unsafe {
+		let p = EMMA.alloc(layout);
+		assert!(sampled_sizes().contains(&layout.size()));
+		let q = EMMA.realloc(p, layout, new_size);
+		let sizes = sampled_sizes();
+		assert!(!sizes.contains(&layout.size()), "{sizes:?}");
+		assert!(sizes.contains(&new_size), "{sizes:?}");
+
+		EMMA.dealloc(q, Layout::from_size_align(new_size, 4096).unwrap());
+		assert!(!sampled_sizes().contains(&new_size));
+	}
+}
+
+#[test]
+fn objects_grown_in_place_keep_their_samples() {
+	let _lock = LOCK.lock().unwrap();
+	EMMA.set_heap_profile_sampling_interval(1);
+	// Sizes that no other test samples.
+	let layout = Layout::from_size_align(600 * 1024, 8).unwrap();
+	let new_size = 700 * 1024;
+
+	unsafe {
+		let p = EMMA.alloc(layout);
+		assert!(sampled_sizes().contains(&layout.size()));
+		// A fresh arena has room behind the most recently carved object.
+		let q = EMMA.realloc(p, layout, new_size);
+		assert_eq!(p, q);
+		let sizes = sampled_sizes();
+		assert!(!sizes.contains(&layout.size()), "{sizes:?}");
+		assert!(sizes.contains(&new_size), "{sizes:?}");
+
+		EMMA.dealloc(q, Layout::from_size_align(new_size, 8).unwrap());
+		assert!(!sampled_sizes().contains(&new_size));
+	}
+}
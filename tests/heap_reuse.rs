@@ -0,0 +1,49 @@
+#![cfg(any(feature = "tls", feature = "std-tls"))]
+
+use std::alloc::Layout;
+
+use emma::DefaultEmma;
+
+extern crate alloc;
+use alloc::alloc::GlobalAlloc;
+
+static EMMA: DefaultEmma = DefaultEmma::new();
+
+/// Sums the sizes of all mappings that hold heaps, or returns `None` if the kernel does not support naming mappings.
+fn heap_meta_bytes() -> Option<usize> {
+	let maps = std::fs::read_to_string("/proc/self/maps").unwrap();
+	let mut named = false;
+	let mut bytes = 0;
+	for line in maps.lines() {
+		named |= line.contains("[anon:emma:");
+		if line.ends_with("[anon:emma:heap-meta]") {
+			let range = line.split(' ').next().unwrap();
+			let (start, end) = range.split_once('-').unwrap();
+			bytes += usize::from_str_radix(end, 16).unwrap() - usize::from_str_radix(start, 16).unwrap();
+		}
+	}
+	named.then_some(bytes)
+}
+
+fn allocate_on_new_thread() {
+	std::thread::spawn(|| unsafe {
+		let layout = Layout::from_size_align(64, 8).unwrap();
+		let p = EMMA.alloc(layout);
+		assert!(!p.is_null());
+		EMMA.dealloc(p, layout);
+	})
+	.join()
+	.unwrap();
+}
+
+#[test]
+fn heaps_of_exited_threads_are_reused() {
+	allocate_on_new_thread();
+	let Some(before) = heap_meta_bytes() else {
+		return;
+	};
+	for _ in 0..64 {
+		allocate_on_new_thread();
+	}
+	assert_eq!(heap_meta_bytes(), Some(before));
+}
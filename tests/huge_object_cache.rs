@@ -0,0 +1,81 @@
+use std::alloc::Layout;
+use std::time::Duration;
+
+use emma::DefaultEmma;
+
+extern crate alloc;
+use alloc::alloc::GlobalAlloc;
+
+static EMMA: DefaultEmma = DefaultEmma::new();
+static OTHER: DefaultEmma = DefaultEmma::new();
+
+#[test]
+fn reuse_and_decay() {
+	const MIB: usize = 1024 * 1024;
+	EMMA.set_huge_object_cache(16 * MIB, Duration::from_secs(3600), true);
+
+	unsafe {
+		let layout = Layout::from_size_align(4 * MIB, 8).unwrap();
+		let p = EMMA.alloc(layout);
+		assert!(!p.is_null());
+		p.write_bytes(0x42, layout.size());
+		EMMA.dealloc(p, layout);
+		let stats = EMMA.huge_object_cache_stats();
+		assert_eq!(stats.cached_mappings, 1);
+		assert_eq!(stats.cached_bytes, 4 * MIB);
+
+		// A mapping of the same size is reused, ...
+		let q = EMMA.alloc(layout);
+		assert_eq!(q, p);
+		q.write_bytes(0x17, layout.size());
+		assert_eq!(EMMA.huge_object_cache_stats().hits, 1);
+
+		// ... but one of a different size is not.
+		let other = Layout::from_size_align(5 * MIB, 8).unwrap();
+		let r = EMMA.alloc(other);
+		assert!(!r.is_null());
+		assert_eq!(EMMA.huge_object_cache_stats().hits, 1);
+
+		// Objects that do not fit the limit are not cached, and the least recently freed mappings are evicted.
+		EMMA.dealloc(q, layout);
+		EMMA.dealloc(r, other);
+		assert_eq!(EMMA.huge_object_cache_stats().cached_bytes, 9 * MIB);
+		let big = Layout::from_size_align(32 * MIB, 8).unwrap();
+		let s = EMMA.alloc(big);
+		assert!(!s.is_null());
+		EMMA.dealloc(s, big);
+		assert_eq!(EMMA.huge_object_cache_stats().cached_bytes, 9 * MIB);
+		let t = EMMA.alloc(Layout::from_size_align(8 * MIB, 8).unwrap());
+		EMMA.dealloc(t, Layout::from_size_align(8 * MIB, 8).unwrap());
+		let stats = EMMA.huge_object_cache_stats();
+		assert_eq!(stats.cached_mappings, 2);
+		assert_eq!(stats.cached_bytes, 13 * MIB);
+
+		// Trimming unmaps the cached mappings of the trimmed instance only.
+		let small = Layout::from_size_align(MIB, 8).unwrap();
+		let u = OTHER.alloc(small);
+		assert!(!u.is_null());
+		OTHER.dealloc(u, small);
+		assert_eq!(EMMA.huge_object_cache_stats().cached_mappings, 3);
+		EMMA.trim();
+		let stats = EMMA.huge_object_cache_stats();
+		assert_eq!(stats.cached_mappings, 1);
+		assert_eq!(stats.cached_bytes, MIB);
+		OTHER.trim();
+		assert_eq!(EMMA.huge_object_cache_stats().cached_mappings, 0);
+
+		// Mappings decay once they have not been reused in time.
+		EMMA.set_huge_object_cache(16 * MIB, Duration::ZERO, false);
+		let p = EMMA.alloc(layout);
+		EMMA.dealloc(p, layout);
+		assert_eq!(EMMA.huge_object_cache_stats().cached_mappings, 1);
+		std::thread::sleep(Duration::from_millis(100));
+		let q = EMMA.alloc(layout);
+		assert!(!q.is_null());
+		assert_eq!(EMMA.huge_object_cache_stats().cached_mappings, 0);
+		EMMA.dealloc(q, layout);
+
+		EMMA.set_huge_object_cache(0, Duration::ZERO, false);
+		assert_eq!(EMMA.huge_object_cache_stats().cached_mappings, 0);
+	}
+}
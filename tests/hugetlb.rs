@@ -0,0 +1,62 @@
+use std::alloc::Layout;
+
+use emma::{DefaultEmma, HugetlbPageSize};
+
+extern crate alloc;
+use alloc::alloc::GlobalAlloc;
+
+static EMMA: DefaultEmma = DefaultEmma::new();
+
+fn free_2mib_hugetlb_pages() -> usize {
+	std::fs::read_to_string("/sys/kernel/mm/hugepages/hugepages-2048kB/free_hugepages")
+		.map(|free| free.trim().parse().unwrap())
+		.unwrap_or(0)
+}
+
+#[test]
+fn hugetlb_or_fallback() {
+	let free_pages = free_2mib_hugetlb_pages();
+	EMMA.set_hugetlb_arenas(true);
+	EMMA.set_hugetlb_huge_objects(Some(HugetlbPageSize::Size2MiB), 4 * 1024 * 1024);
+
+	let layouts = [
+		Layout::from_size_align(64, 8).unwrap(),
+		Layout::from_size_align(2000, 8).unwrap(),
+		Layout::from_size_align(100_000, 8).unwrap(),
+		Layout::from_size_align(5 * 1024 * 1024 + 1, 8).unwrap(),
+	];
+	let mut objs: Vec<_> = layouts
+		.iter()
+		.map(|&layout| unsafe {
+			let p = EMMA.alloc(layout);
+			assert!(!p.is_null());
+			p.write_bytes(0x42, layout.size());
+			(p, layout)
+		})
+		.collect();
+
+	let stats = EMMA.huge_page_stats();
+	assert_eq!(stats.hugetlb_mappings + stats.hugetlb_fallbacks, layouts.len());
+	if free_pages == 0 {
+		assert_eq!(stats.hugetlb_mappings, 0);
+	} else if free_pages >= 16 {
+		// 3 arenas of 2 pages each and the huge object with 3 pages
+		assert_eq!(stats.hugetlb_mappings, layouts.len());
+	}
+
+	// Shrink the huge object within its mapping, then grow it beyond.
+	let (mut p, mut layout) = objs.pop().unwrap();
+	for new_size in [4 * 1024 * 1024 + 1, 5 * 1024 * 1024, 9 * 1024 * 1024] {
+		p = unsafe { EMMA.realloc(p, layout, new_size) };
+		assert!(!p.is_null());
+		assert!((0..layout.size().min(new_size)).all(|i| unsafe { p.add(i).read() } == 0x42));
+		unsafe { p.write_bytes(0x42, new_size) };
+		layout = Layout::from_size_align(new_size, layout.align()).unwrap();
+	}
+	objs.push((p, layout));
+
+	for (p, layout) in objs {
+		assert!((0..layout.size()).all(|i| unsafe { p.add(i).read() } == 0x42));
+		unsafe { EMMA.dealloc(p, layout) };
+	}
+}
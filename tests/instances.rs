@@ -0,0 +1,101 @@
+use std::alloc::Layout;
+
+use emma::{Config, DefaultEmma};
+
+extern crate alloc;
+use alloc::alloc::GlobalAlloc;
+
+const ARENA_SIZE: usize = emma::DefaultConfig::ARENA_SIZE as usize;
+
+static FIRST: DefaultEmma = DefaultEmma::new();
+static SECOND: DefaultEmma = DefaultEmma::new();
+
+fn arena_of(p: *mut u8) -> usize {
+	p as usize & !(ARENA_SIZE - 1)
+}
+
+#[test]
+fn instances_do_not_share_arenas() {
+	for size in [64, 8 * 1024, 256 * 1024] {
+		let layout = Layout::from_size_align(size, 8).unwrap();
+		unsafe {
+			let p = FIRST.alloc(layout);
+			let q = SECOND.alloc(layout);
+			assert!(!p.is_null() && !q.is_null());
+			assert_ne!(arena_of(p), arena_of(q), "{size}");
+			FIRST.dealloc(p, layout);
+			SECOND.dealloc(q, layout);
+		}
+	}
+}
+
+#[test]
+fn resident_bytes_are_per_instance() {
+	let emma = DefaultEmma::new();
+	let other = DefaultEmma::new();
+	let small = Layout::from_size_align(64, 8).unwrap();
+	let huge = Layout::from_size_align(8 * 1024 * 1024, 4096).unwrap();
+
+	unsafe {
+		let p = emma.alloc(small);
+		p.write_bytes(0x42, small.size());
+		let q = emma.alloc(huge);
+		q.write_bytes(0x42, huge.size());
+
+		let resident = emma.resident_bytes();
+		assert!(resident.small >= 4096, "{resident:?}");
+		assert!(resident.huge >= huge.size(), "{resident:?}");
+		assert_eq!(other.resident_bytes().total(), 0);
+
+		let r = other.alloc(small);
+		r.write_bytes(0x42, small.size());
+		let resident = other.resident_bytes();
+		assert!(resident.small >= 4096, "{resident:?}");
+		assert_eq!(resident.huge, 0, "{resident:?}");
+
+		emma.dealloc(q, huge);
+		emma.dealloc(p, small);
+		other.dealloc(r, small);
+	}
+}
+
+#[test]
+fn threads_use_more_instances_than_they_cache() {
+	let instances: [DefaultEmma; 6] = [const { DefaultEmma::new() }; 6];
+	let layout = Layout::from_size_align(256, 8).unwrap();
+
+	let arenas = std::thread::scope(|scope| {
+		let threads = (0..4)
+			.map(|_| {
+				scope.spawn(|| {
+					let mut arenas = [Vec::new(), Vec::new(), Vec::new(), Vec::new(), Vec::new(), Vec::new()];
+					for _ in 0..100 {
+						for (emma, arenas) in instances.iter().zip(arenas.iter_mut()) {
+							unsafe {
+								let p = emma.alloc(layout);
+								assert!(!p.is_null());
+								p.write_bytes(0x42, layout.size());
+								arenas.push(arena_of(p));
+								emma.dealloc(p, layout);
+							}
+						}
+					}
+					arenas
+				})
+			})
+			.collect::<Vec<_>>();
+		threads
+			.into_iter()
+			.map(|thread| thread.join().unwrap())
+			.collect::<Vec<_>>()
+	});
+
+	// Each thread used arenas of every instance, but no arena was used by two instances.
+	for i in 0..instances.len() {
+		for j in i + 1..instances.len() {
+			for arena in arenas.iter().flat_map(|arenas| &arenas[i]) {
+				assert!(arenas.iter().all(|arenas| !arenas[j].contains(arena)), "{i} {j}");
+			}
+		}
+	}
+}
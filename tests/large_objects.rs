@@ -1,12 +1,16 @@
 use std::alloc::Layout;
 use std::ptr::NonNull;
 
-use emma::DefaultEmma;
+use emma::{Config, DefaultEmma};
 
 extern crate alloc;
 use alloc::alloc::GlobalAlloc;
 
 static EMMA: DefaultEmma = DefaultEmma::new();
+// The tests that grow objects in place depend on which objects share an arena, so each of them has an instance of its
+// own, from whose arenas no other test carves objects.
+static GROW_IN_PLACE: DefaultEmma = DefaultEmma::new();
+static GROW_FULL_PAGE: DefaultEmma = DefaultEmma::new();
 
 unsafe fn check(objs: &[(NonNull<u8>, Layout)]) {
 	let mut sorted = objs.to_owned();
@@ -78,3 +82,87 @@ fn main() {
 		}
 	}
 }
+
+#[test]
+fn grow_in_place() {
+	unsafe {
+		let layout = Layout::from_size_align(100_000, 8).unwrap();
+		let p = GROW_IN_PLACE.alloc(layout);
+		assert!(!p.is_null());
+		p.write_bytes(0x42, layout.size());
+
+		let q = GROW_IN_PLACE.realloc(p, layout, 300_000);
+		assert_eq!(q, p, "the most recently carved object should grow in place");
+		let layout = Layout::from_size_align(300_000, 8).unwrap();
+		assert!(std::slice::from_raw_parts(q, 100_000).iter().all(|&b| b == 0x42));
+		q.write_bytes(0x17, layout.size());
+
+		// Objects that are carved afterwards do not overlap the grown object.
+		let other = Layout::from_size_align(100_000, 8).unwrap();
+		let r = GROW_IN_PLACE.alloc(other);
+		assert!(!r.is_null());
+		assert!(r as usize >= q as usize + layout.size() || r as usize + other.size() <= q as usize);
+		r.write_bytes(0x23, other.size());
+		assert!(std::slice::from_raw_parts(q, layout.size()).iter().all(|&b| b == 0x17));
+
+		// The grown object is no longer the last one, so it has to move.
+		let s = GROW_IN_PLACE.realloc(q, layout, 600_000);
+		assert!(!s.is_null());
+		assert_ne!(s, q);
+		assert!(std::slice::from_raw_parts(s, layout.size()).iter().all(|&b| b == 0x17));
+
+		// All slots of the grown object were freed, so they can be reused.
+		let objs: Vec<_> = (0..3).map(|_| GROW_IN_PLACE.alloc(other)).collect();
+		assert!(objs.contains(&p));
+		for o in objs {
+			GROW_IN_PLACE.dealloc(o, other);
+		}
+
+		GROW_IN_PLACE.dealloc(s, Layout::from_size_align(600_000, 8).unwrap());
+		GROW_IN_PLACE.dealloc(r, other);
+		GROW_IN_PLACE.trim();
+	}
+}
+
+#[test]
+fn full_page_of_grown_object_returns_to_its_bin() {
+	const ARENA_SIZE: usize = emma::DefaultConfig::ARENA_SIZE as usize;
+
+	unsafe {
+		let small = Layout::from_size_align(128 * 1024, 8).unwrap();
+		let grown = Layout::from_size_align(160 * 1024, 8).unwrap();
+		let p = GROW_FULL_PAGE.alloc(small);
+		assert!(!p.is_null());
+		assert_eq!(GROW_FULL_PAGE.realloc(p, small, grown.size()), p);
+		p.write_bytes(0x42, grown.size());
+
+		// Fill the arena of the grown object, so that its page is full.
+		let mut objs = Vec::new();
+		loop {
+			let q = GROW_FULL_PAGE.alloc(small);
+			assert!(!q.is_null());
+			q.write_bytes(0x17, small.size());
+			objs.push(q);
+			if q as usize & !(ARENA_SIZE - 1) != p as usize & !(ARENA_SIZE - 1) {
+				break;
+			}
+		}
+
+		// Freeing the grown object returns the full page to the bin of its slots, not to the bin of the grown size.
+		GROW_FULL_PAGE.dealloc(p, grown);
+		let r = GROW_FULL_PAGE.alloc(grown);
+		assert!(!r.is_null());
+		for &q in &objs {
+			assert!(r as usize >= q as usize + small.size() || r as usize + grown.size() <= q as usize);
+		}
+		r.write_bytes(0x23, grown.size());
+		for &q in &objs {
+			assert!(std::slice::from_raw_parts(q, small.size()).iter().all(|&b| b == 0x17));
+		}
+
+		GROW_FULL_PAGE.dealloc(r, grown);
+		for q in objs {
+			GROW_FULL_PAGE.dealloc(q, small);
+		}
+	}
+}
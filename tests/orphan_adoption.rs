@@ -0,0 +1,54 @@
+#![cfg(any(feature = "std", feature = "std-tls"))]
+
+use std::alloc::Layout;
+use std::collections::HashSet;
+
+use emma::DefaultEmma;
+
+extern crate alloc;
+use alloc::alloc::GlobalAlloc;
+
+static EMMA: DefaultEmma = DefaultEmma::new();
+
+#[test]
+fn pages_of_exited_threads_are_adopted() {
+	const COUNT: usize = 1024;
+	let layout = Layout::from_size_align(1024, 8).unwrap();
+
+	unsafe {
+		// Acquire a heap for this thread before the other thread exits, so that it cannot simply take over that heap.
+		let small = Layout::from_size_align(16, 8).unwrap();
+		let p = EMMA.alloc(small);
+		assert!(!p.is_null());
+
+		let (kept, freed) = std::thread::spawn(move || {
+			let objs: Vec<usize> = (0..COUNT)
+				.map(|_| {
+					let p = EMMA.alloc(layout);
+					assert!(!p.is_null());
+					p as usize
+				})
+				.collect();
+			let (kept, freed): (Vec<usize>, Vec<usize>) = objs.into_iter().partition(|&p| p % (2 * layout.size()) == 0);
+			for &p in freed.iter() {
+				EMMA.dealloc(p as *mut u8, layout);
+			}
+			(kept, freed)
+		})
+		.join()
+		.unwrap();
+
+		// The partially used pages of the exited thread are reused by this thread.
+		let freed: HashSet<usize> = freed.into_iter().collect();
+		let objs: Vec<*mut u8> = (0..freed.len()).map(|_| EMMA.alloc(layout)).collect();
+		assert!(objs.iter().any(|&p| freed.contains(&(p as usize))));
+
+		for p in objs {
+			EMMA.dealloc(p, layout);
+		}
+		for p in kept {
+			EMMA.dealloc(p as *mut u8, layout);
+		}
+		EMMA.dealloc(p, small);
+	}
+}
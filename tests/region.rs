@@ -0,0 +1,126 @@
+#![cfg_attr(feature = "allocator-api", feature(allocator_api))]
+
+use std::alloc::Layout;
+
+use emma::{Config, DefaultEmma, Region};
+
+extern crate alloc;
+use alloc::alloc::GlobalAlloc;
+
+const ARENA_SIZE: usize = emma::DefaultConfig::ARENA_SIZE as usize;
+
+fn arena_of(p: *mut u8) -> usize {
+	p as usize & !(ARENA_SIZE - 1)
+}
+
+#[test]
+fn objects_are_bump_allocated() {
+	let emma = DefaultEmma::new();
+	let region = Region::new(&emma);
+	let layout = Layout::from_size_align(24, 8).unwrap();
+
+	unsafe {
+		let mut previous = region.alloc(layout);
+		previous.write_bytes(0x42, layout.size());
+		for _ in 0..1000 {
+			let p = region.alloc(layout);
+			assert_eq!(p, previous.add(layout.size()));
+			p.write_bytes(0x42, layout.size());
+			region.dealloc(p, layout);
+			previous = p;
+		}
+
+		for align in [16, 256, 4096] {
+			let layout = Layout::from_size_align(8, align).unwrap();
+			let p = region.alloc(layout);
+			assert_eq!(p as usize % align, 0);
+			p.write_bytes(0x42, layout.size());
+		}
+	}
+}
+
+#[test]
+fn objects_span_arenas() {
+	let emma = DefaultEmma::new();
+	let region = Region::new(&emma);
+	let mut arenas = Vec::new();
+
+	for size in [
+		ARENA_SIZE / 4,
+		3 * ARENA_SIZE / 4,
+		ARENA_SIZE,
+		3 * ARENA_SIZE,
+		ARENA_SIZE / 8,
+	] {
+		let layout = Layout::from_size_align(size, 8).unwrap();
+		unsafe {
+			let p = region.alloc(layout);
+			assert!(!p.is_null(), "{size}");
+			p.write_bytes(0x42, size);
+			arenas.push(arena_of(p));
+		}
+	}
+
+	// The first two objects do not fit into a single arena, but the last one fits behind the second.
+	assert_ne!(arenas[0], arenas[1]);
+	assert_eq!(arenas[1], arenas[4]);
+}
+
+#[test]
+fn last_object_grows_in_place() {
+	let emma = DefaultEmma::new();
+	let region = Region::new(&emma);
+	let layout = Layout::from_size_align(64, 8).unwrap();
+
+	unsafe {
+		let p = region.alloc(layout);
+		p.write_bytes(0x42, layout.size());
+		let q = region.realloc(p, layout, 4096);
+		assert_eq!(p, q);
+		q.write_bytes(0x42, 4096);
+
+		let r = region.alloc(layout);
+		let s = region.realloc(q, Layout::from_size_align(4096, 8).unwrap(), 8192);
+		assert_ne!(q, s);
+		assert!(s > r);
+		assert_eq!(*s.add(4095), 0x42);
+	}
+}
+
+#[test]
+fn arenas_are_reused() {
+	let emma = DefaultEmma::new();
+	let layout = Layout::from_size_align(64, 8).unwrap();
+
+	let first = {
+		let region = Region::new(&emma);
+		unsafe { region.alloc(layout) }
+	};
+	let second = {
+		let region = Region::new(&emma);
+		unsafe { region.alloc(layout) }
+	};
+	assert_eq!(arena_of(first), arena_of(second));
+
+	let mut region = Region::new(&emma);
+	unsafe {
+		let p = region.alloc(layout);
+		region.reset();
+		assert_eq!(region.alloc(layout), p);
+	}
+}
+
+#[cfg(feature = "allocator-api")]
+#[test]
+fn collections_borrow_regions() {
+	let emma = DefaultEmma::new();
+	let region = Region::new(&emma);
+
+	let mut numbers = Vec::new_in(&region);
+	for i in 0..100_000u64 {
+		numbers.push(i);
+	}
+	let boxed = Box::new_in([1u8; 64], &region);
+	assert_eq!(numbers.iter().sum::<u64>(), 99_999 * 100_000 / 2);
+	assert_eq!(boxed.iter().map(|&b| b as usize).sum::<usize>(), 64);
+}
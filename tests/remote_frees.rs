@@ -0,0 +1,62 @@
+#![cfg(any(feature = "tls", feature = "std-tls"))]
+
+use std::alloc::Layout;
+use std::collections::HashSet;
+use std::sync::mpsc;
+
+use emma::DefaultEmma;
+
+extern crate alloc;
+use alloc::alloc::GlobalAlloc;
+
+static EMMA: DefaultEmma = DefaultEmma::new();
+
+#[test]
+fn foreign_frees_are_handed_back_in_batches() {
+	let layout = Layout::from_size_align(64, 8).unwrap();
+
+	let (request, requests) = mpsc::channel::<usize>();
+	let (objects_sender, objects) = mpsc::channel::<Vec<usize>>();
+	let producer = std::thread::spawn(move || {
+		while let Ok(count) = requests.recv() {
+			let objs = (0..count)
+				.map(|_| unsafe {
+					let p = EMMA.alloc(layout);
+					assert!(!p.is_null());
+					p as usize
+				})
+				.collect();
+			objects_sender.send(objs).unwrap();
+		}
+	});
+
+	unsafe {
+		// Acquire a heap for this thread, as only threads with a heap buffer their frees.
+		let p = EMMA.alloc(layout);
+		assert!(!p.is_null());
+
+		request.send(16).unwrap();
+		let freed: HashSet<usize> = objects.recv().unwrap().into_iter().collect();
+		for &p in freed.iter() {
+			EMMA.dealloc(p as *mut u8, layout);
+		}
+
+		// The objects are still buffered by this thread, so the producer cannot reuse them yet, even after it has used up
+		// the free list of their page.
+		request.send(256).unwrap();
+		let buffered = objects.recv().unwrap();
+		assert!(buffered.iter().all(|p| !freed.contains(p)));
+
+		EMMA.trim();
+		request.send(256).unwrap();
+		let reused = objects.recv().unwrap();
+		assert!(freed.iter().all(|p| reused.contains(p)));
+
+		for p in buffered.into_iter().chain(reused) {
+			EMMA.dealloc(p as *mut u8, layout);
+		}
+		EMMA.dealloc(p, layout);
+	}
+	drop(request);
+	producer.join().unwrap();
+}
@@ -0,0 +1,89 @@
+use std::alloc::Layout;
+use std::sync::Mutex;
+
+use emma::DefaultEmma;
+
+extern crate alloc;
+use alloc::alloc::GlobalAlloc;
+
+/// Serializes the tests, so that no other test maps an instance while instance mappings are counted.
+static LOCK: Mutex<()> = Mutex::new(());
+
+const SIZES: [usize; 4] = [64, 8 * 1024, 256 * 1024, 8 * 1024 * 1024];
+
+/// Counts the mappings that hold instances, or returns `None` if the kernel does not support naming mappings.
+fn instance_mappings() -> Option<usize> {
+	let maps = std::fs::read_to_string("/proc/self/maps").unwrap();
+	let named = maps.contains("[anon:emma:");
+	let count = maps
+		.lines()
+		.filter(|line| line.ends_with("[anon:emma:instance]"))
+		.count();
+	named.then_some(count)
+}
+
+fn allocate_all_tiers(emma: &DefaultEmma) {
+	for size in SIZES {
+		let layout = Layout::from_size_align(size, 8).unwrap();
+		unsafe {
+			let p = emma.alloc(layout);
+			assert!(!p.is_null(), "{size}");
+			p.write_bytes(0x42, size);
+		}
+	}
+}
+
+#[test]
+fn reset_releases_all_memory() {
+	let _lock = LOCK.lock().unwrap();
+	let emma = DefaultEmma::new();
+	unsafe { emma.reset() };
+
+	allocate_all_tiers(&emma);
+	assert!(emma.resident_bytes().total() > 0);
+	unsafe { emma.reset() };
+	assert_eq!(emma.resident_bytes().total(), 0);
+
+	// The instance remains usable.
+	allocate_all_tiers(&emma);
+	let resident = emma.resident_bytes();
+	assert!(resident.small >= 4096, "{resident:?}");
+	assert!(resident.huge >= 8 * 1024 * 1024, "{resident:?}");
+}
+
+#[test]
+fn dropped_instances_are_reused() {
+	let _lock = LOCK.lock().unwrap();
+	allocate_all_tiers(&DefaultEmma::new());
+	let Some(before) = instance_mappings() else {
+		return;
+	};
+	for _ in 0..64 {
+		allocate_all_tiers(&DefaultEmma::new());
+	}
+	assert_eq!(instance_mappings(), Some(before));
+}
+
+#[test]
+fn threads_outlive_dropped_instances() {
+	let _lock = LOCK.lock().unwrap();
+	let (request, requests) = std::sync::mpsc::channel::<usize>();
+	let (done, dones) = std::sync::mpsc::channel::<()>();
+	let thread = std::thread::spawn(move || {
+		for emma in requests {
+			allocate_all_tiers(unsafe { &*(emma as *const DefaultEmma) });
+			done.send(()).unwrap();
+		}
+	});
+
+	// The thread keeps the heaps of each dropped instance cached, which must be usable by the instance's successor.
+	for _ in 0..8 {
+		let emma = Box::new(DefaultEmma::new());
+		allocate_all_tiers(&emma);
+		request.send(&*emma as *const DefaultEmma as usize).unwrap();
+		dones.recv().unwrap();
+		drop(emma);
+	}
+	drop(request);
+	thread.join().unwrap();
+}
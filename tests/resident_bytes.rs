@@ -0,0 +1,72 @@
+use std::alloc::Layout;
+
+use emma::DefaultEmma;
+
+extern crate alloc;
+use alloc::alloc::GlobalAlloc;
+
+static EMMA: DefaultEmma = DefaultEmma::new();
+
+#[test]
+fn resident_bytes_per_tier() {
+	let small = Layout::from_size_align(64, 8).unwrap();
+	let huge = Layout::from_size_align(16 * 1024 * 1024, 4096).unwrap();
+
+	let before = EMMA.resident_bytes();
+	let (p, q) = unsafe {
+		let p = EMMA.alloc(small);
+		p.write_bytes(0x42, small.size());
+		let q = EMMA.alloc(huge);
+		// Only touch the first half of the huge object.
+		q.write_bytes(0x42, huge.size() / 2);
+		(p, q)
+	};
+
+	let during = EMMA.resident_bytes();
+	assert!(during.small >= 4096, "{during:?}");
+	assert!(during.huge >= before.huge + huge.size() / 2, "{before:?} {during:?}");
+	assert!(during.huge < before.huge + huge.size(), "{before:?} {during:?}");
+	assert_eq!(
+		during.total(),
+		during.small + during.medium + during.large + during.huge
+	);
+
+	unsafe {
+		EMMA.dealloc(q, huge);
+		EMMA.dealloc(p, small);
+	}
+	let after = EMMA.resident_bytes();
+	assert_eq!(after.huge, before.huge, "{after:?}");
+}
+
+#[test]
+fn resident_bytes_of_many_mappings() {
+	let emma = DefaultEmma::new();
+	let huge = Layout::from_size_align(2 * 1024 * 1024, 4096).unwrap();
+
+	// More huge objects than the registry collects from a shard at once, allocated and counted concurrently.
+	let objects = std::thread::scope(|scope| {
+		let threads: Vec<_> = (0..4)
+			.map(|_| {
+				scope.spawn(|| {
+					(0..100)
+						.map(|_| unsafe {
+							let p = emma.alloc(huge);
+							p.write_bytes(0x42, 4096);
+							assert!(emma.resident_bytes().huge >= 4096);
+							p as usize
+						})
+						.collect::<Vec<_>>()
+				})
+			})
+			.collect();
+		threads.into_iter().flat_map(|t| t.join().unwrap()).collect::<Vec<_>>()
+	});
+
+	let resident = emma.resident_bytes();
+	assert!(resident.huge >= 400 * 4096, "{resident:?}");
+	assert!(resident.huge < 400 * huge.size(), "{resident:?}");
+	for p in objects {
+		unsafe { emma.dealloc(p as *mut u8, huge) };
+	}
+}
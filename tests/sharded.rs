@@ -0,0 +1,82 @@
+#![cfg(feature = "sharded")]
+
+use std::alloc::Layout;
+use std::sync::mpsc;
+
+use emma::DefaultEmma;
+
+extern crate alloc;
+use alloc::alloc::GlobalAlloc;
+
+static EMMA: DefaultEmma = DefaultEmma::new();
+
+const LAYOUTS: [(usize, usize); 5] = [(8, 8), (100, 8), (3000, 64), (40_000, 4096), (100_000, 8)];
+
+type Object = (usize, Layout, u8);
+
+fn alloc_filled(layout: Layout, tag: u8) -> Object {
+	unsafe {
+		let p = EMMA.alloc(layout);
+		assert!(!p.is_null());
+		p.write_bytes(tag, layout.size());
+		(p as usize, layout, tag)
+	}
+}
+
+fn check_and_free((p, layout, tag): Object) {
+	unsafe {
+		let object = std::slice::from_raw_parts(p as *const u8, layout.size());
+		assert!(object.iter().all(|&b| b == tag));
+		EMMA.dealloc(p as *mut u8, layout);
+	}
+}
+
+#[test]
+fn threads_share_shards() {
+	let (sender, receiver) = mpsc::channel::<Vec<Object>>();
+	let consumer = std::thread::spawn(move || {
+		for objects in receiver {
+			objects.into_iter().for_each(check_and_free);
+		}
+	});
+
+	let producers = (0..8u8)
+		.map(|thread| {
+			let sender = sender.clone();
+			std::thread::spawn(move || {
+				for round in 0..10u8 {
+					let tag = thread * 10 + round;
+					let mut objects = (0..100)
+						.map(|i| {
+							let (size, align) = LAYOUTS[i % LAYOUTS.len()];
+							alloc_filled(Layout::from_size_align(size, align).unwrap(), tag)
+						})
+						.collect::<Vec<_>>();
+					// Half of the objects are freed by their thread, the other half by another one.
+					let remote = objects.split_off(objects.len() / 2);
+					objects.into_iter().for_each(check_and_free);
+					sender.send(remote).unwrap();
+				}
+			})
+		})
+		.collect::<Vec<_>>();
+	for producer in producers {
+		producer.join().unwrap();
+	}
+	drop(sender);
+	consumer.join().unwrap();
+	EMMA.trim();
+}
+
+#[cfg(feature = "percpu")]
+#[test]
+fn threads_share_per_cpu_shards_without_glibc_rseq() {
+	// glibc registers rseq for every thread, in which case emma falls back to `getcpu`. Disabling that registration
+	// makes emma register rseq itself.
+	let status = std::process::Command::new(std::env::current_exe().unwrap())
+		.env("GLIBC_TUNABLES", "glibc.pthread.rseq=0")
+		.args(["--exact", "threads_share_shards", "--quiet"])
+		.status()
+		.unwrap();
+	assert!(status.success());
+}
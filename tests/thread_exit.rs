@@ -0,0 +1,48 @@
+#![cfg(any(feature = "std", feature = "std-tls"))]
+
+use std::alloc::Layout;
+use std::sync::mpsc;
+
+use emma::{DefaultEmma, HugePagePolicy, Tier};
+
+extern crate alloc;
+use alloc::alloc::GlobalAlloc;
+
+static EMMA: DefaultEmma = DefaultEmma::new();
+
+#[test]
+fn heap_is_flushed_on_exit() {
+	const COUNT: usize = 4096;
+	let layout = Layout::from_size_align(1024, 8).unwrap();
+	EMMA.set_huge_page_policy(Tier::Medium, HugePagePolicy::Never);
+
+	let (objects_sender, objects) = mpsc::channel();
+	let (exit, exit_receiver) = mpsc::channel::<()>();
+	let thread = std::thread::spawn(move || {
+		let objs: Vec<usize> = (0..COUNT)
+			.map(|_| unsafe {
+				let p = EMMA.alloc(layout);
+				assert!(!p.is_null());
+				p.write_bytes(0x42, layout.size());
+				p as usize
+			})
+			.collect();
+		objects_sender.send(objs).unwrap();
+		exit_receiver.recv().unwrap();
+	});
+
+	let objs = objects.recv().unwrap();
+	let full = EMMA.resident_bytes();
+	// The objects are freed by a foreign thread, so only the exiting thread can collect them.
+	for &p in objs.iter() {
+		unsafe { EMMA.dealloc(p as *mut u8, layout) };
+	}
+	exit.send(()).unwrap();
+	thread.join().unwrap();
+
+	let released = EMMA.resident_bytes();
+	assert!(
+		released.medium + COUNT * layout.size() / 2 < full.medium,
+		"{full:?} {released:?}"
+	);
+}
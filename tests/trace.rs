@@ -0,0 +1,165 @@
+#![cfg(feature = "trace")]
+
+use std::alloc::Layout;
+use std::io::{Read, Seek};
+use std::os::fd::AsRawFd;
+
+use emma::DefaultEmma;
+
+extern crate alloc;
+use alloc::alloc::GlobalAlloc;
+
+static EMMA: DefaultEmma = DefaultEmma::new();
+/// The trace is shared by all instances, so the tests must not trace concurrently.
+static LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+	u32::from_ne_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn u64_at(bytes: &[u8], offset: usize) -> u64 {
+	u64::from_ne_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+fn trace_file(name: &str) -> std::fs::File {
+	let path = std::env::temp_dir().join(format!("emma-trace-{name}-{}.bin", std::process::id()));
+	let file = std::fs::File::options()
+		.read(true)
+		.write(true)
+		.create(true)
+		.truncate(true)
+		.open(&path)
+		.unwrap();
+	std::fs::remove_file(&path).unwrap();
+	file
+}
+
+/// Reads the records of the trace in `file`.
+fn read_records(file: &mut std::fs::File) -> Vec<Vec<u8>> {
+	let mut trace = Vec::new();
+	file.rewind().unwrap();
+	file.read_to_end(&mut trace).unwrap();
+	assert_eq!(&trace[..8], b"EMMATRCE");
+	trace[16..].chunks_exact(48).map(<[u8]>::to_vec).collect()
+}
+
+/// Returns the id of the calling thread, which records carry.
+fn thread_id() -> u32 {
+	let link = std::fs::read_link("/proc/thread-self").unwrap();
+	link.file_name().unwrap().to_str().unwrap().parse().unwrap()
+}
+
+#[test]
+fn record_and_read_back() {
+	let _lock = LOCK.lock().unwrap();
+	let mut file = trace_file("read-back");
+
+	assert!(unsafe { EMMA.start_trace(file.as_raw_fd()) });
+	let layout = Layout::from_size_align(100, 16).unwrap();
+	let (p, q) = unsafe {
+		let p = EMMA.alloc(layout);
+		let q = EMMA.realloc(p, layout, 5000);
+		EMMA.dealloc(q, Layout::from_size_align(5000, 16).unwrap());
+		(p, q)
+	};
+	EMMA.stop_trace();
+
+	let mut trace = Vec::new();
+	file.rewind().unwrap();
+	file.read_to_end(&mut trace).unwrap();
+
+	assert_eq!(&trace[..8], b"EMMATRCE");
+	assert_eq!(u32_at(&trace, 8), emma::trace::VERSION);
+	let record_size = u32_at(&trace, 12) as usize;
+	assert_eq!(record_size, 48);
+
+	let records: Vec<_> = trace[16..].chunks_exact(record_size).collect();
+	assert_eq!(records.len(), 3);
+	let tid = u32_at(records[0], 36);
+	assert!(records.iter().all(|r| u32_at(r, 36) == tid));
+	assert!(records.windows(2).all(|w| u64_at(w[0], 0) <= u64_at(w[1], 0)));
+
+	assert_eq!((records[0][40], records[0][41]), (1, 0));
+	assert_eq!(u64_at(records[0], 8), p as u64);
+	assert_eq!(u64_at(records[0], 24), 100);
+	assert_eq!(u32_at(records[0], 32), 16);
+
+	assert_eq!((records[1][40], records[1][41]), (3, 1));
+	assert_eq!(u64_at(records[1], 8), p as u64);
+	assert_eq!(u64_at(records[1], 16), q as u64);
+	assert_eq!(u64_at(records[1], 24), 5000);
+
+	assert_eq!(records[2][40], 2);
+	assert_eq!(u64_at(records[2], 8), q as u64);
+	assert_eq!(u64_at(records[2], 24), 5000);
+}
+
+#[test]
+fn records_are_buffered_per_thread() {
+	let _lock = LOCK.lock().unwrap();
+	let mut file = trace_file("per-thread");
+
+	assert!(unsafe { EMMA.start_trace(file.as_raw_fd()) });
+	let threads = std::thread::scope(|scope| {
+		let threads: Vec<_> = (0..4)
+			.map(|_| {
+				scope.spawn(|| unsafe {
+					let tid = thread_id();
+					let layout = Layout::from_size_align(64, 8).unwrap();
+					for _ in 0..100 {
+						let p = EMMA.alloc(layout);
+						EMMA.dealloc(p, layout);
+					}
+					tid
+				})
+			})
+			.collect();
+		threads.into_iter().map(|t| t.join().unwrap()).collect::<Vec<_>>()
+	});
+	EMMA.stop_trace();
+
+	// Each thread flushed its own buffer once, so its records form a single chunk.
+	let records = read_records(&mut file);
+	for tid in threads {
+		let positions: Vec<_> = (0..records.len()).filter(|&i| u32_at(&records[i], 36) == tid).collect();
+		assert_eq!(positions.len(), 200);
+		assert_eq!(positions[199] - positions[0], 199);
+	}
+}
+
+#[test]
+fn traces_do_not_share_records() {
+	let _lock = LOCK.lock().unwrap();
+	let mut first = trace_file("first");
+	let mut second = trace_file("second");
+
+	assert!(unsafe { EMMA.start_trace(first.as_raw_fd()) });
+	let tid = std::thread::scope(|scope| {
+		let (stopped, wait) = std::sync::mpsc::channel();
+		let (recorded, done) = std::sync::mpsc::channel();
+		let thread = scope.spawn(move || unsafe {
+			let tid = thread_id();
+			let layout = Layout::from_size_align(64, 8).unwrap();
+			let p = EMMA.alloc(layout);
+			recorded.send(()).unwrap();
+			wait.recv().unwrap();
+			// The allocation was flushed into the first trace when it was stopped, so the second trace only gets the
+			// deallocation.
+			EMMA.dealloc(p, layout);
+			EMMA.flush_trace();
+			tid
+		});
+		done.recv().unwrap();
+		EMMA.stop_trace();
+		assert!(unsafe { EMMA.start_trace(second.as_raw_fd()) });
+		stopped.send(()).unwrap();
+		let tid = thread.join().unwrap();
+		EMMA.stop_trace();
+		tid
+	});
+
+	let first = read_records(&mut first);
+	assert_eq!(first.iter().filter(|r| u32_at(r, 36) == tid).count(), 1);
+	let second = read_records(&mut second);
+	assert_eq!(second.iter().filter(|r| u32_at(r, 36) == tid).count(), 1);
+}
@@ -0,0 +1,84 @@
+use std::alloc::Layout;
+
+use emma::{DefaultEmma, HugePagePolicy, Tier};
+
+extern crate alloc;
+use alloc::alloc::GlobalAlloc;
+
+static EMMA: DefaultEmma = DefaultEmma::new();
+
+fn trim_releases(tier: Tier, layout: Layout, count: usize) {
+	EMMA.set_huge_page_policy(tier, HugePagePolicy::Never);
+
+	let objs: Vec<_> = (0..count)
+		.map(|_| unsafe {
+			let p = EMMA.alloc(layout);
+			assert!(!p.is_null());
+			p.write_bytes(0x42, layout.size());
+			p
+		})
+		.collect();
+	let full = EMMA.resident_bytes();
+
+	for &p in objs.iter() {
+		unsafe { EMMA.dealloc(p, layout) };
+	}
+	EMMA.trim();
+	let trimmed = EMMA.resident_bytes();
+	assert!(
+		trimmed.total() + count * layout.size() / 2 < full.total(),
+		"{full:?} {trimmed:?}"
+	);
+
+	// Trimmed pages are reused.
+	let objs: Vec<_> = (0..count)
+		.map(|_| unsafe {
+			let p = EMMA.alloc(layout);
+			assert!(!p.is_null());
+			p.write_bytes(0x42, layout.size());
+			p
+		})
+		.collect();
+	for &p in objs.iter() {
+		unsafe { EMMA.dealloc(p, layout) };
+	}
+}
+
+/// Whether huge pages are available depends on the system, so this only checks that arenas are promoted and demoted
+/// without corrupting any objects.
+fn trim_when_mostly_full(tier: Tier, layout: Layout, count: usize) {
+	EMMA.set_huge_page_policy(tier, HugePagePolicy::WhenMostlyFull);
+
+	for round in 0..2 {
+		let objs: Vec<_> = (0..count)
+			.map(|i| unsafe {
+				let p = EMMA.alloc(layout);
+				assert!(!p.is_null());
+				p.cast::<usize>().write(i);
+				p
+			})
+			.collect();
+		// Releasing every other object leaves arenas half full, the rest leaves them empty.
+		for &p in objs.iter().step_by(2) {
+			unsafe { EMMA.dealloc(p, layout) };
+		}
+		EMMA.trim();
+		for (i, &p) in objs.iter().enumerate().skip(1).step_by(2) {
+			assert_eq!(unsafe { p.cast::<usize>().read() }, i, "round {round}");
+			unsafe { EMMA.dealloc(p, layout) };
+		}
+		EMMA.trim();
+	}
+}
+
+#[test]
+fn trim() {
+	// The tiers share the test's heap, so they are not tested concurrently.
+	trim_releases(Tier::Small, Layout::from_size_align(64, 8).unwrap(), 100_000);
+	trim_releases(Tier::Medium, Layout::from_size_align(2000, 8).unwrap(), 5_000);
+	trim_releases(Tier::Large, Layout::from_size_align(100_000, 8).unwrap(), 100);
+
+	trim_when_mostly_full(Tier::Small, Layout::from_size_align(64, 8).unwrap(), 100_000);
+	trim_when_mostly_full(Tier::Medium, Layout::from_size_align(2000, 8).unwrap(), 5_000);
+	trim_when_mostly_full(Tier::Large, Layout::from_size_align(100_000, 8).unwrap(), 100);
+}